@@ -19,7 +19,7 @@ fn bench_not_optimize_mandelbrot(bencher: &mut test::Bencher) {
             .root_node(&block)
             .input(io::empty())
             .output(io::sink())
-            .memory(AutoExtendMemory::new(vec![0]))
+            .memory(AutoExtendMemory::new(vec![0u8]))
             .build()
             .run()
             .unwrap();
@@ -36,7 +36,7 @@ fn bench_optimized_mandelbrot(bencher: &mut test::Bencher) {
             .root_node(&block)
             .input(io::empty())
             .output(io::sink())
-            .memory(AutoExtendMemory::new(vec![0]))
+            .memory(AutoExtendMemory::new(vec![0u8]))
             .build()
             .run()
             .unwrap();
@@ -53,7 +53,7 @@ fn bench_hello_world(bencher: &mut test::Bencher) {
             .root_node(&block)
             .input(io::empty())
             .output(io::sink())
-            .memory(AutoExtendMemory::new(vec![0]))
+            .memory(AutoExtendMemory::new(vec![0u8]))
             .build()
             .run()
             .unwrap();
@@ -72,7 +72,7 @@ fn bench_optimized_hello_world(bencher: &mut test::Bencher) {
             .root_node(&block)
             .input(io::empty())
             .output(io::sink())
-            .memory(AutoExtendMemory::new(vec![0]))
+            .memory(AutoExtendMemory::new(vec![0u8]))
             .build()
             .run()
             .unwrap();
@@ -91,7 +91,7 @@ fn bench_optimized_pi16(bencher: &mut test::Bencher) {
             .root_node(&block)
             .input(io::empty())
             .output(io::sink())
-            .memory(AutoExtendMemory::new(vec![0]))
+            .memory(AutoExtendMemory::new(vec![0u8]))
             .build()
             .run()
             .unwrap();
@@ -3,7 +3,10 @@ extern crate test;
 
 use std::io;
 
-use bf::{transpile::wasm::block_to_wasm, utils};
+use bf::{
+    transpile::wasm::{block_to_wasm, Config},
+    utils,
+};
 
 const MANDELBROT: &str = include_str!("../bf_codes/mandelbrot.bf");
 
@@ -15,6 +18,6 @@ fn bench_block_to_wasm(bencher: &mut test::Bencher) {
     let mut sink = io::sink();
 
     bencher.iter(|| {
-        block_to_wasm(&block, &mut sink).unwrap();
+        block_to_wasm(&block, &Config::default(), &mut sink).unwrap();
     })
 }
@@ -0,0 +1,191 @@
+use std::{fmt, str::FromStr};
+
+/// メモリセルのビット幅。シリアライズするときはビット数で表す
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(into = "u32", try_from = "u32")]
+pub enum CellWidth {
+    #[default]
+    W8,
+    W16,
+    W32,
+    W64,
+}
+impl CellWidth {
+    pub fn bits(self) -> u32 {
+        match self {
+            CellWidth::W8 => 8,
+            CellWidth::W16 => 16,
+            CellWidth::W32 => 32,
+            CellWidth::W64 => 64,
+        }
+    }
+    /// 1セルのバイト数
+    pub fn bytes(self) -> u32 {
+        self.bits() / 8
+    }
+    /// セルに入る最大の値
+    pub fn max(self) -> u64 {
+        u64::MAX >> (64 - self.bits())
+    }
+}
+
+impl TryFrom<u32> for CellWidth {
+    type Error = String;
+
+    fn try_from(bits: u32) -> Result<Self, Self::Error> {
+        match bits {
+            8 => Ok(CellWidth::W8),
+            16 => Ok(CellWidth::W16),
+            32 => Ok(CellWidth::W32),
+            64 => Ok(CellWidth::W64),
+            _ => Err(format!("unsupported cell width: {bits} (8, 16, 32, 64)")),
+        }
+    }
+}
+
+impl From<CellWidth> for u32 {
+    fn from(width: CellWidth) -> Self {
+        width.bits()
+    }
+}
+
+impl FromStr for CellWidth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bits = s
+            .parse::<u32>()
+            .map_err(|_| format!("invalid cell width: {s}"))?;
+        Self::try_from(bits)
+    }
+}
+
+impl fmt::Display for CellWidth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.bits())
+    }
+}
+
+/// セルの値がセル幅に収まらなくなったときの動作
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// セル幅でwrapする
+    #[default]
+    Wrap,
+    /// 0か最大値で止める
+    Saturate,
+    /// エラーにする
+    Trap,
+}
+impl OverflowPolicy {
+    /// 計算結果`value`をセルの値にする。`Trap`で収まらなければ`None`
+    pub fn apply<C: Cell>(self, value: i128) -> Option<C> {
+        let max = C::WIDTH.max();
+        match self {
+            OverflowPolicy::Wrap => Some(C::from_u64(value as u64 & max)),
+            OverflowPolicy::Saturate => Some(C::from_u64(value.clamp(0, max as i128) as u64)),
+            OverflowPolicy::Trap => u64::try_from(value)
+                .ok()
+                .filter(|&value| value <= max)
+                .map(C::from_u64),
+        }
+    }
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wrap" => Ok(OverflowPolicy::Wrap),
+            "saturate" => Ok(OverflowPolicy::Saturate),
+            "trap" => Ok(OverflowPolicy::Trap),
+            _ => Err(format!(
+                "invalid overflow policy: {s} (wrap, saturate, trap)"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OverflowPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            OverflowPolicy::Wrap => "wrap",
+            OverflowPolicy::Saturate => "saturate",
+            OverflowPolicy::Trap => "trap",
+        };
+        f.write_str(name)
+    }
+}
+
+/// インタプリタのメモリセルとして使える整数型。
+///
+/// 演算はすべてセル幅でwrapする。wrapしない計算は`OverflowPolicy::apply`で行う。
+pub trait Cell:
+    Copy + Default + PartialEq + fmt::Debug + serde::Serialize + serde::de::DeserializeOwned
+{
+    const WIDTH: CellWidth;
+
+    /// `x`をセル幅に切り詰める（負の値は2の補数で表現される）。
+    fn from_i32(x: i32) -> Self;
+    fn from_u8(x: u8) -> Self;
+    /// `x`をセル幅に切り詰める
+    fn from_u64(x: u64) -> Self;
+    fn to_u64(self) -> u64;
+    /// 下位8bitを返す。出力に使う。
+    fn low_byte(self) -> u8;
+    fn is_zero(self) -> bool;
+    fn wrapping_add(self, rhs: Self) -> Self;
+    fn wrapping_sub(self, rhs: Self) -> Self;
+    fn wrapping_mul(self, rhs: Self) -> Self;
+}
+
+macro_rules! impl_cell {
+    ($t:ty, $width:expr) => {
+        impl Cell for $t {
+            const WIDTH: CellWidth = $width;
+
+            #[inline]
+            fn from_i32(x: i32) -> Self {
+                x as $t
+            }
+            #[inline]
+            fn from_u8(x: u8) -> Self {
+                x as $t
+            }
+            #[inline]
+            fn from_u64(x: u64) -> Self {
+                x as $t
+            }
+            #[inline]
+            fn to_u64(self) -> u64 {
+                self as u64
+            }
+            #[inline]
+            fn low_byte(self) -> u8 {
+                self as u8
+            }
+            #[inline]
+            fn is_zero(self) -> bool {
+                self == 0
+            }
+            #[inline]
+            fn wrapping_add(self, rhs: Self) -> Self {
+                <$t>::wrapping_add(self, rhs)
+            }
+            #[inline]
+            fn wrapping_sub(self, rhs: Self) -> Self {
+                <$t>::wrapping_sub(self, rhs)
+            }
+            #[inline]
+            fn wrapping_mul(self, rhs: Self) -> Self {
+                <$t>::wrapping_mul(self, rhs)
+            }
+        }
+    };
+}
+
+impl_cell!(u8, CellWidth::W8);
+impl_cell!(u16, CellWidth::W16);
+impl_cell!(u32, CellWidth::W32);
+impl_cell!(u64, CellWidth::W64);
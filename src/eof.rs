@@ -0,0 +1,43 @@
+use std::{fmt, str::FromStr};
+
+/// 入力がEOFに達したときの`,`の動作。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EofBehavior {
+    /// セルを変更しない
+    Unchanged,
+    /// セルを0にする
+    Zero,
+    /// セルを-1（すべてのbitが1）にする。8bitなら255
+    MinusOne,
+    /// エラーにする
+    #[default]
+    Error,
+}
+
+impl FromStr for EofBehavior {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unchanged" => Ok(EofBehavior::Unchanged),
+            "zero" | "0" => Ok(EofBehavior::Zero),
+            "minus-one" | "-1" | "255" => Ok(EofBehavior::MinusOne),
+            "error" => Ok(EofBehavior::Error),
+            _ => Err(format!(
+                "invalid EOF behavior: {s} (unchanged, zero, minus-one, error)"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for EofBehavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            EofBehavior::Unchanged => "unchanged",
+            EofBehavior::Zero => "zero",
+            EofBehavior::MinusOne => "minus-one",
+            EofBehavior::Error => "error",
+        };
+        f.write_str(name)
+    }
+}
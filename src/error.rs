@@ -1,9 +1,11 @@
 use std::io;
 
+use crate::parse::SyntaxError;
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
-    #[error("{msg}")]
-    InvalidSyntax { msg: &'static str },
+    #[error("{}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n"))]
+    InvalidSyntax(Vec<SyntaxError>),
     #[error("{0}")]
     IoError(#[from] io::Error),
 }
@@ -0,0 +1,468 @@
+//! ランダムに作ったプログラムを、最適化なしのインタプリタ、最適化したインタプリタ、
+//! Cのバックエンドで実行して、結果が食い違うものを探す。
+
+use std::{
+    fmt, fs,
+    io::Write,
+    process::{Command, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+
+use crate::{
+    cell::CellWidth,
+    eof::EofBehavior,
+    ir::Block,
+    opt::{run_interpreter, Outcome, Pipeline},
+    parse,
+    transpile::c,
+    utils::{bf_to_block, XorShift},
+};
+
+/// 構文から止まることが分かるプログラムを作る。
+/// ループは本体でカウンタのセルに触らず、1周ごとに奇数だけ変えるか0にする。
+/// 8bitのセルなら、奇数ずつ変えれば256周以内に0になる。
+/// `[>]`のような走査ループは止まるとは限らないので作らない
+#[derive(Debug, Clone)]
+pub struct Generator {
+    /// 命令の数のおおよその上限
+    pub max_len: usize,
+    /// ループの入れ子の深さの上限。ループごとにカウンタのセルを使うので、`cells - 1`までに抑える
+    pub max_depth: usize,
+    /// 使うセルの数。ポインタは`0..cells`の中だけを動く。0なら1として扱う
+    pub cells: usize,
+}
+impl Default for Generator {
+    fn default() -> Self {
+        Self {
+            max_len: 64,
+            max_depth: 2,
+            cells: 8,
+        }
+    }
+}
+
+struct GenState {
+    code: String,
+    pointer: usize,
+    /// 外側のループのカウンタのセル
+    counters: Vec<usize>,
+}
+impl GenState {
+    fn move_to(&mut self, to: usize) {
+        let c = if to < self.pointer { '<' } else { '>' };
+        for _ in 0..to.abs_diff(self.pointer) {
+            self.code.push(c);
+        }
+        self.pointer = to;
+    }
+}
+
+impl Generator {
+    pub fn generate(&self, rng: &mut XorShift) -> String {
+        // カウンタ以外のセルが残っていないと、ループの本体で動ける場所がない
+        let cells = self.cells.max(1);
+        let generator = Generator {
+            max_depth: self.max_depth.min(cells - 1),
+            cells,
+            ..self.clone()
+        };
+        let mut state = GenState {
+            code: String::new(),
+            pointer: 0,
+            counters: Vec::new(),
+        };
+        generator.block(rng, &mut state, 0);
+        state.code
+    }
+
+    fn block(&self, rng: &mut XorShift, state: &mut GenState, depth: usize) {
+        for _ in 0..=rng.below(8) {
+            if state.code.len() >= self.max_len {
+                break;
+            }
+            self.item(rng, state, depth);
+        }
+    }
+
+    fn item(&self, rng: &mut XorShift, state: &mut GenState, depth: usize) {
+        // カウンタのセルは書き換えない
+        while state.counters.contains(&state.pointer) {
+            let to = rng.below(self.cells as u64) as usize;
+            state.move_to(to);
+        }
+        match rng.below(10) {
+            0..=2 => {
+                let c = if rng.below(2) == 0 { '+' } else { '-' };
+                for _ in 0..=rng.below(5) {
+                    state.code.push(c);
+                }
+            }
+            3 | 4 => {
+                let to = rng.below(self.cells as u64) as usize;
+                state.move_to(to);
+            }
+            5 => state.code.push('.'),
+            6 => state.code.push(','),
+            7 => state
+                .code
+                .push_str(if rng.below(2) == 0 { "[-]" } else { "[+]" }),
+            _ if depth < self.max_depth => {
+                let counter = state.pointer;
+                state.counters.push(counter);
+                state.code.push('[');
+                let step = ["-", "+", "---", "+++"][rng.below(4) as usize];
+                // 1周しかしないループはifになる
+                let end = match rng.below(4) {
+                    0 => "[-]]".to_string(),
+                    1 => {
+                        state.code.push_str(step);
+                        "]".to_string()
+                    }
+                    _ => format!("{step}]"),
+                };
+                self.block(rng, state, depth + 1);
+                state.move_to(counter);
+                state.code.push_str(&end);
+                state.counters.pop();
+            }
+            _ => state.code.push('.'),
+        }
+    }
+}
+
+/// 最適化なしのインタプリタと比べるバックエンド
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// `Fuzzer::pipeline`で最適化したインタプリタ
+    OptimizedInterpreter,
+    /// `Fuzzer::pipeline`で最適化したCのプログラム
+    C,
+}
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::OptimizedInterpreter => write!(f, "optimized interpreter"),
+            Backend::C => write!(f, "C"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Error)]
+#[error("{backend} differs for input {input:?}: expected {expected}, got {actual}")]
+pub struct Mismatch {
+    pub backend: Backend,
+    pub input: Vec<u8>,
+    pub expected: Outcome,
+    pub actual: Outcome,
+}
+
+pub struct Fuzzer {
+    pub pipeline: Pipeline,
+    /// Cのバックエンドも比べる。`cc`でコンパイルする
+    pub c: bool,
+    /// テープの長さ。セルは8bitで、EOFは0を読む
+    pub memory_len: usize,
+    /// 最適化なしのインタプリタの命令数の上限。超えたプログラムは比べない
+    pub max_steps: usize,
+    /// Cのプログラムの実行時間の上限
+    pub timeout: Duration,
+}
+impl Fuzzer {
+    /// `cc`が使えればCのバックエンドも比べる
+    pub fn new(pipeline: Pipeline) -> Self {
+        Self {
+            pipeline: pipeline.cell_width(CellWidth::W8),
+            c: has_cc(),
+            memory_len: 64,
+            max_steps: 1_000_000,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// 最初に食い違ったバックエンドを返す。
+    /// 構文エラーのプログラムや、最適化なしでエラーになったり止まらなかったりするプログラムは比べない
+    pub fn check(&self, source: &str, input: &[u8]) -> Option<Mismatch> {
+        let block = bf_to_block(source).ok()?;
+        let (expected, steps) = run_interpreter(&block, input, self.memory_len, self.max_steps);
+        if let Outcome::Failed(_) = expected {
+            return None;
+        }
+        let mismatch = |backend, actual| {
+            (actual != expected).then(|| Mismatch {
+                backend,
+                input: input.to_vec(),
+                expected: expected.clone(),
+                actual,
+            })
+        };
+
+        let optimized = self.pipeline.run(&block);
+        // 最適化で命令が増えることもあるので、上限には余裕を持たせる
+        let (actual, _) = run_interpreter(&optimized, input, self.memory_len, steps * 4 + 64);
+        if let Some(mismatch) = mismatch(Backend::OptimizedInterpreter, actual) {
+            return Some(mismatch);
+        }
+        if self.c {
+            let actual = self.run_c(&optimized, input);
+            if let Some(mismatch) = mismatch(Backend::C, actual) {
+                return Some(mismatch);
+            }
+        }
+        None
+    }
+
+    fn run_c(&self, block: &Block, input: &[u8]) -> Outcome {
+        let config = c::Config {
+            memory_len: self.memory_len,
+            checked: true,
+            eof: EofBehavior::Zero,
+            dump_memory: true,
+            ..Default::default()
+        };
+        let c_code = c::block_to_c(block, &config);
+
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "bf_fuzz_{}_{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let result = compile_and_run(&path, &c_code, input, self.timeout);
+        for extension in ["c", "out", "stdout", "stderr"] {
+            let _ = fs::remove_file(path.with_extension(extension));
+        }
+        let (output, stderr) = match result {
+            Ok(result) => result,
+            Err(message) => return Outcome::Failed(message),
+        };
+
+        // 1行目がポインタの位置で、残りがテープ
+        let dump = stderr.iter().position(|&b| b == b'\n').and_then(|newline| {
+            let pointer = std::str::from_utf8(&stderr[..newline]).ok()?.parse().ok()?;
+            Some((pointer, stderr[newline + 1..].to_vec()))
+        });
+        match dump {
+            Some((pointer, memory)) if memory.len() == self.memory_len => Outcome::Finished {
+                output,
+                memory,
+                pointer,
+            },
+            _ => Outcome::Failed(String::from_utf8_lossy(&stderr).into_owned()),
+        }
+    }
+}
+
+pub fn has_cc() -> bool {
+    Command::new("cc").arg("--version").output().is_ok()
+}
+
+/// `(標準出力, 標準エラー出力)`。出力が多くても詰まらないようにファイルに書き出す
+fn compile_and_run(
+    path: &std::path::Path,
+    c_code: &str,
+    input: &[u8],
+    timeout: Duration,
+) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let c_path = path.with_extension("c");
+    let exe_path = path.with_extension("out");
+    let stdout_path = path.with_extension("stdout");
+    let stderr_path = path.with_extension("stderr");
+    fs::write(&c_path, c_code).map_err(|e| e.to_string())?;
+
+    let output = Command::new("cc")
+        .args(["-O1", "-o"])
+        .arg(&exe_path)
+        .arg(&c_path)
+        .output()
+        .map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(format!(
+            "cc failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    let mut child = Command::new(&exe_path)
+        .stdin(Stdio::piped())
+        .stdout(fs::File::create(&stdout_path).map_err(|e| e.to_string())?)
+        .stderr(fs::File::create(&stderr_path).map_err(|e| e.to_string())?)
+        .spawn()
+        .map_err(|e| e.to_string())?;
+    // 入力を読まずに終わることもあるので、書き込みのエラーは無視する
+    let _ = child.stdin.take().unwrap().write_all(input);
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+            break status;
+        }
+        if start.elapsed() > timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("timed out after {timeout:?}"));
+        }
+        thread::sleep(Duration::from_millis(1));
+    };
+
+    let stdout = fs::read(&stdout_path).map_err(|e| e.to_string())?;
+    let stderr = fs::read(&stderr_path).map_err(|e| e.to_string())?;
+    if !status.success() {
+        return Err(format!(
+            "exited with {status}: {}",
+            String::from_utf8_lossy(&stderr)
+        ));
+    }
+    Ok((stdout, stderr))
+}
+
+/// `fails`が`true`のままになるように、`source`から文字を消していく。
+/// 命令以外の文字は最初に取り除き、構文エラーになる候補は試さない
+pub fn minimize(source: &str, mut fails: impl FnMut(&str) -> bool) -> String {
+    let mut code: Vec<char> = source.chars().filter(|c| "+-<>[].,".contains(*c)).collect();
+    let mut try_remove = |code: &mut Vec<char>, candidate: Vec<char>| {
+        let candidate_str: String = candidate.iter().collect();
+        let ok = parse::parse(&candidate_str).is_ok() && fails(&candidate_str);
+        if ok {
+            *code = candidate;
+        }
+        ok
+    };
+    if !try_remove(&mut code.clone(), code.clone()) {
+        return source.to_string();
+    }
+
+    let mut chunk = code.len().div_ceil(2).max(1);
+    loop {
+        // 連続した`chunk`文字を消す
+        let mut start = 0;
+        let mut removed = false;
+        while start < code.len() {
+            let end = (start + chunk).min(code.len());
+            let candidate = [&code[..start], &code[end..]].concat();
+            if try_remove(&mut code, candidate) {
+                removed = true;
+            } else {
+                start += chunk;
+            }
+        }
+        if removed {
+            continue;
+        }
+        if chunk > 1 {
+            chunk /= 2;
+            continue;
+        }
+
+        // 対応する`[`と`]`だけを消して、ループの本体を残す
+        let mut stack = Vec::new();
+        let mut pairs = Vec::new();
+        for (i, c) in code.iter().enumerate() {
+            match c {
+                '[' => stack.push(i),
+                ']' => pairs.push((stack.pop().unwrap(), i)),
+                _ => {}
+            }
+        }
+        let unwrapped = pairs.into_iter().any(|(open, close)| {
+            let candidate = [&code[..open], &code[open + 1..close], &code[close + 1..]].concat();
+            try_remove(&mut code, candidate)
+        });
+        if !unwrapped {
+            break;
+        }
+    }
+    code.into_iter().collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        ir::{BlockItem, Op},
+        opt::{OptLevel, Pass},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_generator() {
+        let generator = Generator::default();
+        let mut fuzzer = Fuzzer::new(Pipeline::with_level(OptLevel::O3));
+        fuzzer.c = false;
+        let mut rng = XorShift::new(42);
+        for _ in 0..200 {
+            let source = generator.generate(&mut rng);
+            let block = bf_to_block(&source).unwrap();
+            let input = rng.bytes(4);
+            let (outcome, _) = run_interpreter(&block, &input, 64, 100_000_000);
+            assert!(matches!(outcome, Outcome::Finished { .. }), "{source}");
+            assert!(fuzzer.check(&source, &input).is_none(), "{source}");
+        }
+
+        // セルが足りなければ、入れ子を浅くする
+        for (cells, max_depth) in [(0, 2), (1, 2), (2, 5)] {
+            let generator = Generator {
+                max_depth,
+                cells,
+                ..Default::default()
+            };
+            for _ in 0..20 {
+                let source = generator.generate(&mut rng);
+                assert!(cells > 1 || !source.contains(['<', '>']), "{source}");
+                bf_to_block(&source).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_fuzz_c() {
+        let mut fuzzer = Fuzzer::new(Pipeline::with_level(OptLevel::O2));
+        if !fuzzer.c {
+            return;
+        }
+        fuzzer.max_steps = 100_000;
+        let generator = Generator::default();
+        let mut rng = XorShift::new(7);
+        for _ in 0..5 {
+            let source = generator.generate(&mut rng);
+            let input = rng.bytes(4);
+            let mismatch = fuzzer.check(&source, &input);
+            assert!(mismatch.is_none(), "{source}: {}", mismatch.unwrap());
+        }
+    }
+
+    /// `+`を1つ多く足す、壊れたパス
+    struct Broken;
+    impl Pass for Broken {
+        fn name(&self) -> &'static str {
+            "broken"
+        }
+        fn run(&self, block: &mut Block) {
+            for item in &mut block.items {
+                if let BlockItem::Op(Op::Add(x, offset)) = item {
+                    *item = BlockItem::Op(Op::Add(*x + 1, *offset));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_minimize() {
+        let mut fuzzer = Fuzzer::new(Pipeline::new().pass(Broken));
+        fuzzer.c = false;
+        let source = ">++[->+++<]>.<<,[-]- this is a comment";
+        let mismatch = fuzzer.check(source, b"a").unwrap();
+        assert_eq!(mismatch.backend, Backend::OptimizedInterpreter);
+
+        let minimized = minimize(source, |source| fuzzer.check(source, b"a").is_some());
+        assert!(minimized == "+" || minimized == "-", "{minimized}");
+
+        // `[`と`]`を一緒に消さないと縮まない
+        let minimized = minimize("[.]+", |source| source.contains('.'));
+        assert_eq!(minimized, ".");
+        assert_eq!(minimize("+", |_| false), "+");
+    }
+}
@@ -0,0 +1,604 @@
+//! `InterPreter`を1命令ずつ動かすデバッガ。
+//!
+//! ブレークポイントは「次に実行する命令」に対して判定する。
+//! ウォッチポイントは1命令実行するごとに、監視しているセルが変わったかを確認する。
+//! 逆実行は`InterPreterBuilder::record`で記録した範囲でだけできる。
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{Read, Write},
+    str::FromStr,
+};
+
+use super::{Error, FlatInstruction, InterPreter, Memory, Record, RecordedIo};
+use crate::parse::{offset_of, Span};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// 実行を止めた理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop<C> {
+    /// 指示された分だけ進めた
+    Step,
+    Breakpoint {
+        id: usize,
+    },
+    Watchpoint {
+        index: usize,
+        old: C,
+        new: C,
+    },
+    /// 最後まで実行した
+    Finished,
+    /// 記録の先頭まで戻った。これ以上は戻れない
+    Beginning,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub line: usize,
+    pub column: usize,
+    /// 止まる命令の位置
+    pub pcs: Vec<usize>,
+}
+
+pub struct Debugger<'a, R: Read, W: Write, M: Memory> {
+    interpreter: InterPreter<R, W, M>,
+    source: &'a str,
+    breakpoints: BTreeMap<usize, Breakpoint>,
+    next_breakpoint_id: usize,
+    /// 監視しているセルと、最後に見た値
+    watchpoints: BTreeMap<usize, M::Cell>,
+}
+impl<'a, R: Read, W: Write, M: Memory> Debugger<'a, R, W, M> {
+    /// `source`は`interpreter`に渡した`Block`の元のソースコード
+    pub fn new(interpreter: InterPreter<R, W, M>, source: &'a str) -> Self {
+        Self {
+            interpreter,
+            source,
+            breakpoints: BTreeMap::new(),
+            next_breakpoint_id: 1,
+            watchpoints: BTreeMap::new(),
+        }
+    }
+    pub fn interpreter(&self) -> &InterPreter<R, W, M> {
+        &self.interpreter
+    }
+    pub fn into_interpreter(self) -> InterPreter<R, W, M> {
+        self.interpreter
+    }
+
+    /// `line:column`の位置にブレークポイントを置き、そのIDを返す。
+    /// その位置に命令がなければ、同じ行の後ろにある最初の命令に置く
+    pub fn add_breakpoint(&mut self, line: usize, column: usize) -> Option<usize> {
+        let offset = offset_of(self.source, line, column)?;
+        let pcs = self.resolve(offset);
+        if pcs.is_empty() {
+            return None;
+        }
+
+        let id = self.next_breakpoint_id;
+        self.next_breakpoint_id += 1;
+        self.breakpoints
+            .insert(id, Breakpoint { line, column, pcs });
+        Some(id)
+    }
+    pub fn remove_breakpoint(&mut self, id: usize) -> bool {
+        self.breakpoints.remove(&id).is_some()
+    }
+    pub fn breakpoints(&self) -> impl Iterator<Item = (usize, &Breakpoint)> {
+        self.breakpoints.iter().map(|(id, b)| (*id, b))
+    }
+    /// `offset`にある命令の位置。ループは`[`で止まれば十分なので、`WhileEnd`は含めない
+    fn resolve(&self, offset: usize) -> Vec<usize> {
+        let line_end = self.source[offset..]
+            .find('\n')
+            .map_or(self.source.len(), |i| offset + i);
+        let candidates = || {
+            self.interpreter
+                .spans()
+                .iter()
+                .zip(self.interpreter.instructions())
+                .enumerate()
+                .filter(|(_, (span, instruction))| {
+                    !span.is_empty() && !matches!(instruction, FlatInstruction::WhileEnd(_))
+                })
+                .map(|(pc, (span, _))| (pc, *span))
+        };
+
+        // 位置を含む命令があればそれを、なければ同じ行の後ろにある最初の命令を選ぶ
+        let containing: Vec<_> = candidates()
+            .filter(|(_, span)| span.start <= offset && offset < span.end)
+            .collect();
+        let matches = if containing.is_empty() {
+            let next_start = candidates()
+                .map(|(_, span)| span.start)
+                .filter(|start| (offset..line_end).contains(start))
+                .min();
+            candidates()
+                .filter(|(_, span)| Some(span.start) == next_start)
+                .collect()
+        } else {
+            containing
+        };
+
+        // ループの中の命令は、ループ全体の範囲にも含まれる。最も狭い範囲の命令だけを残す
+        let min_len = matches.iter().map(|(_, span)| span.end - span.start).min();
+        matches
+            .into_iter()
+            .filter(|(_, span)| Some(span.end - span.start) == min_len)
+            .map(|(pc, _)| pc)
+            .collect()
+    }
+    fn breakpoint_at(&self, pc: usize) -> Option<usize> {
+        self.breakpoints
+            .iter()
+            .find(|(_, b)| b.pcs.contains(&pc))
+            .map(|(id, _)| *id)
+    }
+
+    /// `index`番目のセルが変わったら止まるようにする
+    pub fn add_watchpoint(&mut self, index: usize) {
+        let value = self.interpreter.cell(index);
+        self.watchpoints.insert(index, value);
+    }
+    pub fn remove_watchpoint(&mut self, index: usize) -> bool {
+        self.watchpoints.remove(&index).is_some()
+    }
+    pub fn watchpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.watchpoints.keys().copied()
+    }
+
+    /// 1命令だけ実行する
+    pub fn step(&mut self) -> Result<Stop<M::Cell>> {
+        self.run_until(|_| true)
+    }
+    /// 1命令実行する。次の命令がループの始まりなら、ループを抜けるまで実行する
+    pub fn step_over(&mut self) -> Result<Stop<M::Cell>> {
+        let pc = self.interpreter.pc();
+        match self.interpreter.instructions().get(pc) {
+            Some(&FlatInstruction::WhileBegin(to)) => self.run_until(|i| i.pc() == to),
+            _ => self.step(),
+        }
+    }
+    /// 今いる最も内側のループを抜けるまで実行する。ループの外なら`resume`と同じ
+    pub fn finish(&mut self) -> Result<Stop<M::Cell>> {
+        let pc = self.interpreter.pc();
+        // 手前にある`WhileBegin`のうち、飛び先が今の位置より後ろのものが外側のループ
+        let exit = self.interpreter.instructions()[..pc]
+            .iter()
+            .rev()
+            .find_map(|instruction| match *instruction {
+                FlatInstruction::WhileBegin(to) if to > pc => Some(to),
+                _ => None,
+            });
+        match exit {
+            Some(exit) => self.run_until(|i| i.pc() == exit),
+            None => self.resume(),
+        }
+    }
+    /// ブレークポイントかウォッチポイントで止まるか、最後まで実行する
+    pub fn resume(&mut self) -> Result<Stop<M::Cell>> {
+        self.run_until(|_| false)
+    }
+
+    /// `done`がtrueを返すまで実行する。ブレークポイントとウォッチポイントでも止まる
+    fn run_until(
+        &mut self,
+        mut done: impl FnMut(&InterPreter<R, W, M>) -> bool,
+    ) -> Result<Stop<M::Cell>> {
+        loop {
+            let origin = self.interpreter.origin();
+            if self.interpreter.step()?.is_none() {
+                return Ok(Stop::Finished);
+            }
+            // テープが左に伸びたら、監視しているセルの位置もずれる
+            let shift = self.interpreter.origin() - origin;
+            if shift > 0 {
+                self.watchpoints = std::mem::take(&mut self.watchpoints)
+                    .into_iter()
+                    .map(|(index, value)| (index + shift, value))
+                    .collect();
+            }
+            for (&index, value) in &mut self.watchpoints {
+                let new = self.interpreter.cell(index);
+                if new != *value {
+                    let old = std::mem::replace(value, new);
+                    return Ok(Stop::Watchpoint { index, old, new });
+                }
+            }
+            if done(&self.interpreter) {
+                return Ok(Stop::Step);
+            }
+            if let Some(id) = self.breakpoint_at(self.interpreter.pc()) {
+                return Ok(Stop::Breakpoint { id });
+            }
+        }
+    }
+
+    /// 1命令戻る
+    pub fn step_back(&mut self) -> Stop<M::Cell> {
+        self.reverse_until(|_| true)
+    }
+    /// `index`番目のセルを最後に書き換えた命令の、実行前まで戻る
+    pub fn reverse_to_write(&mut self, index: usize) -> Stop<M::Cell> {
+        let index = index as isize - self.interpreter.origin() as isize;
+        self.reverse_until(|record| record.write.is_some_and(|(i, _)| i == index))
+    }
+    /// 最後に出力した命令の、実行前まで戻る
+    pub fn rewind_to_output(&mut self) -> Stop<M::Cell> {
+        self.reverse_until(|record| record.io == RecordedIo::Output)
+    }
+
+    /// `done`がtrueを返す命令を取り消すまで戻る
+    fn reverse_until(&mut self, mut done: impl FnMut(&Record<M::Cell>) -> bool) -> Stop<M::Cell> {
+        let stop = loop {
+            match self.interpreter.step_back() {
+                Some(record) if done(&record) => break Stop::Step,
+                Some(_) => {}
+                None => break Stop::Beginning,
+            }
+        };
+        // 戻った後の値から、また変化を監視する
+        for (&index, value) in &mut self.watchpoints {
+            *value = self.interpreter.cell(index);
+        }
+        stop
+    }
+
+    /// 次に実行する命令のソースコード上の範囲
+    pub fn current_span(&self) -> Option<Span> {
+        self.interpreter.spans().get(self.interpreter.pc()).copied()
+    }
+
+    /// 次に実行する命令と、ソースコード上の位置を表示用に整形する
+    pub fn location(&self) -> String {
+        let pc = self.interpreter.pc();
+        let (Some(instruction), Some(span)) =
+            (self.interpreter.instructions().get(pc), self.current_span())
+        else {
+            return format!("{pc}: (finished)\n");
+        };
+
+        let mut text = format!("{pc}: {instruction:?}");
+        if !span.is_empty() {
+            let (line, column) = span.line_column(self.source);
+            let line_text = self.source.lines().nth(line - 1).unwrap_or_default();
+            // 複数行にまたがる場合は、最初の行の終わりまで
+            let width = self.source[span.start..span.end]
+                .split('\n')
+                .next()
+                .map_or(1, |s| s.chars().count().max(1));
+            write!(
+                text,
+                " at {line}:{column}\n{line_text}\n{}{}",
+                " ".repeat(column - 1),
+                "^".repeat(width)
+            )
+            .unwrap();
+        }
+        text.push('\n');
+        text
+    }
+
+    /// ポインタの前後`radius`個のセルを`[index]value`の形で並べる。ポインタの位置は`*`で示す
+    pub fn tape(&self, radius: usize) -> String {
+        let pointer = self.interpreter.pointer();
+        let begin = pointer.saturating_sub(radius);
+        (begin..=pointer + radius)
+            .map(|index| {
+                let mark = if index == pointer { "*" } else { "" };
+                format!("{mark}[{index}]{:?}", self.interpreter.cell(index))
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// `bff debug`で受け付けるコマンド
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// `step [N]`, `s`: N命令実行する
+    Step(usize),
+    /// `next`, `n`: ループを1つの命令として実行する
+    Next,
+    /// `continue`, `c`
+    Continue,
+    /// `finish`, `f`: 今のループを抜けるまで実行する
+    Finish,
+    /// `back [N]`, `bk`: N命令戻る
+    Back(usize),
+    /// `last-write INDEX`, `lw`: セルを最後に書き換えた命令まで戻る
+    LastWrite(usize),
+    /// `rewind`, `rw`: 直前の出力まで戻る
+    Rewind,
+    /// `break LINE[:COLUMN]`, `b`
+    Break {
+        line: usize,
+        column: usize,
+    },
+    /// `delete ID`, `d`
+    Delete(usize),
+    /// `watch INDEX`, `w`
+    Watch(usize),
+    /// `unwatch INDEX`
+    Unwatch(usize),
+    /// `tape [RADIUS]`, `t`: ポインタの周りのセルを表示する
+    Tape(usize),
+    /// `where`: 次に実行する命令を表示する
+    Where,
+    /// `info`, `i`: ブレークポイントとウォッチポイントの一覧
+    Info,
+    Help,
+    Quit,
+}
+
+impl Command {
+    pub const HELP: &'static str = "\
+step [N]          (s)  N命令実行する
+next              (n)  ループを1つの命令として実行する
+continue          (c)  ブレークポイントかウォッチポイントまで実行する
+finish            (f)  今のループを抜けるまで実行する
+back [N]          (bk) N命令戻る
+last-write INDEX  (lw) セルを最後に書き換えた命令まで戻る
+rewind            (rw) 直前の出力まで戻る
+break LINE[:COL]  (b)  ブレークポイントを置く
+delete ID         (d)  ブレークポイントを消す
+watch INDEX       (w)  セルが変わったら止まる
+unwatch INDEX          ウォッチポイントを消す
+tape [RADIUS]     (t)  ポインタの周りのセルを表示する
+where                  次に実行する命令を表示する
+info              (i)  ブレークポイントとウォッチポイントの一覧
+help              (h)
+quit              (q)";
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let name = words.next().unwrap_or("step");
+        let arg = words.next();
+        if words.next().is_some() {
+            return Err(format!("too many arguments: {s}"));
+        }
+
+        let number = |default: Option<usize>| -> std::result::Result<usize, String> {
+            match arg {
+                Some(arg) => arg.parse().map_err(|_| format!("invalid number: {arg}")),
+                None => default.ok_or_else(|| format!("{name}: missing argument")),
+            }
+        };
+
+        let command = match name {
+            "step" | "s" => Command::Step(number(Some(1))?),
+            "next" | "n" => Command::Next,
+            "continue" | "c" => Command::Continue,
+            "finish" | "f" => Command::Finish,
+            "back" | "bk" => Command::Back(number(Some(1))?),
+            "last-write" | "lw" => Command::LastWrite(number(None)?),
+            "rewind" | "rw" => Command::Rewind,
+            "break" | "b" => {
+                let arg = arg.ok_or("break: missing LINE[:COLUMN]")?;
+                let (line, column) = arg.split_once(':').unwrap_or((arg, "1"));
+                let parse = |n: &str| {
+                    n.parse()
+                        .ok()
+                        .filter(|n| *n > 0)
+                        .ok_or_else(|| format!("invalid position: {arg}"))
+                };
+                Command::Break {
+                    line: parse(line)?,
+                    column: parse(column)?,
+                }
+            }
+            "delete" | "d" => Command::Delete(number(None)?),
+            "watch" | "w" => Command::Watch(number(None)?),
+            "unwatch" => Command::Unwatch(number(None)?),
+            "tape" | "t" => Command::Tape(number(Some(8))?),
+            "where" => Command::Where,
+            "info" | "i" => Command::Info,
+            "help" | "h" => Command::Help,
+            "quit" | "q" => Command::Quit,
+            _ => return Err(format!("unknown command: {name} (help for usage)")),
+        };
+        Ok(command)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::{ir::Block, parse::parse};
+
+    use super::*;
+
+    fn debugger<'a>(source: &'a str, block: &Block) -> Debugger<'a, io::Empty, io::Sink, Vec<u8>> {
+        let interpreter = InterPreter::builder()
+            .root_node(block)
+            .input(io::empty())
+            .output(io::sink())
+            .memory(vec![0u8; 8])
+            .record(1000)
+            .build();
+        Debugger::new(interpreter, source)
+    }
+
+    #[test]
+    fn test_breakpoint_and_step() {
+        let source = "++[>+\n+<-]>.";
+        let block = Block::from_ast(&parse(source).unwrap());
+        let mut debugger = debugger(source, &block);
+
+        // 2行目の`+`
+        let id = debugger.add_breakpoint(2, 1).unwrap();
+        assert_eq!(debugger.resume().unwrap(), Stop::Breakpoint { id });
+        assert_eq!(debugger.current_span(), Some(Span::new(6, 7)));
+        assert_eq!(debugger.interpreter().memory()[..2], [2, 1]);
+
+        // ループ1周分進むと、また同じ場所で止まる
+        assert_eq!(debugger.resume().unwrap(), Stop::Breakpoint { id });
+        assert_eq!(debugger.interpreter().memory()[..2], [1, 3]);
+
+        assert!(debugger.remove_breakpoint(id));
+        assert_eq!(debugger.step().unwrap(), Stop::Step);
+        assert_eq!(debugger.interpreter().memory()[..2], [1, 4]);
+        assert_eq!(debugger.resume().unwrap(), Stop::Finished);
+        assert_eq!(debugger.interpreter().memory()[..2], [0, 4]);
+
+        // 命令がない位置には、同じ行の次の命令に置く。なければ置けない
+        let source = "+ +\n\n";
+        let block = Block::from_ast(&parse(source).unwrap());
+        let mut debugger = self::debugger(source, &block);
+        let id = debugger.add_breakpoint(1, 2).unwrap();
+        assert_eq!(debugger.breakpoints().next().unwrap().1.pcs, [1]);
+        assert_eq!(debugger.resume().unwrap(), Stop::Breakpoint { id });
+        assert_eq!(debugger.add_breakpoint(2, 1), None);
+    }
+
+    #[test]
+    fn test_next_and_finish() {
+        let source = "+++[>++[>+<-]<-]>>.";
+        let block = Block::from_ast(&parse(source).unwrap());
+        let mut debugger = debugger(source, &block);
+
+        for _ in 0..3 {
+            debugger.step().unwrap();
+        }
+        // 外側のループを丸ごと実行する
+        assert_eq!(debugger.step_over().unwrap(), Stop::Step);
+        assert_eq!(debugger.interpreter().memory()[..3], [0, 0, 6]);
+        assert_eq!(debugger.current_span(), Some(Span::new(16, 17)));
+
+        let mut debugger = self::debugger(source, &block);
+        // 内側のループの中まで進めてから、内側のループを抜ける
+        let id = debugger.add_breakpoint(1, 10).unwrap();
+        assert_eq!(debugger.resume().unwrap(), Stop::Breakpoint { id });
+        debugger.remove_breakpoint(id);
+        assert_eq!(debugger.finish().unwrap(), Stop::Step);
+        assert_eq!(debugger.interpreter().memory()[..3], [3, 0, 2]);
+        assert_eq!(debugger.current_span(), Some(Span::new(13, 14)));
+        // 外側のループを抜ける
+        assert_eq!(debugger.finish().unwrap(), Stop::Step);
+        assert_eq!(debugger.interpreter().memory()[..3], [0, 0, 6]);
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        let source = "+++[>++<-]";
+        let block = Block::from_ast(&parse(source).unwrap());
+        let mut debugger = debugger(source, &block);
+
+        debugger.add_watchpoint(1);
+        assert_eq!(
+            debugger.resume().unwrap(),
+            Stop::Watchpoint {
+                index: 1,
+                old: 0,
+                new: 1
+            }
+        );
+        assert_eq!(debugger.tape(1), "[0]3 *[1]1 [2]0");
+        assert_eq!(
+            debugger.resume().unwrap(),
+            Stop::Watchpoint {
+                index: 1,
+                old: 1,
+                new: 2
+            }
+        );
+        assert!(debugger.remove_watchpoint(1));
+        assert_eq!(debugger.resume().unwrap(), Stop::Finished);
+    }
+
+    #[test]
+    fn test_reverse() {
+        let source = "++>+++[<+>-]<.>+.";
+        let block = Block::from_ast(&parse(source).unwrap());
+        let mut debugger = debugger(source, &block);
+        assert_eq!(debugger.resume().unwrap(), Stop::Finished);
+        assert_eq!(debugger.interpreter().memory()[..2], [5, 1]);
+
+        // 最後の`.`の前
+        assert_eq!(debugger.rewind_to_output(), Stop::Step);
+        assert_eq!(debugger.current_span(), Some(Span::new(16, 17)));
+        // その前の`.`
+        assert_eq!(debugger.rewind_to_output(), Stop::Step);
+        assert_eq!(debugger.current_span(), Some(Span::new(13, 14)));
+        assert_eq!(debugger.interpreter().memory()[..2], [5, 0]);
+
+        // [0]を最後に書き換えたのは、ループの最後の周の`<+`
+        assert_eq!(debugger.reverse_to_write(0), Stop::Step);
+        assert_eq!(debugger.current_span(), Some(Span::new(8, 9)));
+        assert_eq!(debugger.interpreter().memory()[..2], [4, 1]);
+
+        // 戻った後の値から監視する
+        debugger.add_watchpoint(1);
+        assert_eq!(debugger.step_back(), Stop::Step);
+        assert_eq!(debugger.interpreter().pointer(), 1);
+        assert_eq!(
+            debugger.resume().unwrap(),
+            Stop::Watchpoint {
+                index: 1,
+                old: 1,
+                new: 0
+            }
+        );
+        assert_eq!(debugger.reverse_to_write(7), Stop::Beginning);
+        assert_eq!(debugger.interpreter().count(), 0);
+        assert_eq!(
+            debugger.resume().unwrap(),
+            Stop::Watchpoint {
+                index: 1,
+                old: 0,
+                new: 1
+            }
+        );
+        debugger.remove_watchpoint(1);
+        assert_eq!(debugger.resume().unwrap(), Stop::Finished);
+        assert_eq!(debugger.interpreter().memory()[..2], [5, 1]);
+    }
+
+    #[test]
+    fn test_step_into_error() {
+        let source = ">+<<";
+        let block = Block::from_ast(&parse(source).unwrap());
+        let mut debugger = debugger(source, &block);
+        for _ in 0..3 {
+            assert_eq!(debugger.step().unwrap(), Stop::Step);
+        }
+        assert!(debugger.step().is_err());
+        assert_eq!(debugger.interpreter().count(), 3);
+        assert_eq!(debugger.interpreter().pointer(), 0);
+        assert_eq!(debugger.current_span(), Some(Span::new(3, 4)));
+
+        // エラーになった命令は実行していないので、1つ戻ると`<`の前
+        assert_eq!(debugger.step_back(), Stop::Step);
+        assert_eq!(debugger.interpreter().count(), 2);
+        assert_eq!(debugger.interpreter().pointer(), 1);
+        assert_eq!(debugger.current_span(), Some(Span::new(2, 3)));
+
+        assert_eq!(debugger.step().unwrap(), Stop::Step);
+        assert_eq!(debugger.interpreter().count(), 3);
+        assert_eq!(debugger.interpreter().pointer(), 0);
+        assert!(debugger.step().is_err());
+        assert_eq!(debugger.interpreter().count(), 3);
+    }
+
+    #[test]
+    fn test_command() {
+        assert_eq!("".parse(), Ok(Command::Step(1)));
+        assert_eq!("s 10".parse(), Ok(Command::Step(10)));
+        assert_eq!("b 3:4".parse(), Ok(Command::Break { line: 3, column: 4 }));
+        assert_eq!("break 3".parse(), Ok(Command::Break { line: 3, column: 1 }));
+        assert_eq!("tape".parse(), Ok(Command::Tape(8)));
+        assert_eq!("bk".parse(), Ok(Command::Back(1)));
+        assert_eq!("lw 2".parse(), Ok(Command::LastWrite(2)));
+        assert!("b 0:1".parse::<Command>().is_err());
+        assert!("watch".parse::<Command>().is_err());
+        assert!("jump 1".parse::<Command>().is_err());
+    }
+}
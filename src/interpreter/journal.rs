@@ -0,0 +1,56 @@
+//! 逆実行のための記録。命令ごとに、実行で書き換わる状態の実行前の値だけを残す。
+
+use std::collections::VecDeque;
+
+/// 1命令分の記録。`InterPreter::step_back`で、この命令を実行する前の状態に戻す。
+/// テープ上の位置は`InterPreter::origin`からの相対位置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record<C> {
+    /// 実行した命令の位置
+    pub pc: usize,
+    /// 実行前のポインタ
+    pub pointer: isize,
+    /// 書き換えたセルと、書き換える前の値
+    pub write: Option<(isize, C)>,
+    pub io: RecordedIo,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordedIo {
+    None,
+    /// 入力を読んだ。EOFなら`read`はfalse
+    Input {
+        read: bool,
+    },
+    Output,
+}
+
+/// 直近`limit`命令分の記録。古いものから捨てる
+#[derive(Debug)]
+pub(super) struct Journal<C> {
+    records: VecDeque<Record<C>>,
+    limit: usize,
+}
+impl<C> Journal<C> {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            records: VecDeque::new(),
+            limit,
+        }
+    }
+    pub fn push(&mut self, record: Record<C>) {
+        if self.limit == 0 {
+            return;
+        }
+        if self.records.len() == self.limit {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+    pub fn pop(&mut self) -> Option<Record<C>> {
+        self.records.pop_back()
+    }
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+}
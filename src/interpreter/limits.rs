@@ -0,0 +1,83 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+/// 別スレッドから実行を止めるためのハンドル。`clone`したものはすべて同じ状態を共有する
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+impl CancelHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// 実行が中断された理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptReason {
+    /// 実行した命令の数が上限に達した
+    MaxSteps,
+    /// 期限を過ぎた
+    Timeout,
+    /// `CancelHandle::cancel`が呼ばれた
+    Cancelled,
+}
+impl fmt::Display for InterruptReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            InterruptReason::MaxSteps => "step limit",
+            InterruptReason::Timeout => "timeout",
+            InterruptReason::Cancelled => "cancellation",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// 時刻の取得やアトミック変数の読み込みは命令ごとに行うには重いので、この命令数ごとに確認する
+const CHECK_INTERVAL: usize = 1 << 16;
+
+#[derive(Debug, Clone, Default)]
+pub(super) struct Limits {
+    pub max_steps: Option<usize>,
+    pub deadline: Option<Instant>,
+    pub cancel_handle: Option<CancelHandle>,
+}
+impl Limits {
+    /// `count`命令を実行した時点で、実行を続けて良いか確認する。
+    /// 続けて良ければ、次に確認すべき命令数を返す
+    pub fn check(&self, count: usize) -> Result<usize, InterruptReason> {
+        if self.max_steps.is_some_and(|max_steps| count >= max_steps) {
+            return Err(InterruptReason::MaxSteps);
+        }
+        if self
+            .cancel_handle
+            .as_ref()
+            .is_some_and(CancelHandle::is_cancelled)
+        {
+            return Err(InterruptReason::Cancelled);
+        }
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return Err(InterruptReason::Timeout);
+        }
+
+        let next = if self.deadline.is_some() || self.cancel_handle.is_some() {
+            count.saturating_add(CHECK_INTERVAL)
+        } else {
+            usize::MAX
+        };
+        Ok(self.max_steps.map_or(next, |max_steps| next.min(max_steps)))
+    }
+}
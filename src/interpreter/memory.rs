@@ -1,52 +1,248 @@
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
 use log::trace;
 
+use crate::cell::Cell;
+
+/// 長さが決まっているテープ（`Memory::fixed_len`が`Some`）の外に出たときの動作
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TapePolicy {
+    /// `Error::OutOfBounds`にする
+    #[default]
+    Bounded,
+    /// 反対側の端に回り込む
+    Wrap,
+    /// 右側なら`Memory::grow`でテープを伸ばす
+    Extend,
+}
+
+impl FromStr for TapePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bounded" => Ok(TapePolicy::Bounded),
+            "wrap" => Ok(TapePolicy::Wrap),
+            "extend" => Ok(TapePolicy::Extend),
+            _ => Err(format!("invalid tape policy: {s} (bounded, wrap, extend)")),
+        }
+    }
+}
+
+impl fmt::Display for TapePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TapePolicy::Bounded => "bounded",
+            TapePolicy::Wrap => "wrap",
+            TapePolicy::Extend => "extend",
+        };
+        f.write_str(name)
+    }
+}
+
+/// 確保済みの領域を`(先頭の位置, セル)`の形で返すイテレータ
+pub type Regions<'a, C> = Box<dyn Iterator<Item = (usize, &'a [C])> + 'a>;
+
 pub trait Memory {
+    type Cell: Cell;
+
     #[inline]
-    fn get(&mut self, index: usize) -> u8 {
+    fn get(&mut self, index: usize) -> Self::Cell {
         *self.get_mut(index)
     }
-    fn get_mut(&mut self, index: usize) -> &mut u8;
-    fn inner(&self) -> &[u8];
+    fn get_mut(&mut self, index: usize) -> &mut Self::Cell;
+    /// テープを伸ばさずに`index`番目のセルを読む。確保されていなければ0
+    fn peek(&self, index: usize) -> Self::Cell;
+    /// 確保済みの領域を位置の順に返す。どの領域にも含まれないセルは0
+    fn regions(&self) -> Regions<'_, Self::Cell>;
+    /// 長さが決まっていればその長さ。`get_mut`で自動的に伸びるなら`None`
+    fn fixed_len(&self) -> Option<usize>;
+    /// `index`番目のセルまで使えるように右に伸ばす。伸ばせなければfalse
+    #[inline]
+    fn grow(&mut self, _index: usize) -> bool {
+        false
+    }
+    /// 先頭より`len`個左のセルまで使えるように、左にセルを追加する。
+    /// 既存のセルを右にずらした数を返す。左に伸ばせなければ`None`
+    #[inline]
+    fn extend_left(&mut self, _len: usize) -> Option<usize> {
+        None
+    }
 }
 
-impl Memory for Vec<u8> {
+impl<C: Cell> Memory for Vec<C> {
+    type Cell = C;
+
     #[inline]
-    fn get_mut(&mut self, index: usize) -> &mut u8 {
+    fn get_mut(&mut self, index: usize) -> &mut C {
         &mut self[index]
     }
 
+    fn peek(&self, index: usize) -> C {
+        self.as_slice().get(index).copied().unwrap_or_default()
+    }
+    fn regions(&self) -> Regions<'_, C> {
+        Box::new(std::iter::once((0, &self[..])))
+    }
     #[inline]
-    fn inner(&self) -> &[u8] {
-        self
+    fn fixed_len(&self) -> Option<usize> {
+        Some(self.len())
+    }
+    fn grow(&mut self, index: usize) -> bool {
+        let len = (index + 1).max(self.len() * 2);
+        trace!("grow! {} -> {}", self.len(), len);
+        self.resize(len, C::default());
+        true
     }
 }
 
 #[derive(Debug)]
-pub struct AutoExtendMemory(Vec<u8>);
+pub struct AutoExtendMemory<C: Cell = u8>(Vec<C>);
 
-impl AutoExtendMemory {
-    pub fn new(memory: Vec<u8>) -> Self {
+impl<C: Cell> AutoExtendMemory<C> {
+    pub fn new(memory: Vec<C>) -> Self {
         Self(memory)
     }
     #[inline]
     fn extend(&mut self, index: usize) {
-        if self.0.len() <= index + 1 {
-            let extend_len = self.0.len() * 2 + index + 1;
+        extend_right(&mut self.0, index);
+    }
+}
 
-            trace!("extend! {} -> {}", self.0.len(), extend_len);
-            self.0.resize(extend_len, 0);
-        }
+#[inline]
+fn extend_right<C: Cell>(memory: &mut Vec<C>, index: usize) {
+    if memory.len() <= index + 1 {
+        let extend_len = memory.len() * 2 + index + 1;
+
+        trace!("extend! {} -> {}", memory.len(), extend_len);
+        memory.resize(extend_len, C::default());
     }
 }
 
-impl Memory for AutoExtendMemory {
+impl<C: Cell> Memory for AutoExtendMemory<C> {
+    type Cell = C;
+
     #[inline]
-    fn inner(&self) -> &[u8] {
-        &self.0
+    fn get_mut(&mut self, index: usize) -> &mut C {
+        self.extend(index);
+        &mut self.0[index]
+    }
+    fn peek(&self, index: usize) -> C {
+        self.0.peek(index)
+    }
+    fn regions(&self) -> Regions<'_, C> {
+        self.0.regions()
+    }
+    fn fixed_len(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// 左右どちらにも伸びるテープ。
+/// 左に伸びるときは既存のセルを右にずらすので、インタプリタのポインタもその分ずれる
+#[derive(Debug)]
+pub struct BidirectionalMemory<C: Cell = u8>(Vec<C>);
+
+impl<C: Cell> BidirectionalMemory<C> {
+    pub fn new(memory: Vec<C>) -> Self {
+        Self(memory)
     }
+}
+
+impl<C: Cell> Memory for BidirectionalMemory<C> {
+    type Cell = C;
+
     #[inline]
-    fn get_mut(&mut self, index: usize) -> &mut u8 {
-        self.extend(index);
+    fn get_mut(&mut self, index: usize) -> &mut C {
+        extend_right(&mut self.0, index);
         &mut self.0[index]
     }
+    fn peek(&self, index: usize) -> C {
+        self.0.peek(index)
+    }
+    fn regions(&self) -> Regions<'_, C> {
+        self.0.regions()
+    }
+    fn fixed_len(&self) -> Option<usize> {
+        None
+    }
+    fn extend_left(&mut self, len: usize) -> Option<usize> {
+        // 右に伸ばすときと同じく、何度も伸ばさなくて済むように倍々で伸ばす
+        let shift = len.max(self.0.len()).max(1);
+
+        trace!("extend left! {} -> {}", self.0.len(), self.0.len() + shift);
+        self.0
+            .splice(0..0, std::iter::repeat_n(C::default(), shift));
+        Some(shift)
+    }
+}
+
+/// 触ったページだけを確保するテープ。
+/// 遠く離れたセルを使うプログラムでも、間のセルの分のメモリは使わない
+#[derive(Debug)]
+pub struct PagedMemory<C: Cell = u8> {
+    /// ページ番号から`pages`の位置
+    table: BTreeMap<usize, usize>,
+    pages: Vec<Box<[C]>>,
+    /// 最後に使ったページ番号と`pages`の位置。
+    /// 同じページへのアクセスが続くことがほとんどなので、`table`を引かずに済ませる
+    hot: (usize, usize),
+}
+
+impl<C: Cell> PagedMemory<C> {
+    /// 1ページのセル数
+    pub const PAGE_LEN: usize = 4096;
+
+    pub fn new() -> Self {
+        Self {
+            table: BTreeMap::new(),
+            pages: Vec::new(),
+            // `index / PAGE_LEN`はusize::MAXにならない
+            hot: (usize::MAX, 0),
+        }
+    }
+    /// `page`番目のページの`pages`での位置。なければ確保する
+    #[cold]
+    fn slot(&mut self, page: usize) -> usize {
+        let pages = &mut self.pages;
+        *self.table.entry(page).or_insert_with(|| {
+            trace!("new page! {page}");
+            pages.push(vec![C::default(); Self::PAGE_LEN].into_boxed_slice());
+            pages.len() - 1
+        })
+    }
+}
+
+impl<C: Cell> Default for PagedMemory<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: Cell> Memory for PagedMemory<C> {
+    type Cell = C;
+
+    #[inline]
+    fn get_mut(&mut self, index: usize) -> &mut C {
+        let page = index / Self::PAGE_LEN;
+        if page != self.hot.0 {
+            self.hot = (page, self.slot(page));
+        }
+        &mut self.pages[self.hot.1][index % Self::PAGE_LEN]
+    }
+    fn peek(&self, index: usize) -> C {
+        self.table
+            .get(&(index / Self::PAGE_LEN))
+            .map_or_else(C::default, |&slot| self.pages[slot][index % Self::PAGE_LEN])
+    }
+    fn regions(&self) -> Regions<'_, C> {
+        Box::new(
+            self.table
+                .iter()
+                .map(|(&page, &slot)| (page * Self::PAGE_LEN, &self.pages[slot][..])),
+        )
+    }
+    fn fixed_len(&self) -> Option<usize> {
+        None
+    }
 }
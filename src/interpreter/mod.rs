@@ -1,16 +1,33 @@
-use crate::ir::{Block, BlockItem, Op};
+use crate::{
+    cell::{Cell, OverflowPolicy},
+    eof::EofBehavior,
+    ir::{Block, BlockItem, Op},
+    parse::Span,
+};
 
-use std::io::{self, Read, Write};
+use std::{
+    io::{self, Read, Write},
+    time::Instant,
+};
 
 use log::warn;
 use thiserror::Error;
 
-pub use memory::{AutoExtendMemory, Memory};
+pub use journal::{Record, RecordedIo};
+pub use limits::{CancelHandle, InterruptReason};
+pub use memory::{AutoExtendMemory, BidirectionalMemory, Memory, PagedMemory, Regions, TapePolicy};
+pub use profiling::{Frame, FrameKind};
+pub use snapshot::Snapshot;
 
-mod memory;
+use journal::Journal;
+use limits::Limits;
 
-// メモリセルのサイズを変えられるようにしたいわね
-const MOD: i32 = 256;
+pub mod debugger;
+mod journal;
+mod limits;
+mod memory;
+pub mod profiling;
+mod snapshot;
 
 type Result<T> = std::result::Result<T, Error>;
 
@@ -18,74 +35,174 @@ type Result<T> = std::result::Result<T, Error>;
 struct State<M: Memory> {
     pointer: usize,
     memory: M,
+    /// 最初の0番目のセルが今ある位置。テープが左に伸びると、その分右にずれる
+    origin: usize,
+    policy: TapePolicy,
+    overflow: OverflowPolicy,
 }
 impl<M: Memory> State<M> {
     #[inline]
-    fn at(&mut self) -> u8 {
-        self.memory.get(self.pointer)
+    fn at(&mut self) -> Result<M::Cell> {
+        self.at_offset(0)
     }
     #[inline]
-    fn at_offset(&mut self, offset: isize) -> Result<u8> {
+    fn at_offset(&mut self, offset: isize) -> Result<M::Cell> {
         self.at_offset_mut(offset).map(|v| *v)
     }
     #[inline]
-    fn at_offset_mut(&mut self, offset: isize) -> Result<&mut u8> {
+    fn at_offset_mut(&mut self, offset: isize) -> Result<&mut M::Cell> {
         let p = self.pointer as isize + offset;
-        if p >= 0 {
-            Ok(self.memory.get_mut(p as usize))
+        let in_range = p >= 0 && self.memory.fixed_len().is_none_or(|len| (p as usize) < len);
+        let index = if in_range {
+            p as usize
         } else {
-            Err(Error::NegativePointer(p))
+            self.out_of_range(offset)?
+        };
+        Ok(self.memory.get_mut(index))
+    }
+    /// ポインタから`offset`離れた位置がテープの外にあるとき、`policy`に従って使える位置を求める
+    #[cold]
+    fn out_of_range(&mut self, offset: isize) -> Result<usize> {
+        let p = self.pointer as isize + offset;
+        match (self.policy, self.memory.fixed_len()) {
+            (TapePolicy::Wrap, Some(len)) => Ok(p.rem_euclid(len as isize) as usize),
+            _ if p < 0 => {
+                self.extend_left(p)?;
+                Ok((self.pointer as isize + offset) as usize)
+            }
+            (TapePolicy::Extend, Some(_)) if self.memory.grow(p as usize) => Ok(p as usize),
+            (_, Some(len)) => Err(Error::out_of_bounds(p as usize, len)),
+            (_, None) => Ok(p as usize),
+        }
+    }
+    /// ポインタから`offset`離れたセルの、テープ上の実際の位置。負なら`None`
+    fn index_of(&self, offset: isize) -> Option<usize> {
+        let p = self.pointer as isize + offset;
+        match (self.policy, self.memory.fixed_len()) {
+            (TapePolicy::Wrap, Some(len)) => Some(p.rem_euclid(len as isize) as usize),
+            _ => usize::try_from(p).ok(),
+        }
+    }
+    /// 負の位置`pointer`まで使えるようにテープを左に伸ばし、ずれた分ポインタを動かす
+    #[cold]
+    fn extend_left(&mut self, pointer: isize) -> Result<()> {
+        let shift = self
+            .memory
+            .extend_left(pointer.unsigned_abs())
+            .ok_or(Error::negative_pointer(pointer))?;
+        self.pointer += shift;
+        self.origin += shift;
+        Ok(())
+    }
+    /// 回り込むテープでは、ポインタを常にテープの中に置く
+    #[cold]
+    fn wrap_pointer(&mut self, pointer: isize) {
+        if let Some(len) = self.memory.fixed_len() {
+            self.pointer = pointer.rem_euclid(len as isize) as usize;
+        }
+    }
+    #[inline]
+    fn add(&mut self, offset: isize, value: i32) -> Result<()> {
+        if self.overflow == OverflowPolicy::Wrap {
+            let value = M::Cell::from_i32(value);
+            return self
+                .at_offset_mut(offset)
+                .map(|a| *a = a.wrapping_add(value));
         }
+        self.update(offset, |a| a + value as i128)
     }
+    /// `offset`のセルに`value * x`を足す
     #[inline]
-    fn add(&mut self, offset: isize, value: u8) -> Result<()> {
-        self.at_offset_mut(offset)
-            .map(|a| *a = a.wrapping_add(value))
+    fn mul_add(&mut self, offset: isize, value: M::Cell, x: i32) -> Result<()> {
+        if self.overflow == OverflowPolicy::Wrap {
+            let value = value.wrapping_mul(M::Cell::from_i32(x));
+            return self
+                .at_offset_mut(offset)
+                .map(|a| *a = a.wrapping_add(value));
+        }
+        self.update(offset, |a| a + value.to_u64() as i128 * x as i128)
     }
     #[inline]
-    fn sub(&mut self, offset: isize, value: u8) -> Result<()> {
-        self.at_offset_mut(offset)
-            .map(|a| *a = a.wrapping_sub(value))
+    fn set(&mut self, offset: isize, value: i32) -> Result<()> {
+        if self.overflow == OverflowPolicy::Wrap {
+            return self
+                .at_offset_mut(offset)
+                .map(|a| *a = M::Cell::from_i32(value));
+        }
+        self.update(offset, |_| value as i128)
+    }
+    /// `offset`のセルを`f(今の値)`にする。セル幅に収まらなければ`overflow`に従う
+    #[cold]
+    fn update(&mut self, offset: isize, f: impl FnOnce(i128) -> i128) -> Result<()> {
+        let value = f(self.at_offset(offset)?.to_u64() as i128);
+        match self.overflow.apply(value) {
+            Some(value) => self.at_offset_mut(offset).map(|a| *a = value),
+            None => {
+                // `at_offset`でテープの中にあることは確認済み
+                let index = self.index_of(offset).unwrap_or_default();
+                Err(Error::overflow(index as isize - self.origin as isize))
+            }
+        }
     }
     #[inline]
     fn pointer_add(&mut self, value: usize) {
         self.pointer += value;
+        if self.policy == TapePolicy::Wrap {
+            self.wrap_pointer(self.pointer as isize);
+        }
     }
     #[inline]
     fn pointer_sub(&mut self, value: usize) -> Result<()> {
-        let ptr = self
-            .pointer
-            .checked_sub(value)
-            .ok_or(Error::NegativePointer(
-                self.pointer as isize - value as isize,
-            ))?;
-
-        self.pointer = ptr;
+        if self.pointer < value {
+            let pointer = self.pointer as isize - value as isize;
+            if self.policy == TapePolicy::Wrap && self.memory.fixed_len().is_some() {
+                self.wrap_pointer(pointer);
+                return Ok(());
+            }
+            self.extend_left(pointer)?;
+        }
+        self.pointer -= value;
 
         Ok(())
     }
     #[inline]
     fn output(&mut self, offset: isize, writer: &mut impl Write) -> Result<()> {
         let value = self.at_offset(offset)?;
-        writer.write_all(&[value])?;
+        writer.write_all(&[value.low_byte()])?;
         writer.flush()?;
         Ok(())
     }
+    /// 1byte読み込めたらtrueを返す
     #[inline]
-    fn input(&mut self, offset: isize, reader: &mut impl Read) -> Result<()> {
+    fn input(&mut self, offset: isize, reader: &mut impl Read, eof: EofBehavior) -> Result<bool> {
+        let cell = self.at_offset_mut(offset)?;
         let mut buf = [0];
 
-        reader.read_exact(&mut buf)?;
+        let n = loop {
+            match reader.read(&mut buf) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => break result?,
+            }
+        };
+        if n == 0 {
+            match eof {
+                EofBehavior::Unchanged => {}
+                EofBehavior::Zero => *cell = M::Cell::from_u8(0),
+                EofBehavior::MinusOne => *cell = M::Cell::from_i32(-1),
+                EofBehavior::Error => return Err(Error::unexpected_eof()),
+            }
+            return Ok(false);
+        }
         if &buf == b"\r" {
             warn!("\\r!!!");
         }
 
-        *self.at_offset_mut(offset)? = buf[0];
-        Ok(())
+        *cell = M::Cell::from_u8(buf[0]);
+        Ok(true)
     }
 }
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum FlatInstruction {
     Instruction(Op),
     // 行き先
@@ -93,30 +210,37 @@ pub enum FlatInstruction {
     WhileEnd(usize),
 }
 
-fn block_to_flat_instructions(block: &Block) -> Vec<FlatInstruction> {
-    fn inner(flat_instructions: &mut Vec<FlatInstruction>, block: &Block) {
-        for item in &block.items {
+/// 命令列と、各命令に対応するソースコード上の範囲を返す
+fn block_to_flat_instructions(block: &Block) -> (Vec<FlatInstruction>, Vec<Span>) {
+    fn inner(flat_instructions: &mut Vec<FlatInstruction>, spans: &mut Vec<Span>, block: &Block) {
+        for (item, span) in block.iter() {
             match item {
                 BlockItem::Loop(loop_block) => {
                     let loop_first = flat_instructions.len();
 
                     let begin_index = flat_instructions.len();
                     flat_instructions.push(FlatInstruction::WhileBegin(0));
+                    spans.push(span);
 
-                    inner(flat_instructions, loop_block);
+                    inner(flat_instructions, spans, loop_block);
 
                     // これまでの長さ + ループ内の長さ + Begin + End
                     flat_instructions[begin_index] =
                         FlatInstruction::WhileBegin(flat_instructions.len() + 1);
 
                     flat_instructions.push(FlatInstruction::WhileEnd(loop_first));
+                    spans.push(span);
+                }
+                BlockItem::Op(op) => {
+                    flat_instructions.push(FlatInstruction::Instruction(*op));
+                    spans.push(span);
                 }
-                BlockItem::Op(op) => flat_instructions.push(FlatInstruction::Instruction(*op)),
                 BlockItem::If(if_block) => {
                     let begin_index = flat_instructions.len();
                     flat_instructions.push(FlatInstruction::WhileBegin(0));
+                    spans.push(span);
 
-                    inner(flat_instructions, if_block);
+                    inner(flat_instructions, spans, if_block);
 
                     flat_instructions[begin_index] =
                         FlatInstruction::WhileBegin(flat_instructions.len());
@@ -127,24 +251,103 @@ fn block_to_flat_instructions(block: &Block) -> Vec<FlatInstruction> {
     }
 
     let mut instructions = vec![];
-    inner(&mut instructions, block);
+    let mut spans = vec![];
+    inner(&mut instructions, &mut spans, block);
 
-    instructions
+    (instructions, spans)
 }
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("I/O Error: {0}")]
     IoError(#[from] io::Error),
-    #[error("Pointer is Negative: {0}")]
-    NegativePointer(isize),
+    #[error("Pointer is Negative: {pointer} (at {span})")]
+    NegativePointer { pointer: isize, span: Span },
+    #[error("Unexpected EOF (at {span})")]
+    UnexpectedEof { span: Span },
+    /// セルの値がセル幅に収まらなくなった（`OverflowPolicy::Trap`のとき）。
+    /// `pointer`は`InterPreter::origin`からの相対位置
+    #[error("Cell overflow at {pointer} (at {span})")]
+    Overflow { pointer: isize, span: Span },
+    #[error("Pointer is out of bounds: {pointer} (len: {len}, at {span})")]
+    OutOfBounds {
+        pointer: usize,
+        len: usize,
+        span: Span,
+    },
+    /// 実行を中断した。`pc`の命令はまだ実行していない
+    #[error("Interrupted by {reason} after {steps} steps (pointer: {pointer}, at {span})")]
+    Interrupted {
+        reason: InterruptReason,
+        steps: usize,
+        pointer: usize,
+        pc: usize,
+        span: Span,
+    },
+}
+impl Error {
+    fn negative_pointer(pointer: isize) -> Self {
+        Self::NegativePointer {
+            pointer,
+            span: Span::default(),
+        }
+    }
+    fn out_of_bounds(pointer: usize, len: usize) -> Self {
+        Self::OutOfBounds {
+            pointer,
+            len,
+            span: Span::default(),
+        }
+    }
+    fn overflow(pointer: isize) -> Self {
+        Self::Overflow {
+            pointer,
+            span: Span::default(),
+        }
+    }
+    fn unexpected_eof() -> Self {
+        Self::UnexpectedEof {
+            span: Span::default(),
+        }
+    }
+    /// エラーの原因になった命令の範囲を設定する
+    fn with_span(self, span: Span) -> Self {
+        match self {
+            Self::NegativePointer { pointer, .. } => Self::NegativePointer { pointer, span },
+            Self::OutOfBounds { pointer, len, .. } => Self::OutOfBounds { pointer, len, span },
+            Self::Overflow { pointer, .. } => Self::Overflow { pointer, span },
+            Self::UnexpectedEof { .. } => Self::UnexpectedEof { span },
+            e => e,
+        }
+    }
+    /// エラーの原因になった命令のソースコード上の範囲
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Self::NegativePointer { span, .. }
+            | Self::OutOfBounds { span, .. }
+            | Self::Overflow { span, .. }
+            | Self::UnexpectedEof { span }
+            | Self::Interrupted { span, .. }
+                if !span.is_empty() =>
+            {
+                Some(*span)
+            }
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, serde::Serialize)]
 pub struct ProfilingResult {
     pub count: usize,
     pub instructions: Vec<FlatInstruction>,
+    /// 各命令のソースコード上の範囲
+    pub spans: Vec<Span>,
     pub instruction_count: Vec<i32>,
+    /// ループとifの入れ子
+    pub frames: Vec<Frame>,
+    /// 各命令を直接含むフレーム。どのループにも入っていなければ`None`
+    pub instruction_frames: Vec<Option<usize>>,
 }
 
 pub struct InterPreter<R: Read, W: Write, M: Memory> {
@@ -152,33 +355,170 @@ pub struct InterPreter<R: Read, W: Write, M: Memory> {
     input: R,
     output: W,
     instructions: Vec<FlatInstruction>,
+    spans: Vec<Span>,
+    eof: EofBehavior,
+    /// 次に実行する命令の位置
+    pc: usize,
+    /// これまでに実行した命令の数
+    count: usize,
+    limits: Limits,
+    /// 次に`limits`を確認する命令数
+    next_check: usize,
+    /// これまでに読み込んだ入力のbyte数
+    input_offset: u64,
+    /// 逆実行のための記録。`None`なら記録しない
+    journal: Option<Journal<M::Cell>>,
+    /// 巻き戻した`,`が読んだ入力。再実行のときは入力ではなくこちらから読む
+    replay_input: Vec<Option<u8>>,
+    /// 巻き戻した`.`の数。再実行のときはその分書き出さない
+    skip_output: u64,
 }
 impl<R: Read, W: Write, M: Memory> InterPreter<R, W, M> {
     pub fn builder<'a>() -> InterPreterBuilder<'a, R, W, M> {
         InterPreterBuilder::default()
     }
-    fn new(block: &Block, input: R, output: W, memory: M) -> Self {
-        let state = State { pointer: 0, memory };
-
-        let instructions = block_to_flat_instructions(block);
+    fn new(
+        (instructions, spans): (Vec<FlatInstruction>, Vec<Span>),
+        input: R,
+        output: W,
+        memory: M,
+        eof: EofBehavior,
+        limits: Limits,
+    ) -> Self {
+        let state = State {
+            pointer: 0,
+            memory,
+            origin: 0,
+            policy: TapePolicy::default(),
+            overflow: OverflowPolicy::default(),
+        };
 
         Self {
             state,
             instructions,
+            spans,
             input,
             output,
+            eof,
+            pc: 0,
+            count: 0,
+            limits,
+            next_check: 0,
+            input_offset: 0,
+            journal: None,
+            replay_input: Vec::new(),
+            skip_output: 0,
+        }
+    }
+    /// テープの内容を先頭から並べたもの。確保されていない部分は0で埋める。
+    /// 遠く離れたセルを使っている場合は`regions`を使う
+    pub fn memory(&self) -> Vec<M::Cell> {
+        let mut memory = Vec::new();
+        for (start, cells) in self.regions() {
+            memory.resize(start, M::Cell::default());
+            memory.extend_from_slice(cells);
         }
+        memory
     }
-    pub fn memory(&self) -> &[u8] {
-        self.state.memory.inner()
+    /// 確保済みの領域を`(先頭の位置, セル)`の形で、位置の順に返す
+    pub fn regions(&self) -> Regions<'_, M::Cell> {
+        self.state.memory.regions()
     }
     pub fn pointer(&self) -> usize {
         self.state.pointer
     }
+    /// 最初の0番目のセルが今ある位置。`BidirectionalMemory`が左に伸びると増える
+    pub fn origin(&self) -> usize {
+        self.state.origin
+    }
+    /// `index`番目のセル。まだ確保されていなければ0
+    pub fn cell(&self, index: usize) -> M::Cell {
+        self.state.memory.peek(index)
+    }
+    /// 次に実行する命令の位置
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+    /// これまでに実行した命令の数
+    pub fn count(&self) -> usize {
+        self.count
+    }
+    /// これまでに読み込んだ入力のbyte数
+    pub fn input_offset(&self) -> u64 {
+        self.input_offset
+    }
+    /// `step_back`で戻れる命令の数
+    pub fn recorded_steps(&self) -> usize {
+        self.journal.as_ref().map_or(0, Journal::len)
+    }
+    pub fn is_finished(&self) -> bool {
+        self.pc >= self.instructions.len()
+    }
+    pub fn instructions(&self) -> &[FlatInstruction] {
+        &self.instructions
+    }
+    pub fn spans(&self) -> &[Span] {
+        &self.spans
+    }
+    /// 次の命令が入出力なら、その内容
+    pub fn pending_io(&self) -> Option<PendingIo> {
+        let FlatInstruction::Instruction(op) = *self.instructions.get(self.pc)? else {
+            return None;
+        };
+        let index = |offset: i32| self.state.index_of(offset as isize);
+        match op {
+            Op::Input(offset) => Some(PendingIo::Input {
+                index: index(offset)?,
+            }),
+            Op::Out(offset) => {
+                let index = index(offset)?;
+                Some(PendingIo::Output {
+                    index,
+                    byte: self.cell(index).low_byte(),
+                })
+            }
+            _ => None,
+        }
+    }
+    /// 今の状態を取り出す。テープは領域ごとに末尾の0を省略する
+    pub fn snapshot(&self) -> Snapshot<M::Cell> {
+        let memory = self
+            .regions()
+            .filter_map(|(start, cells)| {
+                let len = cells.iter().rposition(|c| !c.is_zero())? + 1;
+                Some((start, cells[..len].to_vec()))
+            })
+            .collect();
+
+        Snapshot {
+            cell_width: M::Cell::WIDTH,
+            instructions: self.instructions.clone(),
+            spans: self.spans.clone(),
+            pc: self.pc,
+            count: self.count,
+            pointer: self.state.pointer,
+            origin: self.state.origin,
+            memory,
+            input_offset: self.input_offset,
+        }
+    }
+    fn restore(&mut self, snapshot: Snapshot<M::Cell>) {
+        self.pc = snapshot.pc;
+        self.count = snapshot.count;
+        self.state.pointer = snapshot.pointer;
+        self.state.origin = snapshot.origin;
+        self.input_offset = snapshot.input_offset;
+        for (start, cells) in snapshot.memory {
+            for (i, cell) in cells.into_iter().enumerate() {
+                *self.state.memory.get_mut(start + i) = cell;
+            }
+        }
+    }
     pub fn iter(&mut self) -> InterPreterIter<'_, R, W, M> {
         InterPreterIter(self)
     }
 
+    /// 最後まで実行し、これまでに実行した命令の数を返す
     pub fn run(&mut self) -> Result<usize> {
         self._run(|_| {})
     }
@@ -186,92 +526,242 @@ impl<R: Read, W: Write, M: Memory> InterPreter<R, W, M> {
         let mut instruction_count = vec![0; self.instructions.len()];
 
         let count = self._run(|now| instruction_count[now] += 1)?;
+        let (frames, instruction_frames) = profiling::frames(&self.instructions, &self.spans);
 
         Ok(ProfilingResult {
             count,
             instruction_count,
+            frames,
+            instruction_frames,
             instructions: self.instructions,
+            spans: self.spans,
         })
     }
-    fn _run(&mut self, mut before_exec: impl FnMut(usize)) -> Result<usize> {
-        let mut now = 0;
-        let mut count = 0;
-
-        while let Some(ins) = self.instructions.get(now) {
-            before_exec(now);
-            count += 1;
-            match *ins {
-                FlatInstruction::Instruction(instruction) => {
-                    match instruction {
-                        Op::MovePtr(offset) => {
-                            if offset < 0 {
-                                self.state.pointer_sub(offset.unsigned_abs() as usize)?;
-                            } else {
-                                self.state.pointer_add(offset as usize);
-                            }
-                        }
-                        Op::Add(value, to_offset) => {
-                            let x = value % MOD;
-                            if x.is_positive() {
-                                self.state.add(to_offset as isize, x.unsigned_abs() as u8)?;
-                            } else {
-                                self.state.sub(to_offset as isize, x.unsigned_abs() as u8)?;
-                            }
-                        }
-                        Op::Mul(to, x, offset) => {
-                            let value = self.state.at_offset(offset as isize)? as i32;
-                            let value = value.wrapping_mul(x) % MOD;
-
-                            let to = to as isize + offset as isize;
-
-                            if value < 0 {
-                                self.state.sub(to, value.unsigned_abs() as u8)?;
-                            } else {
-                                self.state.add(to, value.unsigned_abs() as u8)?;
-                            }
-                        }
-                        Op::Out(offset) => self.state.output(offset as isize, &mut self.output)?,
-                        Op::Input(offset) => {
-                            self.state.input(offset as isize, &mut self.input)?;
-                        }
-                        Op::Set(value, offset) => {
-                            *self.state.at_offset_mut(offset as isize)? = (value % MOD) as u8;
-                        }
-                        Op::Lick(x) => {
-                            while self.state.at() != 0 {
-                                if x < 0 {
-                                    self.state.pointer_sub(x.unsigned_abs() as usize)?;
-                                } else {
-                                    self.state.pointer_add(x as usize);
-                                }
-                            }
-                        }
-                    };
-                    now += 1
+    /// 1命令だけ実行する。すでに終了していれば`None`を返す
+    pub fn step(&mut self) -> Result<Option<Step<M::Cell>>> {
+        let pc = self.pc;
+        let Some(&instruction) = self.instructions.get(pc) else {
+            return Ok(None);
+        };
+        if self.count >= self.next_check {
+            self.check_limits()?;
+        }
+        let record = self
+            .journal
+            .is_some()
+            .then(|| self.record_before(pc, instruction));
+        let input_offset = self.input_offset;
+        self.pc = self.next_pc(pc, instruction)?;
+        self.count += 1;
+
+        if let (Some(journal), Some(mut record)) = (&mut self.journal, record) {
+            if let RecordedIo::Input { read } = &mut record.io {
+                *read = self.input_offset > input_offset;
+            }
+            journal.push(record);
+        }
+
+        Ok(Some(Step {
+            pc,
+            instruction,
+            span: self.spans[pc],
+            pointer: self.state.pointer,
+            cell: self.state.memory.peek(self.state.pointer),
+        }))
+    }
+    /// `instruction`を実行する前の状態のうち、実行で変わるものを記録する
+    fn record_before(&self, pc: usize, instruction: FlatInstruction) -> Record<M::Cell> {
+        let (offset, io) = match instruction {
+            FlatInstruction::Instruction(Op::Add(_, offset) | Op::Set(_, offset)) => {
+                (Some(offset as isize), RecordedIo::None)
+            }
+            FlatInstruction::Instruction(Op::Mul(to, _, offset)) => {
+                (Some(to as isize + offset as isize), RecordedIo::None)
+            }
+            FlatInstruction::Instruction(Op::Input(offset)) => {
+                (Some(offset as isize), RecordedIo::Input { read: false })
+            }
+            FlatInstruction::Instruction(Op::Out(_)) => (None, RecordedIo::Output),
+            _ => (None, RecordedIo::None),
+        };
+        // テープが左に伸びても変わらないように、`origin`からの相対位置で記録する
+        let pointer = self.state.pointer as isize - self.state.origin as isize;
+        let write = offset.map(|offset| match self.state.index_of(offset) {
+            Some(index) => (
+                index as isize - self.state.origin as isize,
+                self.cell(index),
+            ),
+            // 負の位置はテープが左に伸びてから書き込まれる
+            None => (pointer + offset, M::Cell::default()),
+        });
+
+        Record {
+            pc,
+            pointer,
+            write,
+            io,
+        }
+    }
+    /// 最後に実行した命令を取り消し、その命令を実行する前の状態に戻す。記録がなければ`None`。
+    /// 取り消した`,`はもう一度実行すると同じ入力を読み、取り消した`.`はもう一度は書き出さない
+    pub fn step_back(&mut self) -> Option<Record<M::Cell>> {
+        let record = self.journal.as_mut()?.pop()?;
+
+        let origin = self.state.origin as isize;
+        if let Some((index, old)) = record.write {
+            let cell = self.state.memory.get_mut((origin + index) as usize);
+            if let RecordedIo::Input { read } = record.io {
+                self.replay_input.push(read.then(|| cell.low_byte()));
+                if read {
+                    self.input_offset -= 1;
                 }
-                FlatInstruction::WhileBegin(to) if self.state.at() == 0 => now = to,
-                FlatInstruction::WhileBegin(_) => now += 1,
-                FlatInstruction::WhileEnd(to) => now = to,
             }
+            *cell = old;
         }
+        if record.io == RecordedIo::Output {
+            self.skip_output += 1;
+        }
+        self.pc = record.pc;
+        self.state.pointer = (origin + record.pointer) as usize;
+        self.count -= 1;
 
-        Ok(count)
+        Some(record)
+    }
+    fn _run(&mut self, mut before_exec: impl FnMut(usize)) -> Result<usize> {
+        if self.journal.is_some() {
+            // 記録するときは1命令ずつ実行する
+            while self.pc < self.instructions.len() {
+                before_exec(self.pc);
+                self.step()?;
+            }
+            return Ok(self.count);
+        }
+        while let Some(&instruction) = self.instructions.get(self.pc) {
+            if self.count >= self.next_check {
+                self.check_limits()?;
+            }
+            before_exec(self.pc);
+            self.pc = self.next_pc(self.pc, instruction)?;
+            self.count += 1;
+        }
+
+        Ok(self.count)
+    }
+    #[cold]
+    fn check_limits(&mut self) -> Result<()> {
+        match self.limits.check(self.count) {
+            Ok(next_check) => {
+                self.next_check = next_check;
+                Ok(())
+            }
+            Err(reason) => Err(Error::Interrupted {
+                reason,
+                steps: self.count,
+                pointer: self.state.pointer,
+                pc: self.pc,
+                span: self.spans[self.pc],
+            }),
+        }
+    }
+    /// `now`番目の命令を実行し、次に実行する命令の位置を返す。
+    /// エラーのときは`pc`を進めないので、エラーの原因になった命令を指したままになる
+    #[inline]
+    fn next_pc(&mut self, now: usize, instruction: FlatInstruction) -> Result<usize> {
+        match instruction {
+            FlatInstruction::Instruction(instruction) => {
+                self.exec(instruction)
+                    .map_err(|e| e.with_span(self.spans[now]))?;
+                Ok(now + 1)
+            }
+            FlatInstruction::WhileBegin(to) => {
+                let cell = self.state.at().map_err(|e| e.with_span(self.spans[now]))?;
+                Ok(if cell.is_zero() { to } else { now + 1 })
+            }
+            FlatInstruction::WhileEnd(to) => Ok(to),
+        }
+    }
+    #[inline]
+    fn exec(&mut self, instruction: Op) -> Result<()> {
+        match instruction {
+            Op::MovePtr(offset) => {
+                if offset < 0 {
+                    self.state.pointer_sub(offset.unsigned_abs() as usize)?;
+                } else {
+                    self.state.pointer_add(offset as usize);
+                }
+            }
+            Op::Add(value, to_offset) => {
+                self.state.add(to_offset as isize, value)?;
+            }
+            Op::Mul(to, x, offset) => {
+                let value = self.state.at_offset(offset as isize)?;
+
+                let to = to as isize + offset as isize;
+
+                self.state.mul_add(to, value, x)?;
+            }
+            Op::Out(offset) if self.skip_output > 0 => {
+                self.state.at_offset(offset as isize)?;
+                self.skip_output -= 1;
+            }
+            Op::Out(offset) => self.state.output(offset as isize, &mut self.output)?,
+            Op::Input(offset) => {
+                let read = match self.replay_input.pop() {
+                    Some(byte) => {
+                        let buf = byte.map(|byte| [byte]);
+                        let mut replay: &[u8] = buf.as_ref().map_or(&[], |buf| buf);
+                        self.state.input(offset as isize, &mut replay, self.eof)?
+                    }
+                    None => self
+                        .state
+                        .input(offset as isize, &mut self.input, self.eof)?,
+                };
+                if read {
+                    self.input_offset += 1;
+                }
+            }
+            Op::Set(value, offset) => {
+                self.state.set(offset as isize, value)?;
+            }
+            Op::Lick(x) => {
+                while !self.state.at()?.is_zero() {
+                    if x < 0 {
+                        self.state.pointer_sub(x.unsigned_abs() as usize)?;
+                    } else {
+                        self.state.pointer_add(x as usize);
+                    }
+                }
+            }
+        };
+        Ok(())
     }
 }
 
 pub struct InterPreterBuilder<'a, R: Read, W: Write, M: Memory> {
     root_node: Option<&'a Block>,
+    snapshot: Option<Snapshot<M::Cell>>,
     memory: Option<M>,
     input: Option<R>,
     output: Option<W>,
+    eof: EofBehavior,
+    limits: Limits,
+    record: Option<usize>,
+    tape: TapePolicy,
+    overflow: OverflowPolicy,
 }
 impl<'a, R: Read, W: Write, M: Memory> Default for InterPreterBuilder<'a, R, W, M> {
     fn default() -> Self {
         Self {
             root_node: Default::default(),
+            snapshot: Default::default(),
             memory: Default::default(),
             input: Default::default(),
             output: Default::default(),
+            eof: Default::default(),
+            limits: Default::default(),
+            record: Default::default(),
+            tape: Default::default(),
+            overflow: Default::default(),
         }
     }
 }
@@ -282,6 +772,14 @@ impl<'a, R: Read, W: Write, M: Memory> InterPreterBuilder<'a, R, W, M> {
             ..self
         }
     }
+    /// `root_node`の代わりに、スナップショットの命令列と状態から始める。
+    /// `memory`はスナップショットのテープが収まる長さが必要
+    pub fn snapshot(self, snapshot: Snapshot<M::Cell>) -> Self {
+        Self {
+            snapshot: Some(snapshot),
+            ..self
+        }
+    }
     pub fn memory(self, memory: M) -> Self {
         Self {
             memory: Some(memory),
@@ -300,24 +798,123 @@ impl<'a, R: Read, W: Write, M: Memory> InterPreterBuilder<'a, R, W, M> {
             ..self
         }
     }
+    /// 入力がEOFに達したときの`,`の動作。デフォルトはエラー
+    pub fn eof(self, eof: EofBehavior) -> Self {
+        Self { eof, ..self }
+    }
+    /// 実行する命令の数の上限。超えると`Error::Interrupted`になる
+    pub fn max_steps(mut self, max_steps: usize) -> Self {
+        self.limits.max_steps = Some(max_steps);
+        self
+    }
+    /// この時刻を過ぎると`Error::Interrupted`になる
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.limits.deadline = Some(deadline);
+        self
+    }
+    /// `cancel_handle.cancel()`が呼ばれると`Error::Interrupted`になる
+    pub fn cancel_handle(mut self, cancel_handle: CancelHandle) -> Self {
+        self.limits.cancel_handle = Some(cancel_handle);
+        self
+    }
+    /// 長さが決まっているテープの外に出たときの動作。デフォルトはエラー
+    pub fn tape(self, tape: TapePolicy) -> Self {
+        Self { tape, ..self }
+    }
+    /// セルの値が収まらなくなったときの動作。デフォルトはwrap。
+    /// 最適化で消えた演算（`[+]`を0にするなど）のオーバーフローは検出しない
+    pub fn overflow(self, overflow: OverflowPolicy) -> Self {
+        Self { overflow, ..self }
+    }
+    /// 直近`limit`命令分を記録し、`InterPreter::step_back`で戻れるようにする
+    pub fn record(self, limit: usize) -> Self {
+        Self {
+            record: Some(limit),
+            ..self
+        }
+    }
+    /// スナップショットのテープやポインタが`memory`に収まらなければpanicする
     pub fn build(self) -> InterPreter<R, W, M> {
+        self.try_build().unwrap()
+    }
+    /// スナップショットのテープやポインタが長さの決まった`memory`に収まらなければ、
+    /// `Error::OutOfBounds`を返す
+    pub fn try_build(self) -> Result<InterPreter<R, W, M>> {
         let Self {
             root_node,
+            snapshot,
             memory,
             input,
             output,
+            eof,
+            limits,
+            record,
+            tape,
+            overflow,
         } = self;
 
-        let root_node = root_node.unwrap();
         let input = input.unwrap();
         let output = output.unwrap();
         let memory = memory.unwrap();
 
-        InterPreter::new(root_node, input, output, memory)
+        if let (Some(snapshot), Some(len)) = (&snapshot, memory.fixed_len()) {
+            let required = snapshot.memory_len().max(snapshot.pointer + 1);
+            if required > len {
+                return Err(Error::out_of_bounds(required - 1, len));
+            }
+        }
+
+        let program = match &snapshot {
+            Some(snapshot) => (snapshot.instructions.clone(), snapshot.spans.clone()),
+            None => block_to_flat_instructions(root_node.unwrap()),
+        };
+        let mut interpreter = InterPreter::new(program, input, output, memory, eof, limits);
+        interpreter.journal = record.map(Journal::new);
+        interpreter.state.policy = tape;
+        interpreter.state.overflow = overflow;
+        if let Some(snapshot) = snapshot {
+            interpreter.restore(snapshot);
+        }
+        Ok(interpreter)
     }
 }
 
+/// 1命令ずつ実行するイテレータ。エラーになったら、その後は同じエラーを返し続ける
 pub struct InterPreterIter<'a, R: Read, W: Write, M: Memory>(&'a mut InterPreter<R, W, M>);
+impl<R: Read, W: Write, M: Memory> InterPreterIter<'_, R, W, M> {
+    pub fn interpreter(&self) -> &InterPreter<R, W, M> {
+        self.0
+    }
+}
+impl<R: Read, W: Write, M: Memory> Iterator for InterPreterIter<'_, R, W, M> {
+    type Item = Result<Step<M::Cell>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.step().transpose()
+    }
+}
+
+/// 1命令を実行した結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Step<C> {
+    /// 実行した命令の位置
+    pub pc: usize,
+    pub instruction: FlatInstruction,
+    pub span: Span,
+    /// 実行後のポインタ
+    pub pointer: usize,
+    /// 実行後にポインタが指すセル
+    pub cell: C,
+}
+
+/// 次の命令が行う入出力
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PendingIo {
+    /// `index`番目のセルに1byte読み込む
+    Input { index: usize },
+    /// `index`番目のセルの下位8bitを出力する
+    Output { index: usize, byte: u8 },
+}
 
 #[cfg(test)]
 mod test {
@@ -339,18 +936,499 @@ mod test {
     #[test]
     fn test_memory_extend() {
         {
-            let mut memory = AutoExtendMemory::new(Vec::new());
+            let mut memory = AutoExtendMemory::<u8>::new(Vec::new());
             memory.get(0); // 自動で伸びるはず...!
 
-            assert!(!memory.inner().is_empty());
+            assert!(!memory.regions().next().unwrap().1.is_empty());
         }
 
         {
-            let mut memory = AutoExtendMemory::new(Vec::new());
+            let mut memory = AutoExtendMemory::<u8>::new(Vec::new());
             memory.get_mut(0); // 自動で伸びるはず...!2
 
-            assert!(!memory.inner().is_empty());
+            assert!(!memory.regions().next().unwrap().1.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_paged_memory() {
+        const PAGE_LEN: usize = PagedMemory::<u8>::PAGE_LEN;
+
+        let mut memory = PagedMemory::<u16>::new();
+        *memory.get_mut(10_000_000) = 5;
+        *memory.get_mut(3) = 1;
+        assert_eq!(memory.peek(10_000_000), 5);
+        assert_eq!(memory.peek(10_000_001), 0);
+        assert_eq!(memory.peek(20_000_000), 0);
+        let regions: Vec<_> = memory.regions().map(|(start, _)| start).collect();
+        assert_eq!(regions, [0, 10_000_000 / PAGE_LEN * PAGE_LEN]);
+
+        // ページをまたいで動くプログラム
+        let source = format!("+[{}+]", ">".repeat(PAGE_LEN + 1));
+        let block = block(&source);
+        let mut interpreter = InterPreter::builder()
+            .root_node(&block)
+            .input(io::empty())
+            .output(io::sink())
+            .memory(PagedMemory::<u8>::new())
+            .max_steps(PAGE_LEN * 20)
+            .build();
+        assert!(interpreter.run().is_err());
+        let snapshot = interpreter.snapshot();
+        assert!(snapshot.memory.len() > 2);
+        assert!(snapshot
+            .memory
+            .iter()
+            .all(|(start, cells)| start % PAGE_LEN == 0 && cells.len() <= PAGE_LEN));
+        assert_eq!(interpreter.cell(PAGE_LEN + 1), 1);
+    }
+
+    #[test]
+    fn test_tape_policy() {
+        fn run(source: &str, tape: TapePolicy) -> Result<Vec<u8>> {
+            let block = block_opt(source);
+            let mut interpreter = InterPreter::builder()
+                .root_node(&block)
+                .input(io::empty())
+                .output(io::sink())
+                .memory(vec![0u8; 4])
+                .tape(tape)
+                .record(100)
+                .build();
+            interpreter.run()?;
+            let memory = interpreter.memory();
+            // 巻き戻しも実際の位置で行う
+            while interpreter.step_back().is_some() {}
+            assert!(interpreter.memory().iter().all(|c| *c == 0));
+            Ok(memory)
+        }
+
+        let error = run(">>>>+", TapePolicy::Bounded).unwrap_err();
+        assert!(matches!(
+            error,
+            Error::OutOfBounds {
+                pointer: 4,
+                len: 4,
+                ..
+            }
+        ));
+        assert_eq!(error.span(), Some(Span::new(4, 5)));
+        // 触らなければ外に出ても良い
+        assert_eq!(run(">>>><<<<+", TapePolicy::Bounded).unwrap(), [1, 0, 0, 0]);
+
+        assert_eq!(run("<+>>>>++", TapePolicy::Wrap).unwrap(), [0, 0, 0, 3]);
+        assert_eq!(run(">>>++[>+<-]", TapePolicy::Wrap).unwrap(), [2, 0, 0, 0]);
+        assert_eq!(run("+[<+>-]", TapePolicy::Wrap).unwrap(), [0, 0, 0, 1]);
+
+        assert_eq!(
+            run(">>>>>+", TapePolicy::Extend).unwrap()[..6],
+            [0, 0, 0, 0, 0, 1]
+        );
+        assert!(matches!(
+            run("<+", TapePolicy::Extend),
+            Err(Error::NegativePointer { pointer: -1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_overflow() {
+        fn run(source: &str, overflow: OverflowPolicy) -> Result<Vec<u8>> {
+            let mut results = [block(source), block_opt(source)].map(|block| {
+                let mut interpreter = InterPreter::builder()
+                    .root_node(&block)
+                    .input(io::empty())
+                    .output(io::sink())
+                    .memory(vec![0u8; 2])
+                    .overflow(overflow)
+                    .build();
+                interpreter.run().map(|_| interpreter.memory())
+            });
+            // 最適化してもしなくても同じ結果になる
+            match &results {
+                [Ok(a), Ok(b)] => assert_eq!(a, b),
+                [Err(Error::Overflow { pointer: a, .. }), Err(Error::Overflow { pointer: b, .. })] =>
+                {
+                    assert_eq!(a, b)
+                }
+                _ => panic!("{source}: {results:?}"),
+            }
+            std::mem::replace(&mut results[1], Ok(Vec::new()))
+        }
+
+        let add = "-";
+        let mul = format!("{}[->+++<]", "+".repeat(100));
+        let set = "+[-]-";
+        let max = "+".repeat(255);
+
+        assert_eq!(run(add, OverflowPolicy::Wrap).unwrap(), [255, 0]);
+        assert_eq!(run(&mul, OverflowPolicy::Wrap).unwrap(), [0, 44]);
+        assert_eq!(run(set, OverflowPolicy::Wrap).unwrap(), [255, 0]);
+
+        assert_eq!(run(add, OverflowPolicy::Saturate).unwrap(), [0, 0]);
+        assert_eq!(run(&mul, OverflowPolicy::Saturate).unwrap(), [0, 255]);
+        assert_eq!(run(set, OverflowPolicy::Saturate).unwrap(), [0, 0]);
+
+        let error = run(add, OverflowPolicy::Trap).unwrap_err();
+        assert!(matches!(error, Error::Overflow { pointer: 0, .. }));
+        assert_eq!(error.span(), Some(Span::new(0, 1)));
+        assert!(matches!(
+            run(&mul, OverflowPolicy::Trap),
+            Err(Error::Overflow { pointer: 1, .. })
+        ));
+        assert!(matches!(
+            run(set, OverflowPolicy::Trap),
+            Err(Error::Overflow { pointer: 0, .. })
+        ));
+        assert_eq!(run(&max, OverflowPolicy::Trap).unwrap(), [255, 0]);
+    }
+
+    #[test]
+    fn test_cell_width() {
+        fn run<C: Cell>(source: &str) -> Vec<C> {
+            let block = block(source);
+            let mut interpreter = InterPreter::builder()
+                .root_node(&block)
+                .input(io::empty())
+                .output(io::sink())
+                .memory(vec![C::default(); 4])
+                .build();
+            interpreter.run().unwrap();
+            interpreter.memory().to_vec()
+        }
+
+        let source = "->++++++++++++++++[>++++++++++++++++<-]";
+        assert_eq!(run::<u8>(source), [255, 0, 0, 0]);
+        assert_eq!(run::<u16>(source), [65535, 0, 256, 0]);
+        assert_eq!(run::<u32>(source), [u32::MAX, 0, 256, 0]);
+        assert_eq!(run::<u64>(source), [u64::MAX, 0, 256, 0]);
+    }
+
+    #[test]
+    fn test_negative_pointer_span() {
+        fn error_span(block: &Block) -> Option<Span> {
+            let mut interpreter = InterPreter::builder()
+                .root_node(block)
+                .input(io::empty())
+                .output(io::sink())
+                .memory(vec![0u8; 4])
+                .build();
+            interpreter.run().unwrap_err().span()
+        }
+
+        let source = "++\n>[-]<<<";
+        assert_eq!(error_span(&block(source)), Some(Span::new(8, 9)));
+        // 最適化でまとめられた命令は、まとめる前の範囲をすべて含む
+        assert_eq!(error_span(&block_opt(source)), Some(Span::new(3, 10)));
+    }
+
+    #[test]
+    fn test_eof() {
+        fn run(eof: EofBehavior) -> Result<Vec<u16>> {
+            let block = block("+>+,<,");
+            let mut interpreter = InterPreter::builder()
+                .root_node(&block)
+                .input(&b"a"[..])
+                .output(io::sink())
+                .memory(vec![0u16; 2])
+                .eof(eof)
+                .build();
+            interpreter.run()?;
+            Ok(interpreter.memory().to_vec())
         }
+
+        assert_eq!(run(EofBehavior::Unchanged).unwrap(), [1, 97]);
+        assert_eq!(run(EofBehavior::Zero).unwrap(), [0, 97]);
+        assert_eq!(run(EofBehavior::MinusOne).unwrap(), [65535, 97]);
+        let error = run(EofBehavior::Error).unwrap_err();
+        assert!(matches!(error, Error::UnexpectedEof { .. }));
+        assert_eq!(error.span(), Some(Span::new(5, 6)));
+    }
+
+    #[test]
+    fn test_iter() {
+        let block = block("+>,.");
+        let mut interpreter = InterPreter::builder()
+            .root_node(&block)
+            .input(&b"a"[..])
+            .output(Vec::new())
+            .memory(vec![0u8; 4])
+            .build();
+
+        let mut iter = interpreter.iter();
+        let step = iter.next().unwrap().unwrap();
+        assert_eq!(
+            (step.pc, step.span, step.pointer, step.cell),
+            (0, Span::new(0, 1), 0, 1)
+        );
+        iter.next().unwrap().unwrap();
+        assert_eq!(
+            iter.interpreter().pending_io(),
+            Some(PendingIo::Input { index: 1 })
+        );
+        let step = iter.next().unwrap().unwrap();
+        assert_eq!(step.cell, b'a');
+        assert_eq!(
+            iter.interpreter().pending_io(),
+            Some(PendingIo::Output {
+                index: 1,
+                byte: b'a'
+            })
+        );
+        assert_eq!(iter.count(), 1);
+
+        assert!(interpreter.is_finished());
+        assert_eq!(interpreter.count(), 4);
+        assert_eq!(interpreter.output, b"a");
+    }
+
+    #[test]
+    fn test_limits() {
+        fn builder(block: &Block) -> InterPreterBuilder<'_, io::Empty, io::Sink, Vec<u8>> {
+            InterPreter::builder()
+                .root_node(block)
+                .input(io::empty())
+                .output(io::sink())
+                .memory(vec![0u8; 4])
+        }
+        let block = block(">+[]");
+
+        let error = builder(&block).max_steps(10).build().run().unwrap_err();
+        assert!(matches!(
+            error,
+            Error::Interrupted {
+                reason: InterruptReason::MaxSteps,
+                steps: 10,
+                pointer: 1,
+                ..
+            }
+        ));
+        assert_eq!(error.span(), Some(Span::new(2, 4)));
+        // 上限ちょうどで終わるなら中断しない
+        assert_eq!(
+            builder(&self::block("++"))
+                .max_steps(2)
+                .build()
+                .run()
+                .unwrap(),
+            2
+        );
+
+        let error = builder(&block)
+            .deadline(Instant::now() + std::time::Duration::from_millis(10))
+            .build()
+            .run()
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            Error::Interrupted {
+                reason: InterruptReason::Timeout,
+                ..
+            }
+        ));
+
+        let cancel_handle = CancelHandle::new();
+        let mut interpreter = builder(&block).cancel_handle(cancel_handle.clone()).build();
+        let thread = std::thread::spawn(move || interpreter.run());
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        cancel_handle.cancel();
+        let error = thread.join().unwrap().unwrap_err();
+        assert!(matches!(
+            error,
+            Error::Interrupted {
+                reason: InterruptReason::Cancelled,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let block = block(",[.,]+++[>+<-]");
+        let mut output = Vec::new();
+        let mut interpreter = InterPreter::builder()
+            .root_node(&block)
+            .input(&b"abc"[..])
+            .output(&mut output)
+            .memory(vec![0u16; 4])
+            .eof(EofBehavior::Zero)
+            .max_steps(7)
+            .build();
+        assert!(matches!(interpreter.run(), Err(Error::Interrupted { .. })));
+        assert_eq!(interpreter.input_offset(), 2);
+
+        let mut json = Vec::new();
+        interpreter.snapshot().write(&mut json).unwrap();
+        let snapshot = Snapshot::<u16>::read(&json[..]).unwrap();
+        assert_eq!(snapshot, interpreter.snapshot());
+        assert!(Snapshot::<u8>::read(&json[..]).is_err());
+
+        // 読み込み済みの入力は読み飛ばして再開する
+        let mut resumed_output = Vec::new();
+        let mut resumed = InterPreter::builder()
+            .snapshot(snapshot)
+            .input(&b"c"[..])
+            .output(&mut resumed_output)
+            .memory(vec![0u16; 4])
+            .eof(EofBehavior::Zero)
+            .build();
+        assert_eq!(resumed.run().unwrap(), 36);
+        assert_eq!(resumed.memory(), [0, 3, 0, 0]);
+        assert_eq!(resumed.input_offset(), 3);
+        drop(interpreter);
+        output.extend(resumed_output);
+        assert_eq!(output, b"abc");
+    }
+
+    #[test]
+    fn test_snapshot_too_short_memory() {
+        let block = block(">>>+<");
+        let mut interpreter = InterPreter::builder()
+            .root_node(&block)
+            .input(io::empty())
+            .output(io::sink())
+            .memory(vec![0u8; 4])
+            .build();
+        interpreter.run().unwrap();
+        let snapshot = interpreter.snapshot();
+        assert_eq!(snapshot.memory_len(), 4);
+
+        let result = InterPreter::builder()
+            .snapshot(snapshot.clone())
+            .input(io::empty())
+            .output(io::sink())
+            .memory(vec![0u8; 3])
+            .try_build();
+        assert!(matches!(
+            result,
+            Err(Error::OutOfBounds {
+                pointer: 3,
+                len: 3,
+                ..
+            })
+        ));
+
+        // 長さの決まっていないテープなら伸ばせる
+        let resumed = InterPreter::builder()
+            .snapshot(snapshot)
+            .input(io::empty())
+            .output(io::sink())
+            .memory(AutoExtendMemory::new(vec![0u8; 1]))
+            .try_build()
+            .unwrap();
+        assert_eq!(resumed.pointer(), 2);
+    }
+
+    #[test]
+    fn test_bidirectional_memory() {
+        let block = block("+<<++<+++.");
+        let mut interpreter = InterPreter::builder()
+            .root_node(&block)
+            .input(io::empty())
+            .output(Vec::new())
+            .memory(BidirectionalMemory::new(vec![0u8; 2]))
+            .record(100)
+            .build();
+        interpreter.run().unwrap();
+        let origin = interpreter.origin();
+        assert_eq!(interpreter.memory()[origin - 3..=origin], [3, 2, 0, 1]);
+        assert_eq!(interpreter.pointer(), origin - 3);
+        assert_eq!(interpreter.output, [3]);
+
+        // 伸びた後でも、伸びる前の状態に戻れる
+        while interpreter.step_back().is_some() {}
+        assert!(interpreter.memory().iter().all(|c| *c == 0));
+        assert_eq!(interpreter.pointer(), origin);
+
+        // 最適化でまとめた命令のオフセットでも伸びる
+        let block = block_opt("+++[<++>-]<.");
+        let mut interpreter = InterPreter::builder()
+            .root_node(&block)
+            .input(io::empty())
+            .output(Vec::new())
+            .memory(BidirectionalMemory::new(vec![0u8; 1]))
+            .build();
+        interpreter.run().unwrap();
+        let origin = interpreter.origin();
+        assert_eq!(interpreter.memory()[origin - 1..=origin], [6, 0]);
+        assert_eq!(interpreter.output, [6]);
+    }
+
+    #[test]
+    fn test_step_back() {
+        let block = block(",+.>,.");
+        let mut interpreter = InterPreter::builder()
+            .root_node(&block)
+            .input(&b"ab"[..])
+            .output(Vec::new())
+            .memory(vec![0u8; 4])
+            .record(10)
+            .build();
+        assert_eq!(interpreter.run().unwrap(), 6);
+        assert_eq!(interpreter.memory(), [98, 98, 0, 0]);
+
+        let record = interpreter.step_back().unwrap();
+        assert_eq!(record.io, RecordedIo::Output);
+        let record = interpreter.step_back().unwrap();
+        assert_eq!(record.write, Some((1, 0)));
+        assert_eq!(record.io, RecordedIo::Input { read: true });
+        assert_eq!((interpreter.pc(), interpreter.pointer()), (4, 1));
+        while interpreter.step_back().is_some() {}
+        assert_eq!(interpreter.memory(), [0, 0, 0, 0]);
+        assert_eq!(
+            (interpreter.pc(), interpreter.pointer(), interpreter.count()),
+            (0, 0, 0)
+        );
+        assert_eq!(interpreter.input_offset(), 0);
+
+        // 再実行では同じ入力を使い、出力は繰り返さない
+        assert_eq!(interpreter.run().unwrap(), 6);
+        assert_eq!(interpreter.memory(), [98, 98, 0, 0]);
+        assert_eq!(interpreter.input_offset(), 2);
+        assert_eq!(interpreter.output, b"bb");
+
+        // 記録は直近の分だけ残る
+        let mut interpreter = InterPreter::builder()
+            .root_node(&block)
+            .input(&b"ab"[..])
+            .output(io::sink())
+            .memory(vec![0u8; 4])
+            .record(2)
+            .build();
+        interpreter.run().unwrap();
+        assert_eq!(interpreter.recorded_steps(), 2);
+        assert!(interpreter.step_back().is_some());
+        assert!(interpreter.step_back().is_some());
+        assert!(interpreter.step_back().is_none());
+        assert_eq!(interpreter.pc(), 4);
+    }
+
+    #[test]
+    fn test_step_back_after_error() {
+        let block = block("+<");
+        let mut interpreter = InterPreter::builder()
+            .root_node(&block)
+            .input(io::empty())
+            .output(io::sink())
+            .memory(vec![0u8; 4])
+            .record(10)
+            .build();
+        assert!(interpreter.step().unwrap().is_some());
+        assert!(matches!(
+            interpreter.step(),
+            Err(Error::NegativePointer { .. })
+        ));
+        // 失敗した命令は数えず、記録もしない
+        assert_eq!((interpreter.pc(), interpreter.count()), (1, 1));
+        assert_eq!(interpreter.recorded_steps(), interpreter.count());
+
+        assert!(interpreter.step_back().is_some());
+        assert_eq!((interpreter.pc(), interpreter.count()), (0, 0));
+        assert_eq!(interpreter.recorded_steps(), interpreter.count());
+        assert_eq!(interpreter.memory(), [0, 0, 0, 0]);
+
+        // runでも同じ
+        assert!(interpreter.run().is_err());
+        assert_eq!(interpreter.count(), 1);
     }
 
     // デバックビルドだとめちゃくちゃ時間がかかるので、デフォルトでは実行しないようになっている
@@ -369,7 +1447,7 @@ mod test {
             .root_node(&block)
             .input(io::empty())
             .output(&mut output_buffer)
-            .memory(AutoExtendMemory::new(vec![0]))
+            .memory(AutoExtendMemory::new(vec![0u8]))
             .build()
             .run()
             .unwrap();
@@ -392,7 +1470,7 @@ mod test {
             .root_node(&block)
             .input(io::empty())
             .output(&mut output_buffer)
-            .memory(AutoExtendMemory::new(vec![0]))
+            .memory(AutoExtendMemory::new(vec![0u8]))
             .build()
             .run()
             .unwrap();
@@ -413,7 +1491,7 @@ mod test {
             .root_node(&block)
             .input(io::empty())
             .output(&mut output_buffer)
-            .memory(AutoExtendMemory::new(vec![0]))
+            .memory(AutoExtendMemory::new(vec![0u8]))
             .build()
             .run()
             .unwrap();
@@ -434,7 +1512,7 @@ mod test {
             .root_node(&block)
             .input(io::empty())
             .output(&mut output_buffer)
-            .memory(AutoExtendMemory::new(vec![0]))
+            .memory(AutoExtendMemory::new(vec![0u8]))
             .build()
             .run()
             .unwrap();
@@ -0,0 +1,355 @@
+//! プロファイリング結果を、ソースコードの行とループごとに集計する。
+//! 最適化で命令の並びが変わっても、元のソースコードの位置で読めるようにする。
+
+use std::{collections::HashMap, fmt::Write};
+
+use crate::parse::Span;
+
+use super::{FlatInstruction, ProfilingResult};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub enum FrameKind {
+    Loop,
+    If,
+}
+
+/// 1つのループかif
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Frame {
+    pub kind: FrameKind,
+    pub span: Span,
+    /// すぐ外側のフレーム。一番外側なら`None`
+    pub parent: Option<usize>,
+}
+
+/// 命令列からループとifの入れ子を復元する。
+/// `(フレーム, 各命令を直接含むフレーム)`を返す。`[`と`]`はそのループ自身に含める
+pub(super) fn frames(
+    instructions: &[FlatInstruction],
+    spans: &[Span],
+) -> (Vec<Frame>, Vec<Option<usize>>) {
+    let mut frames = Vec::new();
+    let mut instruction_frames = Vec::with_capacity(instructions.len());
+    // (フレーム, フレームの次の命令の位置)
+    let mut stack: Vec<(usize, usize)> = Vec::new();
+    for (i, instruction) in instructions.iter().enumerate() {
+        while stack.last().is_some_and(|&(_, end)| end <= i) {
+            stack.pop();
+        }
+        let parent = stack.last().map(|&(frame, _)| frame);
+        if let FlatInstruction::WhileBegin(to) = *instruction {
+            // ifにはWhileEndがない
+            let kind =
+                if instructions.get(to.wrapping_sub(1)) == Some(&FlatInstruction::WhileEnd(i)) {
+                    FrameKind::Loop
+                } else {
+                    FrameKind::If
+                };
+            frames.push(Frame {
+                kind,
+                span: spans[i],
+                parent,
+            });
+            stack.push((frames.len() - 1, to));
+            instruction_frames.push(Some(frames.len() - 1));
+        } else {
+            instruction_frames.push(parent);
+        }
+    }
+    (frames, instruction_frames)
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Report {
+    /// 実行した命令の数
+    pub count: usize,
+    /// 行ごとの実行命令数。`lines[0]`が1行目
+    pub lines: Vec<u64>,
+    /// ループごとの集計。ループ内の実行命令数が多い順
+    pub loops: Vec<LoopProfile>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct LoopProfile {
+    /// `[`から`]`までの範囲
+    pub span: Span,
+    /// `[`の1始まりの行番号と列番号
+    pub line: usize,
+    pub column: usize,
+    /// ループに入った回数（1回も回らなかった場合を含む）
+    pub entries: u64,
+    /// ループ本体を実行した回数
+    pub iterations: u64,
+    /// ループ内（内側のループを含む）で実行した命令の数
+    pub steps: u64,
+}
+impl LoopProfile {
+    /// 1回入ったときの平均の繰り返し回数
+    pub fn average_trip_count(&self) -> f64 {
+        if self.entries == 0 {
+            0.0
+        } else {
+            self.iterations as f64 / self.entries as f64
+        }
+    }
+}
+
+impl ProfilingResult {
+    /// `source`はプロファイリングしたプログラムのソースコード
+    pub fn report(&self, source: &str) -> Report {
+        let line_starts = line_starts(source);
+        let line_of = |offset: usize| line_starts.partition_point(|&start| start <= offset) - 1;
+        let counts: Vec<u64> = self
+            .instruction_count
+            .iter()
+            .map(|&count| count as u64)
+            .collect();
+
+        let mut lines = vec![0; line_starts.len()];
+        for ((instruction, span), count) in self.instructions.iter().zip(&self.spans).zip(&counts) {
+            if span.is_empty() {
+                continue;
+            }
+            // ループの`]`はループの最後の行に数える
+            let offset = match instruction {
+                FlatInstruction::WhileEnd(_) => span.end - 1,
+                _ => span.start,
+            };
+            lines[line_of(offset)] += count;
+        }
+
+        let mut loops = Vec::new();
+        for (begin, instruction) in self.instructions.iter().enumerate() {
+            let FlatInstruction::WhileBegin(to) = *instruction else {
+                continue;
+            };
+            // ifにはWhileEndがない
+            let end = to - 1;
+            if self.instructions[end] != FlatInstruction::WhileEnd(begin) {
+                continue;
+            }
+            let span = self.spans[begin];
+            let (line, column) = span.line_column(source);
+            // `]`で戻るたびに`[`の条件をもう一度調べる
+            let iterations = counts[end];
+            loops.push(LoopProfile {
+                span,
+                line,
+                column,
+                entries: counts[begin] - iterations,
+                iterations,
+                steps: counts[begin..=end].iter().sum(),
+            });
+        }
+        loops.sort_by(|a, b| b.steps.cmp(&a.steps).then(a.span.start.cmp(&b.span.start)));
+
+        Report {
+            count: self.count,
+            lines,
+            loops,
+        }
+    }
+
+    /// flamegraphなどで読めるcollapsed stack形式。
+    /// ループとifの入れ子をスタックとみなし、各フレームで直接実行した命令数を重みにする
+    pub fn folded(&self, source: &str) -> String {
+        let mut weights: HashMap<Option<usize>, u64> = HashMap::new();
+        for (frame, count) in self.instruction_frames.iter().zip(&self.instruction_count) {
+            *weights.entry(*frame).or_default() += *count as u64;
+        }
+
+        let labels: Vec<String> = self
+            .frames
+            .iter()
+            .map(|frame| {
+                let (line, column) = frame.span.line_column(source);
+                let kind = match frame.kind {
+                    FrameKind::Loop => "loop",
+                    FrameKind::If => "if",
+                };
+                format!("{kind}@{line}:{column}({})", frame.span)
+            })
+            .collect();
+        let stack = |mut frame: Option<usize>| {
+            let mut names = Vec::new();
+            while let Some(index) = frame {
+                names.push(labels[index].as_str());
+                frame = self.frames[index].parent;
+            }
+            names.push("main");
+            names.reverse();
+            names.join(";")
+        };
+
+        let mut output = String::new();
+        for frame in std::iter::once(None).chain((0..self.frames.len()).map(Some)) {
+            match weights.get(&frame) {
+                Some(&weight) if weight > 0 => {
+                    writeln!(output, "{} {weight}", stack(frame)).unwrap();
+                }
+                _ => {}
+            }
+        }
+        output
+    }
+}
+
+impl Report {
+    /// 各行の先頭に実行命令数を付けたソースコード。`lower_limit`未満の数は省略する
+    pub fn annotated_source(&self, source: &str, lower_limit: u64) -> String {
+        let width = self.count.to_string().len();
+        let mut output = String::new();
+        for (line, count) in source.lines().zip(&self.lines) {
+            if *count > 0 && *count >= lower_limit {
+                writeln!(output, "{count:>width$} | {line}").unwrap();
+            } else {
+                writeln!(output, "{:>width$} | {line}", "").unwrap();
+            }
+        }
+        output
+    }
+
+    /// 実行命令数の多いループ`limit`個の表
+    pub fn hottest_loops(&self, source: &str, limit: usize) -> String {
+        let mut output = String::new();
+        writeln!(
+            output,
+            "{:>10} {:>14} {:>10} {:>12} {:>10}  source",
+            "line:col", "steps", "entries", "iterations", "avg trip"
+        )
+        .unwrap();
+        for profile in self.loops.iter().take(limit) {
+            let location = format!("{}:{}", profile.line, profile.column);
+            writeln!(
+                output,
+                "{location:>10} {:>14} {:>10} {:>12} {:>10.1}  {}",
+                profile.steps,
+                profile.entries,
+                profile.iterations,
+                profile.average_trip_count(),
+                snippet(&source[profile.span.start..profile.span.end], 40),
+            )
+            .unwrap();
+        }
+        output
+    }
+}
+
+/// 各行の先頭の位置
+fn line_starts(source: &str) -> Vec<usize> {
+    std::iter::once(0)
+        .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+        .collect()
+}
+
+/// 空白を詰めて、長ければ`max_chars`文字で切ったもの
+fn snippet(code: &str, max_chars: usize) -> String {
+    let code: String = code.split_whitespace().collect();
+    if code.chars().count() <= max_chars {
+        code
+    } else {
+        let mut code: String = code.chars().take(max_chars - 3).collect();
+        code.push_str("...");
+        code
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::{
+        interpreter::InterPreter,
+        opt::{optimize, optimize_for_interpreter},
+        utils::bf_to_block,
+    };
+
+    use super::*;
+
+    fn profile(source: &str, opt: bool) -> ProfilingResult {
+        let mut block = bf_to_block(source).unwrap();
+        if opt {
+            block = optimize(&block, true, false);
+            optimize_for_interpreter(&mut block);
+        }
+        let interpreter = InterPreter::builder()
+            .root_node(&block)
+            .input(io::empty())
+            .output(io::sink())
+            .memory(vec![0u8; 10])
+            .build();
+        interpreter.profiling().unwrap()
+    }
+
+    fn report(source: &str, opt: bool) -> Report {
+        profile(source, opt).report(source)
+    }
+
+    #[test]
+    fn test_report() {
+        let source = "+++[\n>++[>+<-]\n<-]\n";
+        let report = report(source, false);
+        assert_eq!(report.lines.iter().sum::<u64>(), report.count as u64);
+        assert_eq!(report.lines.len(), 4);
+        assert_eq!(report.lines[3], 0);
+
+        let [outer, inner] = report.loops.as_slice() else {
+            panic!("{:?}", report.loops);
+        };
+        assert_eq!((outer.line, outer.column), (1, 4));
+        assert_eq!((outer.entries, outer.iterations), (1, 3));
+        assert_eq!(outer.steps, report.count as u64 - 3);
+        assert_eq!((inner.line, inner.column), (2, 4));
+        assert_eq!((inner.entries, inner.iterations), (3, 6));
+        assert_eq!(inner.average_trip_count(), 2.0);
+        assert!(inner.steps < outer.steps);
+
+        let annotated = report.annotated_source(source, 0);
+        assert_eq!(annotated.lines().count(), 3);
+        assert!(annotated.lines().nth(1).unwrap().ends_with("| >++[>+<-]"));
+        assert!(report.hottest_loops(source, 1).contains("[>++[>+<-]<-]"));
+    }
+
+    #[test]
+    fn test_folded() {
+        let source = "+++[\n>++[>+<-]\n<-]\n";
+        let result = profile(source, false);
+        assert_eq!(result.frames.len(), 2);
+        assert_eq!(result.frames[1].parent, Some(0));
+        let folded = result.folded(source);
+        let lines: Vec<_> = folded.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("main "));
+        assert!(lines[1].starts_with("main;loop@1:4(3..18) "));
+        assert!(lines[2].starts_with("main;loop@1:4(3..18);loop@2:4(8..14) "));
+        let total: u64 = lines
+            .iter()
+            .map(|line| line.rsplit(' ').next().unwrap().parse::<u64>().unwrap())
+            .sum();
+        assert_eq!(total, result.count as u64);
+
+        // 最適化で内側のループはifになる。外側は出力があるのでループのまま
+        let source = "+++[\n>++[>+<-]\n<.-]\n";
+        let result = profile(source, true);
+        let kinds: Vec<_> = result
+            .frames
+            .iter()
+            .map(|frame| (frame.kind, frame.parent))
+            .collect();
+        assert_eq!(kinds, [(FrameKind::Loop, None), (FrameKind::If, Some(0))]);
+        assert!(result
+            .folded(source)
+            .contains("main;loop@1:4(3..19);if@2:4(8..14) "));
+    }
+
+    #[test]
+    fn test_report_optimized() {
+        // 内側のループはMulになるので、出力がある外側だけが残る
+        let source = "+++[\n>++[>+<-]\n<.-]\n";
+        let report = report(source, true);
+        assert_eq!(report.lines.iter().sum::<u64>(), report.count as u64);
+        assert_eq!(report.loops.len(), 1);
+        assert_eq!(report.loops[0].span, Span::new(3, 19));
+        assert_eq!(report.loops[0].iterations, 3);
+    }
+}
@@ -0,0 +1,86 @@
+//! 実行途中の状態を保存して、後で（別のマシンでも）再開する。
+
+use std::io::{self, Read, Write};
+
+use crate::{
+    cell::{Cell, CellWidth},
+    parse::Span,
+};
+
+use super::FlatInstruction;
+
+/// `InterPreter::snapshot`で取り出し、`InterPreterBuilder::snapshot`で復元する。
+/// 命令列も含むので、再開するときに元のソースコードはいらない
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(bound = "C: Cell")]
+pub struct Snapshot<C> {
+    pub cell_width: CellWidth,
+    pub instructions: Vec<FlatInstruction>,
+    pub spans: Vec<Span>,
+    /// 次に実行する命令の位置
+    pub pc: usize,
+    /// これまでに実行した命令の数
+    pub count: usize,
+    pub pointer: usize,
+    /// 最初の0番目のセルの位置。`BidirectionalMemory`が左に伸びた分
+    #[serde(default)]
+    pub origin: usize,
+    /// 確保済みの領域ごとの`(先頭の位置, セル)`。末尾の0は省略し、0だけの領域は含めない
+    pub memory: Vec<(usize, Vec<C>)>,
+    /// これまでに読み込んだ入力のbyte数
+    pub input_offset: u64,
+}
+impl<C: Cell> Snapshot<C> {
+    pub fn write(&self, writer: impl Write) -> io::Result<()> {
+        serde_json::to_writer(writer, self)?;
+        Ok(())
+    }
+    /// 読み込んだ後、命令列などが壊れていないか確認する
+    pub fn read(reader: impl Read) -> io::Result<Self> {
+        let snapshot: Self = serde_json::from_reader(reader)?;
+        snapshot.validate().map_err(|message| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid snapshot: {message}"),
+            )
+        })?;
+        Ok(snapshot)
+    }
+
+    /// テープの内容を収めるのに必要な長さ
+    pub fn memory_len(&self) -> usize {
+        self.memory
+            .iter()
+            .map(|(start, cells)| start + cells.len())
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.cell_width != C::WIDTH {
+            return Err(format!(
+                "cell width is {}, expected {}",
+                self.cell_width,
+                C::WIDTH
+            ));
+        }
+        let len = self.instructions.len();
+        if self.spans.len() != len {
+            return Err("the number of spans does not match instructions".to_string());
+        }
+        if self.pc > len {
+            return Err(format!("pc {} is out of range", self.pc));
+        }
+        let out_of_range = self
+            .instructions
+            .iter()
+            .any(|instruction| match instruction {
+                FlatInstruction::WhileBegin(to) | FlatInstruction::WhileEnd(to) => *to > len,
+                FlatInstruction::Instruction(_) => false,
+            });
+        if out_of_range {
+            return Err("jump target is out of range".to_string());
+        }
+        Ok(())
+    }
+}
@@ -1,7 +1,9 @@
-use crate::parse::Ast;
+use std::mem;
+
+use crate::parse::{Ast, Span};
 
 // offsetは負の値もとる事ができる。WebAssemblyメモリ操作命令は正のoffsetしか受け付けないので、出力時によしなにする。
-#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum Op {
     Add(i32, i32),
     MovePtr(i32),
@@ -75,27 +77,43 @@ impl BlockItem {
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// 命令列。
+///
+/// `spans`は`items`と同じ長さで、各要素の元になったソースコード上の範囲を持つ。
+/// 最適化で合体した命令は、合体元の範囲をすべて含む範囲を持つ。
+#[derive(Debug, Clone, Default)]
 pub struct Block {
     pub items: Vec<BlockItem>,
+    pub spans: Vec<Span>,
 }
 
-impl From<&[Ast]> for Block {
-    fn from(ast: &[Ast]) -> Self {
-        Block::from_items(
-            ast.iter()
-                .filter_map(|item| match item {
-                    Ast::PtrInc => Some(BlockItem::Op(Op::ptr(1))),
-                    Ast::PtrDec => Some(BlockItem::Op(Op::ptr(-1))),
-                    Ast::Inc => Some(BlockItem::Op(Op::Add(1, 0))),
-                    Ast::Dec => Some(BlockItem::Op(Op::Add(-1, 0))),
-                    Ast::Read => Some(BlockItem::Op(Op::Input(0))),
-                    Ast::Write => Some(BlockItem::Op(Op::Out(0))),
-                    Ast::Loop(loop_items) => Some(BlockItem::Loop(loop_items.as_slice().into())),
-                    Ast::_Invalid => None,
-                })
-                .collect(),
-        )
+// spanは実行結果に影響しないので、比較では無視する
+impl PartialEq for Block {
+    fn eq(&self, other: &Self) -> bool {
+        self.items == other.items
+    }
+}
+impl Eq for Block {}
+
+impl From<&[(Ast, Span)]> for Block {
+    fn from(ast: &[(Ast, Span)]) -> Self {
+        let mut block = Block::new();
+
+        for (item, span) in ast {
+            let item = match item {
+                Ast::PtrInc => BlockItem::Op(Op::ptr(1)),
+                Ast::PtrDec => BlockItem::Op(Op::ptr(-1)),
+                Ast::Inc => BlockItem::Op(Op::Add(1, 0)),
+                Ast::Dec => BlockItem::Op(Op::Add(-1, 0)),
+                Ast::Read => BlockItem::Op(Op::Input(0)),
+                Ast::Write => BlockItem::Op(Op::Out(0)),
+                Ast::Loop(loop_items) => BlockItem::Loop(loop_items.as_slice().into()),
+                Ast::_Invalid => continue,
+            };
+            block.push_item(item, *span);
+        }
+
+        block
     }
 }
 
@@ -103,13 +121,71 @@ impl Block {
     pub fn new() -> Self {
         Self::default()
     }
+    /// ソースコード上の範囲を持たない命令列を作る
     pub fn from_items(items: Vec<BlockItem>) -> Self {
-        Self { items }
+        let spans = vec![Span::default(); items.len()];
+        Self { items, spans }
     }
-    pub fn push_item(&mut self, item: BlockItem) {
-        self.items.push(item)
+    pub fn push_item(&mut self, item: BlockItem, span: Span) {
+        self.items.push(item);
+        self.spans.push(span);
     }
-    pub fn from_ast(ast: &[Ast]) -> Self {
+    pub fn pop_item(&mut self) -> Option<(BlockItem, Span)> {
+        let item = self.items.pop()?;
+        let span = self.spans.pop().unwrap();
+        Some((item, span))
+    }
+    pub fn extend(&mut self, items: impl IntoIterator<Item = (BlockItem, Span)>) {
+        for (item, span) in items {
+            self.push_item(item, span);
+        }
+    }
+    pub fn retain(&mut self, mut f: impl FnMut(&BlockItem) -> bool) {
+        let items = mem::take(&mut self.items);
+        let spans = mem::take(&mut self.spans);
+
+        self.extend(items.into_iter().zip(spans).filter(|(item, _)| f(item)));
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&BlockItem, Span)> {
+        self.items.iter().zip(self.spans.iter().copied())
+    }
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&mut BlockItem, Span)> {
+        self.items.iter_mut().zip(self.spans.iter().copied())
+    }
+    /// 全体の範囲
+    pub fn span(&self) -> Span {
+        self.spans
+            .iter()
+            .fold(Span::default(), |acc, span| acc.union(*span))
+    }
+    pub fn from_ast(ast: &[(Ast, Span)]) -> Self {
         Self::from(ast)
     }
+    /// ループやifの中も含めた要素の数
+    pub fn size(&self) -> usize {
+        self.items
+            .iter()
+            .map(|item| match item {
+                BlockItem::Op(_) => 1,
+                BlockItem::Loop(block) | BlockItem::If(block) => 1 + block.size(),
+            })
+            .sum()
+    }
+    /// プログラム中で使われるoffsetの絶対値の最大。
+    /// テープの両端にこの分だけ余白を取れば、ポインタの範囲チェックだけで範囲外アクセスを防げる。
+    pub fn max_offset(&self) -> usize {
+        self.items
+            .iter()
+            .map(|item| match item {
+                BlockItem::Op(Op::Mul(to, _, offset)) => {
+                    (offset.unsigned_abs()).max((offset + to).unsigned_abs()) as usize
+                }
+                BlockItem::Op(op) => op
+                    .offset()
+                    .map_or(0, |offset| offset.unsigned_abs() as usize),
+                BlockItem::Loop(block) | BlockItem::If(block) => block.max_offset(),
+            })
+            .max()
+            .unwrap_or(0)
+    }
 }
@@ -0,0 +1,291 @@
+//! x86-64 Linux向けのJITコンパイラ。
+//!
+//! 最適化済みの`Block`を機械語に変換して、mmapした領域で直接実行する。
+//! I/Oは生成コードからRustの関数を呼び出して`Read`/`Write`に委ねる。
+
+use std::{
+    io::{self, BufWriter, Read, Write},
+    marker::PhantomData,
+    ptr,
+};
+
+use thiserror::Error;
+
+use crate::{
+    cell::Cell,
+    ir::Block,
+    transpile::x86_64::{self, Codegen, IoFunctions, IoMode},
+};
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("I/O Error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("Pointer is out of range: {0}")]
+    PointerOutOfRange(isize),
+}
+
+/// 生成コードに渡す実行時の情報。
+/// 先頭の`pointer`は生成コードが終了時に書き込むので、`repr(C)`で位置を固定する。
+#[repr(C)]
+struct Context<'a> {
+    pointer: usize,
+    input: &'a mut dyn Read,
+    output: &'a mut dyn Write,
+    error: Option<io::Error>,
+}
+
+extern "C" fn jit_putchar(ctx: *mut Context, value: u8) -> u32 {
+    let ctx = unsafe { &mut *ctx };
+    match ctx.output.write_all(&[value]) {
+        Ok(()) => 0,
+        Err(e) => {
+            ctx.error = Some(e);
+            1
+        }
+    }
+}
+
+extern "C" fn jit_getchar(ctx: *mut Context) -> u32 {
+    let ctx = unsafe { &mut *ctx };
+    let mut buf = [0];
+
+    // 入力待ちの前に、プロンプトなどを出しておく
+    let result = ctx
+        .output
+        .flush()
+        .and_then(|_| ctx.input.read_exact(&mut buf));
+
+    match result {
+        Ok(()) => buf[0] as u32,
+        Err(e) => {
+            ctx.error = Some(e);
+            0x100
+        }
+    }
+}
+
+/// 実行可能なメモリ領域
+struct ExecutableBuffer {
+    ptr: *mut u8,
+    len: usize,
+}
+impl ExecutableBuffer {
+    fn new(code: &[u8]) -> io::Result<Self> {
+        let len = code.len().max(1);
+        unsafe {
+            let ptr = libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            let ptr = ptr as *mut u8;
+            // 先に構築しておけば、mprotectが失敗してもDropでmunmapされる
+            let buffer = Self { ptr, len };
+
+            ptr::copy_nonoverlapping(code.as_ptr(), ptr, code.len());
+
+            if libc::mprotect(ptr as *mut _, len, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(buffer)
+        }
+    }
+}
+impl Drop for ExecutableBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut _, self.len);
+        }
+    }
+}
+
+type EntryPoint = unsafe extern "C" fn(*mut u8, *mut Context, *mut u8, *mut u8) -> u32;
+
+pub struct Jit<C: Cell> {
+    code: ExecutableBuffer,
+    _cell: PhantomData<C>,
+}
+impl<C: Cell> Jit<C> {
+    pub fn compile(block: &Block) -> io::Result<Self> {
+        let io_functions = IoFunctions {
+            putchar: jit_putchar as *const () as u64,
+            getchar: jit_getchar as *const () as u64,
+        };
+        let code = Codegen::new(C::WIDTH, IoMode::Call(io_functions)).compile(block)?;
+
+        Ok(Self {
+            code: ExecutableBuffer::new(&code)?,
+            _cell: PhantomData,
+        })
+    }
+
+    /// `memory`をテープとして実行し、終了時のポインタを返す。
+    /// ポインタやoffset付きのアクセスが`memory`の範囲外に出るとエラーになる。
+    pub fn run(&self, memory: &mut [C], mut input: impl Read, output: impl Write) -> Result<usize> {
+        assert!(!memory.is_empty());
+
+        let mut output = BufWriter::new(output);
+        let mut ctx = Context {
+            pointer: 0,
+            input: &mut input,
+            output: &mut output,
+            error: None,
+        };
+
+        let status = unsafe {
+            let entry: EntryPoint = std::mem::transmute(self.code.ptr);
+            let begin = memory.as_mut_ptr();
+            let end = begin.add(memory.len());
+            entry(begin as *mut u8, &mut ctx, begin as *mut u8, end as *mut u8)
+        };
+
+        let begin = memory.as_ptr() as usize;
+        let pointer = (ctx.pointer as isize - begin as isize) / std::mem::size_of::<C>() as isize;
+        let error = ctx.error.take();
+
+        output.flush()?;
+
+        match status {
+            x86_64::STATUS_OK => Ok(pointer as usize),
+            x86_64::STATUS_POINTER_OUT_OF_RANGE => Err(Error::PointerOutOfRange(pointer)),
+            x86_64::STATUS_IO_ERROR => Err(error.expect("I/O error is not set").into()),
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::{
+        cell::Cell,
+        interpreter::InterPreter,
+        opt::{optimize, optimize_for_interpreter},
+        utils::bf_to_block,
+    };
+
+    use super::*;
+
+    fn run_jit<C: Cell>(block: &Block, input: &[u8]) -> (Vec<u8>, Vec<C>, usize) {
+        let mut memory = vec![C::default(); 1000];
+        let mut output = Vec::new();
+        let pointer = Jit::<C>::compile(block)
+            .unwrap()
+            .run(&mut memory, input, &mut output)
+            .unwrap();
+        (output, memory, pointer)
+    }
+
+    fn run_interpreter<C: Cell>(block: &Block, input: &[u8]) -> (Vec<u8>, Vec<C>, usize) {
+        let mut output = Vec::new();
+        let mut interpreter = InterPreter::builder()
+            .root_node(block)
+            .input(input)
+            .output(&mut output)
+            .memory(vec![C::default(); 1000])
+            .build();
+        interpreter.run().unwrap();
+        let memory = interpreter.memory().to_vec();
+        let pointer = interpreter.pointer();
+        (output, memory, pointer)
+    }
+
+    fn assert_same<C: Cell>(source: &str, input: &[u8]) {
+        let block = bf_to_block(source).unwrap();
+        assert_eq!(
+            run_jit::<C>(&block, input),
+            run_interpreter::<C>(&block, input)
+        );
+
+        let mut block = optimize(&block, true, false);
+        optimize_for_interpreter(&mut block);
+        assert_eq!(
+            run_jit::<C>(&block, input),
+            run_interpreter::<C>(&block, input)
+        );
+    }
+
+    #[test]
+    fn test_hello_world_jit() {
+        let hello_world_code = include_str!("../../bf_codes/hello_world.bf");
+        let hello_world = include_str!("../../bf_codes/hello_world.out");
+
+        let block = bf_to_block(hello_world_code).unwrap();
+        let (output, _, _) = run_jit::<u8>(&block, &[]);
+
+        assert_eq!(String::from_utf8(output).unwrap(), hello_world);
+    }
+
+    #[test]
+    fn test_same_as_interpreter() {
+        let sources = [
+            "+++[>+++<-]>.",
+            ">>>+++>>>+++[-<+++>]<<<<[>>+<<-]",
+            "->++++++++++++++++[>++++++++++++++++<-]>>[-]<<+++[>--<-]",
+            "++++[>+++++<-]>[<+++>>++<-]>>+>>>+<<[>]<<<",
+            "+>+>+>>+<<<<[>]>[<]",
+            ",[.,]",
+            ",>,<[->>+++<<]>[->-<]>.",
+        ];
+        for source in sources {
+            assert_same::<u8>(source, b"hello\0");
+            assert_same::<u16>(source, b"hello\0");
+            assert_same::<u32>(source, b"hello\0");
+            assert_same::<u64>(source, b"hello\0");
+        }
+    }
+
+    #[test]
+    fn test_pointer_out_of_range() {
+        let block = bf_to_block("+<").unwrap();
+        let mut memory = vec![0u8; 10];
+        let result = Jit::<u8>::compile(&block)
+            .unwrap()
+            .run(&mut memory, io::empty(), io::sink());
+
+        assert!(matches!(result, Err(Error::PointerOutOfRange(-1))));
+        assert_eq!(memory[0], 1);
+
+        let block = bf_to_block(">>>>>>>>>>").unwrap();
+        let result = Jit::<u8>::compile(&block)
+            .unwrap()
+            .run(&mut memory, io::empty(), io::sink());
+        assert!(matches!(result, Err(Error::PointerOutOfRange(10))));
+    }
+
+    #[test]
+    fn test_offset_out_of_range() {
+        // offsetがまとめられて、ポインタを動かす前に範囲外へ書き込む
+        let mut block = optimize(&bf_to_block(">>>>>>>>>>+<").unwrap(), true, false);
+        optimize_for_interpreter(&mut block);
+        let mut memory = vec![0u8; 10];
+        let result = Jit::<u8>::compile(&block)
+            .unwrap()
+            .run(&mut memory, io::empty(), io::sink());
+
+        assert!(matches!(result, Err(Error::PointerOutOfRange(_))));
+        assert_eq!(memory, vec![0; 10]);
+    }
+
+    #[test]
+    fn test_eof_is_error() {
+        let block = bf_to_block(",,").unwrap();
+        let mut memory = vec![0u8; 10];
+        let result = Jit::<u8>::compile(&block)
+            .unwrap()
+            .run(&mut memory, &b"a"[..], io::sink());
+
+        assert!(matches!(result, Err(Error::IoError(_))));
+        assert_eq!(memory[0], b'a');
+    }
+}
@@ -1,6 +1,11 @@
+pub mod cell;
+pub mod eof;
 pub mod error;
+pub mod fuzz;
 pub mod interpreter;
 pub mod ir;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub mod jit;
 pub mod opt;
 pub mod parse;
 pub mod transpile;
@@ -9,8 +14,11 @@ pub mod utils;
 pub use error::Error;
 pub use interpreter::InterPreter;
 use ir::Block;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub use jit::Jit;
 pub use transpile::{
     c::block_to_c,
+    elf::block_to_elf,
     wasm::{block_to_wasm, block_to_wat},
 };
 
@@ -18,16 +26,24 @@ pub use transpile::{
 use wasm_bindgen::prelude::*;
 
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
-pub fn bf_to_wasm(bf: &str, optimize: bool) -> Result<Vec<u8>, String> {
+pub fn bf_to_wasm(bf: &str, optimize: bool, eof: &str) -> Result<Vec<u8>, String> {
     let mut buffer = Vec::new();
+    let eof = eof.parse::<eof::EofBehavior>()?;
 
     let ast = parse::parse(bf).map_err(|e| e.to_string())?;
     let mut block = Block::from_ast(&ast);
 
     if optimize {
-        block = opt::optimize(&block, true, true);
+        block = opt::optimize(&block, true, false);
+        opt::optimize_for_interpreter(&mut block);
     }
 
-    block_to_wasm(&block, &mut buffer).map_err(|e| e.to_string())?;
+    // ブラウザではテープの長さを指定できないので、必要に応じて伸ばす
+    let config = transpile::wasm::Config {
+        growable: true,
+        eof,
+        ..Default::default()
+    };
+    block_to_wasm(&block, &config, &mut buffer).map_err(|e| e.to_string())?;
     Ok(buffer)
 }
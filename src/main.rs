@@ -1,13 +1,25 @@
 use std::{
     fs::{self, File},
-    io::{self, Write},
+    io::{self, BufWriter, Read, Write},
     num::NonZeroIsize,
     path::PathBuf,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
 use bf::{
-    interpreter::AutoExtendMemory, opt::optimize_for_interpreter, transpile, utils::bf_to_block,
+    cell::{Cell, CellWidth, OverflowPolicy},
+    eof::EofBehavior,
+    fuzz::{minimize, Fuzzer, Generator},
+    interpreter::{
+        debugger::{Command as DebugCommand, Debugger, Stop},
+        AutoExtendMemory, BidirectionalMemory, Memory, PagedMemory, PendingIo, Snapshot,
+        TapePolicy,
+    },
+    ir::Block,
+    opt::{OptLevel, Pipeline},
+    transpile,
+    utils::{bf_to_block, XorShift},
     InterPreter,
 };
 use clap::{Parser, ValueEnum};
@@ -25,30 +37,219 @@ struct Command {
 #[derive(Debug, clap::Subcommand)]
 enum SubCommand {
     Run(RunArg),
+    /// `run --save-on-exit`で保存した状態から実行を再開する
+    Resume(ResumeArg),
     Profiling(ProfilingArg),
     Trans(TransArg),
+    /// 1命令ずつ実行するデバッガ
+    Debug(DebugArg),
+    /// ランダムなプログラムで、最適化やCのバックエンドの結果が変わらないか調べる
+    Fuzz(FuzzArg),
 }
 
 #[derive(Debug, clap::Parser)]
 struct RunArg {
     file: PathBuf,
+    #[command(flatten)]
+    opt: OptArg,
+    #[clap(long, default_value_t = CellWidth::W8)]
+    cell_bits: CellWidth,
+    /// x86-64の機械語にコンパイルして実行する
+    #[clap(long)]
+    jit: bool,
+    #[command(flatten)]
+    exec: ExecArg,
+    #[clap(short, long)]
+    verbose: bool,
+}
+
+// 最適化のオプション
+#[derive(Debug, clap::Args)]
+struct OptArg {
+    /// -O2 と同じ
     #[clap(short, long)]
     optimize: bool,
+    /// 最適化レベル: 0, 1, 2, 3（3はパスを変化がなくなるまで繰り返す）
+    #[clap(short = 'O', value_name = "LEVEL", conflicts_with = "optimize")]
+    opt_level: Option<OptLevel>,
+    /// 実行するパスをカンマ区切りで並べる: merge, unwrap, clear, mul, if-opt,
+    /// offset-opt, non-negative-offset, remove-nop, lick
+    #[clap(long, conflicts_with_all = ["optimize", "opt_level"])]
+    passes: Option<String>,
+    /// 変化がなくなるまでパスを繰り返す
+    #[clap(long)]
+    fixed_point: bool,
+    /// パスごとの最適化の結果を標準エラー出力に出す
+    #[clap(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "text"
+    )]
+    opt_stats: Option<StatsFormat>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum StatsFormat {
+    Text,
+    Json,
+}
+
+impl OptArg {
+    fn pipeline(&self) -> anyhow::Result<Pipeline> {
+        let pipeline = match (&self.passes, self.opt_level) {
+            (Some(passes), _) => Pipeline::from_names(passes).map_err(anyhow::Error::msg)?,
+            (None, Some(level)) => Pipeline::with_level(level),
+            (None, None) if self.optimize => Pipeline::with_level(OptLevel::O2),
+            (None, None) => Pipeline::with_level(OptLevel::O0),
+        };
+        Ok(if self.fixed_point {
+            pipeline.fixed_point(true)
+        } else {
+            pipeline
+        })
+    }
+    fn apply(
+        &self,
+        block: &Block,
+        cell_width: CellWidth,
+        overflow: OverflowPolicy,
+    ) -> anyhow::Result<Block> {
+        let pipeline = self.pipeline()?;
+        anyhow::ensure!(
+            pipeline.supports(overflow),
+            "最適化は --overflow wrap にのみ対応している"
+        );
+        let (block, stats) = pipeline.cell_width(cell_width).run_with_stats(block);
+        match self.opt_stats {
+            Some(StatsFormat::Text) => eprint!("{stats}"),
+            Some(StatsFormat::Json) => eprintln!("{}", serde_json::to_string_pretty(&stats)?),
+            None => {}
+        }
+        Ok(block)
+    }
+}
+
+#[derive(Debug, clap::Parser)]
+struct ResumeArg {
+    /// `--save-on-exit`で保存したファイル
+    snapshot: PathBuf,
+    #[command(flatten)]
+    exec: ExecArg,
+}
+
+// run, resume に共通するオプション
+#[derive(Debug, clap::Args)]
+struct ExecArg {
+    /// テープの長さ。負の値は --tape extend と同じ
     #[clap(long, default_value_t = NonZeroIsize::try_from(30000).unwrap())]
     memory_len: NonZeroIsize,
-    #[clap(short, long)]
-    verbose: bool,
+    /// テープの種類
+    #[clap(long, value_enum, default_value_t = Tape::Bounded)]
+    tape: Tape,
+    /// EOFでの`,`の動作: unchanged, zero, minus-one(255), error
+    #[clap(long, default_value_t = EofBehavior::Error)]
+    eof: EofBehavior,
+    /// セルの値が収まらなくなったときの動作: wrap, saturate, trap(エラー)
+    #[clap(long, default_value_t = OverflowPolicy::Wrap)]
+    overflow: OverflowPolicy,
+    /// 実行する命令の数の上限（resumeでは再開してからの数）
+    #[clap(long)]
+    max_steps: Option<usize>,
+    /// 実行時間の上限（秒）
+    #[clap(long, value_parser = parse_seconds)]
+    timeout: Option<Duration>,
+    /// 終了したとき（エラーや中断を含む）の状態をこのファイルに保存する
+    #[clap(long)]
+    save_on_exit: Option<PathBuf>,
+}
+
+impl ExecArg {
+    fn tape(&self) -> Tape {
+        if self.memory_len.get() < 0 {
+            Tape::Extend
+        } else {
+            self.tape
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Tape {
+    /// 長さ --memory-len。外に出たらエラー
+    Bounded,
+    /// 長さ --memory-len。端を越えたら反対側に回り込む
+    Wrap,
+    /// 右に伸びる。--memory-len は初期の長さ
+    Extend,
+    /// 左右に伸びる。--memory-len は初期の長さ
+    Bidirectional,
+    /// 触ったページだけを確保する。遠く離れたセルを使うプログラム向け
+    Paged,
+}
+
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    let seconds: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    Duration::try_from_secs_f64(seconds).map_err(|e| format!("{e}"))
 }
 
 #[derive(Debug, clap::Parser)]
 struct ProfilingArg {
     file: PathBuf,
-    #[clap(short, long)]
-    optimize: bool,
+    #[command(flatten)]
+    opt: OptArg,
     #[clap(long, default_value_t = NonZeroIsize::try_from(30000).unwrap())]
     memory_len: NonZeroIsize,
-    #[clap(short, long)]
-    lower_limit: i32,
+    /// 実行命令数がこれ未満の行は数を表示しない
+    #[clap(short, long, default_value_t = 0)]
+    lower_limit: u64,
+    /// 表示するループの数
+    #[clap(long, default_value_t = 10)]
+    top: usize,
+    /// 結果の形式
+    #[clap(long, value_enum, default_value_t = ProfilingFormat::Text)]
+    format: ProfilingFormat,
+    /// 結果をこのファイルに書き出す。指定しなければ標準エラー出力
+    #[clap(long)]
+    report_file: Option<PathBuf>,
+    /// EOFでの`,`の動作: unchanged, zero, minus-one(255), error
+    #[clap(long, default_value_t = EofBehavior::Error)]
+    eof: EofBehavior,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ProfilingFormat {
+    /// 実行命令数を付けたソースコードと、重いループの表
+    Text,
+    /// 行とループごとの集計
+    Json,
+    /// flamegraph用のcollapsed stack形式
+    Folded,
+}
+
+#[derive(Debug, clap::Parser)]
+struct DebugArg {
+    file: PathBuf,
+    #[command(flatten)]
+    opt: OptArg,
+    #[clap(long, default_value_t = 30000)]
+    memory_len: usize,
+    #[clap(long, default_value_t = CellWidth::W8)]
+    cell_bits: CellWidth,
+    /// プログラムへの入力。標準入力はデバッガのコマンドに使う
+    #[clap(long)]
+    input: Option<PathBuf>,
+    /// EOFでの`,`の動作: unchanged, zero, minus-one(255), error
+    #[clap(long, default_value_t = EofBehavior::Error)]
+    eof: EofBehavior,
+    /// セルの値が収まらなくなったときの動作: wrap, saturate, trap(エラー)
+    #[clap(long, default_value_t = OverflowPolicy::Wrap)]
+    overflow: OverflowPolicy,
+    /// 逆実行で戻れる命令数の上限。0なら記録しない
+    #[clap(long, default_value_t = 1_000_000)]
+    history: usize,
 }
 
 #[derive(Debug, clap::Parser)]
@@ -56,20 +257,67 @@ struct TransArg {
     file: PathBuf,
     #[clap(long, short, value_enum)]
     target: Option<TransTarget>,
-    #[clap(short, long)]
-    optimize: bool,
+    #[command(flatten)]
+    opt: OptArg,
     out: PathBuf,
     #[clap(short, long, default_value_t = 30000)]
     memory_len: usize,
+    #[clap(long, default_value_t = CellWidth::W8)]
+    cell_bits: CellWidth,
+    /// ポインタがテープの外に出たら、メッセージを出して終了する（C, WAT, WASM）
+    #[clap(long)]
+    checked: bool,
+    /// テープが足りなくなったら、線形メモリを拡張する（WAT, WASM）
+    #[clap(long)]
+    growable: bool,
+    /// ポインタをテープの中央から始め、左にも動けるようにする（C, WAT, WASM）
+    #[clap(long)]
+    bidirectional: bool,
+    /// EOFでの`,`の動作: unchanged, zero, minus-one(255), error（ELFはerrorのみ）
+    #[clap(long, default_value_t = EofBehavior::Error)]
+    eof: EofBehavior,
+    /// セルの値が収まらなくなったときの動作: wrap, saturate, trap(エラー)（Cのみ）
+    #[clap(long, default_value_t = OverflowPolicy::Wrap)]
+    overflow: OverflowPolicy,
     #[clap(short, long)]
     verbose: bool,
 }
 
+#[derive(Debug, clap::Parser)]
+struct FuzzArg {
+    /// 最適化を指定しなければ -O2
+    #[command(flatten)]
+    opt: OptArg,
+    /// 試すプログラムの数
+    #[clap(short = 'n', long, default_value_t = 1000)]
+    iterations: usize,
+    /// 乱数のシード。指定しなければ時刻から決める
+    #[clap(long)]
+    seed: Option<u64>,
+    /// 1つのプログラムの命令数のおおよその上限
+    #[clap(long, default_value_t = 64)]
+    max_len: usize,
+    /// ループの入れ子の深さの上限
+    #[clap(long, default_value_t = 2)]
+    max_depth: usize,
+    /// 入力の最大の長さ。EOFの後は0を読む
+    #[clap(long, default_value_t = 16)]
+    max_input_len: usize,
+    /// Cのバックエンドを比べない
+    #[clap(long)]
+    no_c: bool,
+    /// 見つかったプログラムを縮めたものをこのファイルに書き出す
+    #[clap(long)]
+    out: Option<PathBuf>,
+}
+
 #[derive(Debug, Clone, Copy, ValueEnum)]
 enum TransTarget {
     C,
     Wat,
     Wasm,
+    /// x86-64 Linuxの実行ファイル
+    Elf,
 }
 
 macro_rules! time {
@@ -91,131 +339,560 @@ fn main() -> anyhow::Result<()> {
 
     match arg.subcommand {
         SubCommand::Run(arg) => {
-            let code = fs::read_to_string(arg.file)?;
+            let code = fs::read_to_string(&arg.file)?;
 
-            let mut block = bf_to_block(&code)?;
-            if arg.optimize {
-                block = bf::opt::optimize(&block, true, false);
-                optimize_for_interpreter(&mut block);
-            }
+            let mut block = parse_block(&code)?;
+            block = arg.opt.apply(&block, arg.cell_bits, arg.exec.overflow)?;
 
             if arg.verbose {
                 info!("block: {:#?}", block);
             }
-            let step_count = match arg.memory_len.get().cmp(&0) {
-                std::cmp::Ordering::Less => {
-                    let mut interpreter = InterPreter::builder()
-                        .input(io::stdin())
-                        .output(io::stdout())
-                        .root_node(&block)
-                        .memory(AutoExtendMemory::new(vec![0; 300000]))
-                        .build();
-
-                    time!(interpreter.run()?)
-                }
-                std::cmp::Ordering::Equal => unreachable!(),
-                std::cmp::Ordering::Greater => {
-                    let mut interpreter = InterPreter::builder()
-                        .input(io::stdin())
-                        .output(io::stdout())
-                        .root_node(&block)
-                        .memory(vec![0; arg.memory_len.get() as usize])
-                        .build();
-
-                    time!(interpreter.run()?)
+            if arg.jit {
+                let exec = &arg.exec;
+                anyhow::ensure!(
+                    exec.eof == EofBehavior::Error,
+                    "--jit は --eof error にのみ対応している"
+                );
+                anyhow::ensure!(
+                    exec.max_steps.is_none() && exec.timeout.is_none(),
+                    "--jit は --max-steps, --timeout に対応していない"
+                );
+                anyhow::ensure!(
+                    exec.save_on_exit.is_none(),
+                    "--jit は --save-on-exit に対応していない"
+                );
+                anyhow::ensure!(
+                    exec.tape() == Tape::Bounded,
+                    "--jit は --tape bounded にのみ対応している"
+                );
+                anyhow::ensure!(
+                    exec.overflow == OverflowPolicy::Wrap,
+                    "--jit は --overflow wrap にのみ対応している"
+                );
+                match arg.cell_bits {
+                    CellWidth::W8 => run_jit::<u8>(&block, exec.memory_len)?,
+                    CellWidth::W16 => run_jit::<u16>(&block, exec.memory_len)?,
+                    CellWidth::W32 => run_jit::<u32>(&block, exec.memory_len)?,
+                    CellWidth::W64 => run_jit::<u64>(&block, exec.memory_len)?,
                 }
-            };
+                return Ok(());
+            }
+
+            let step_count = match arg.cell_bits {
+                CellWidth::W8 => run::<u8>(Program::Block(&block), &arg.exec),
+                CellWidth::W16 => run::<u16>(Program::Block(&block), &arg.exec),
+                CellWidth::W32 => run::<u32>(Program::Block(&block), &arg.exec),
+                CellWidth::W64 => run::<u64>(Program::Block(&block), &arg.exec),
+            }
+            .map_err(|e| with_location(e, &code))?;
+            info!("step: {step_count}");
+        }
+        SubCommand::Resume(arg) => {
+            let json = fs::read_to_string(&arg.snapshot)?;
+
+            // セルの型が決まらないと読み込めないので、先にセル幅だけ取り出す
+            let cell_width = serde_json::from_str::<serde_json::Value>(&json)?
+                .get("cell_width")
+                .and_then(serde_json::Value::as_u64)
+                .and_then(|bits| CellWidth::try_from(u32::try_from(bits).ok()?).ok())
+                .context("スナップショットのセル幅が不正")?;
+
+            let step_count = match cell_width {
+                CellWidth::W8 => resume::<u8>(&json, &arg.exec),
+                CellWidth::W16 => resume::<u16>(&json, &arg.exec),
+                CellWidth::W32 => resume::<u32>(&json, &arg.exec),
+                CellWidth::W64 => resume::<u64>(&json, &arg.exec),
+            }?;
             info!("step: {step_count}");
         }
         SubCommand::Profiling(arg) => {
             let code = fs::read_to_string(arg.file)?;
 
-            let mut block = bf_to_block(&code)?;
-            if arg.optimize {
-                block = bf::opt::optimize(&block, true, false);
-                optimize_for_interpreter(&mut block);
-            }
+            let mut block = parse_block(&code)?;
+            block = arg.opt.apply(&block, CellWidth::W8, OverflowPolicy::Wrap)?;
             let interpreter = InterPreter::builder()
                 .input(io::stdin())
                 .output(io::stdout())
                 .root_node(&block)
-                .memory(AutoExtendMemory::new(vec![0; 300000]))
+                .memory(AutoExtendMemory::new(vec![0u8; 300000]))
+                .eof(arg.eof)
                 .build();
 
             let progiling_result = time!(interpreter.profiling()?);
 
-            let mut skipped = false;
-
-            for (i, (count, instruction)) in progiling_result
-                .instruction_count
-                .iter()
-                .zip(&progiling_result.instructions)
-                .enumerate()
-            {
-                if *count >= arg.lower_limit {
-                    eprintln!("{}: {:?} {}", i, instruction, count);
-                    skipped = false;
-                } else if !skipped {
-                    eprintln!("...");
-                    skipped = true;
+            let report = progiling_result.report(&code);
+            info!("step: {}", report.count);
+
+            let mut output: Box<dyn Write> = match &arg.report_file {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(io::stderr()),
+            };
+            match arg.format {
+                ProfilingFormat::Text => {
+                    write!(
+                        output,
+                        "{}",
+                        report.annotated_source(&code, arg.lower_limit)
+                    )?;
+                    writeln!(output)?;
+                    write!(output, "{}", report.hottest_loops(&code, arg.top))?;
+                }
+                ProfilingFormat::Json => {
+                    serde_json::to_writer_pretty(&mut output, &report)?;
+                    writeln!(output)?;
                 }
+                ProfilingFormat::Folded => write!(output, "{}", progiling_result.folded(&code))?,
             }
-            info!("step: {}", progiling_result.count);
-            info!("count: {:?}", progiling_result.instruction_count);
-
-            let mut output = File::create("profiling_data.json")?;
-            serde_json::to_writer_pretty(&mut output, &progiling_result)?;
+            output.flush()?;
         }
         SubCommand::Trans(arg) => {
             let code = fs::read_to_string(&arg.file)?;
 
-            let mut block = bf_to_block(&code)?;
+            let mut block = parse_block(&code)?;
 
             if arg.verbose {
                 info!("block: {:#?}", block);
             }
 
-            let target = arg
-                .out
-                .extension()
-                .and_then(|ext| ext.to_str())
-                .and_then(|ext| match ext {
-                    "c" => Some(TransTarget::C),
-                    "wasm" => Some(TransTarget::Wasm),
-                    "wat" => Some(TransTarget::Wat),
-                    _ => None,
-                })
-                .or(arg.target)
-                .context(
-                    "出力形式が不明: --target(-t) 引数か, 出力パスの拡張子で出力形式(wasm, wat, c)を指定する",
-                )?;
+            let target = match arg.out.extension() {
+                Some(ext) => ext
+                    .to_str()
+                    .and_then(|ext| match ext {
+                        "c" => Some(TransTarget::C),
+                        "wasm" => Some(TransTarget::Wasm),
+                        "wat" => Some(TransTarget::Wat),
+                        "elf" => Some(TransTarget::Elf),
+                        _ => None,
+                    })
+                    .or(arg.target),
+                // 拡張子がなければ実行ファイル
+                None => arg.target.or(Some(TransTarget::Elf)),
+            }
+            .context(
+                "出力形式が不明: --target(-t) 引数か, 出力パスの拡張子で出力形式(wasm, wat, c, elf)を指定する",
+            )?;
+
+            anyhow::ensure!(
+                !(arg.bidirectional && matches!(target, TransTarget::Elf)),
+                "ELFは --bidirectional に対応していない"
+            );
+            anyhow::ensure!(
+                arg.overflow == OverflowPolicy::Wrap || matches!(target, TransTarget::C),
+                "--overflow wrap 以外はCにのみ対応している"
+            );
+            let origin = if arg.bidirectional {
+                arg.memory_len / 2
+            } else {
+                0
+            };
 
             let mut output = File::create(&arg.out)?;
 
+            let wasm_config = transpile::wasm::Config {
+                memory_len: arg.memory_len,
+                origin,
+                cell_width: arg.cell_bits,
+                growable: arg.growable,
+                checked: arg.checked,
+                eof: arg.eof,
+            };
+
             match target {
                 TransTarget::C => {
-                    if arg.optimize {
-                        block = bf::opt::optimize(&block, true, false);
-                    }
-                    let c_code = transpile::block_to_c(&block, arg.memory_len);
+                    block = arg.opt.apply(&block, arg.cell_bits, arg.overflow)?;
+                    let config = transpile::c::Config {
+                        memory_len: arg.memory_len,
+                        origin,
+                        cell_width: arg.cell_bits,
+                        checked: arg.checked,
+                        eof: arg.eof,
+                        overflow: arg.overflow,
+                        dump_memory: false,
+                    };
+                    let c_code = transpile::block_to_c(&block, &config);
                     output.write_all(c_code.as_bytes())?;
                 }
                 TransTarget::Wat => {
-                    if arg.optimize {
-                        block = bf::opt::optimize(&block, true, true);
-                    }
-                    transpile::block_to_wat(&block, &mut output)?;
+                    block = arg.opt.apply(&block, arg.cell_bits, arg.overflow)?;
+                    transpile::block_to_wat(&block, &wasm_config, &mut output)?;
                 }
                 TransTarget::Wasm => {
-                    if arg.optimize {
-                        block = bf::opt::optimize(&block, true, true);
+                    block = arg.opt.apply(&block, arg.cell_bits, arg.overflow)?;
+                    transpile::block_to_wasm(&block, &wasm_config, &mut output)?;
+                }
+                TransTarget::Elf => {
+                    anyhow::ensure!(
+                        arg.eof == EofBehavior::Error,
+                        "ELFは --eof error にのみ対応している"
+                    );
+                    block = arg.opt.apply(&block, arg.cell_bits, arg.overflow)?;
+                    transpile::block_to_elf(&block, arg.memory_len, arg.cell_bits, &mut output)?;
+
+                    #[cfg(unix)]
+                    {
+                        use std::os::unix::fs::PermissionsExt;
+                        output.set_permissions(fs::Permissions::from_mode(0o755))?;
                     }
-                    transpile::block_to_wasm(&block, &mut output)?;
                 }
             };
 
             info!("Done {:?}", arg.out);
         }
+        SubCommand::Debug(arg) => {
+            let code = fs::read_to_string(&arg.file)?;
+
+            let mut block = parse_block(&code)?;
+            block = arg.opt.apply(&block, arg.cell_bits, arg.overflow)?;
+            match arg.cell_bits {
+                CellWidth::W8 => debug::<u8>(&block, &code, &arg)?,
+                CellWidth::W16 => debug::<u16>(&block, &code, &arg)?,
+                CellWidth::W32 => debug::<u32>(&block, &code, &arg)?,
+                CellWidth::W64 => debug::<u64>(&block, &code, &arg)?,
+            }
+        }
+        SubCommand::Fuzz(arg) => fuzz(&arg)?,
     }
     Ok(())
 }
+
+fn fuzz(arg: &FuzzArg) -> anyhow::Result<()> {
+    let opt = &arg.opt;
+    let pipeline = if !opt.optimize && opt.opt_level.is_none() && opt.passes.is_none() {
+        Pipeline::with_level(OptLevel::O2).fixed_point(opt.fixed_point)
+    } else {
+        opt.pipeline()?
+    };
+    let mut fuzzer = Fuzzer::new(pipeline);
+    if arg.no_c {
+        fuzzer.c = false;
+    } else if !fuzzer.c {
+        log::warn!("cc が見つからないので、Cのバックエンドは比べない");
+    }
+    let generator = Generator {
+        max_len: arg.max_len,
+        max_depth: arg.max_depth,
+        ..Default::default()
+    };
+    // ループごとにカウンタのセルを1つ使うので、本体で使うセルが残るようにする
+    anyhow::ensure!(
+        arg.max_depth < generator.cells,
+        "--max-depth は {} 未満にする",
+        generator.cells
+    );
+
+    let seed = arg.seed.unwrap_or_else(|| {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(1, |elapsed| elapsed.as_nanos() as u64)
+    });
+    info!("seed: {seed}");
+    let mut rng = XorShift::new(seed);
+    for i in 0..arg.iterations {
+        let source = generator.generate(&mut rng);
+        let len = rng.below(arg.max_input_len as u64 + 1) as usize;
+        let input = rng.bytes(len);
+        log::debug!("#{i}: {source}");
+        let Some(mismatch) = fuzzer.check(&source, &input) else {
+            continue;
+        };
+
+        eprintln!("program #{i}: {source}");
+        eprintln!("{mismatch}");
+        // 同じバックエンドで食い違うまま縮める
+        let minimized = minimize(&source, |source| {
+            fuzzer
+                .check(source, &input)
+                .is_some_and(|m| m.backend == mismatch.backend)
+        });
+        eprintln!("minimized: {minimized}");
+        if let Some(mismatch) = fuzzer.check(&minimized, &input) {
+            eprintln!("{mismatch}");
+        }
+        if let Some(path) = &arg.out {
+            fs::write(path, &minimized)?;
+        }
+        anyhow::bail!(
+            "{} が最適化なしのインタプリタと食い違った",
+            mismatch.backend
+        );
+    }
+    info!("{} 個のプログラムで食い違いはなかった", arg.iterations);
+    Ok(())
+}
+
+/// 構文エラーがあれば、ソースコードの抜粋つきで表示する
+fn parse_block(code: &str) -> anyhow::Result<Block> {
+    match bf_to_block(code) {
+        Ok(block) => Ok(block),
+        Err(bf::Error::InvalidSyntax(errors)) => {
+            for error in &errors {
+                eprintln!("{}", error.render(code));
+            }
+            anyhow::bail!("{} syntax error(s)", errors.len())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// 実行時エラーに、原因になった命令のソースコード上の位置を付け加える
+fn with_location(error: anyhow::Error, code: &str) -> anyhow::Error {
+    let span = error
+        .downcast_ref::<bf::interpreter::Error>()
+        .and_then(|e| e.span());
+    match span {
+        Some(span) => {
+            let (line, column) = span.line_column(code);
+            error.context(format!("runtime error at {line}:{column} ({span})"))
+        }
+        None => error,
+    }
+}
+
+/// 実行するプログラム。ソースコードから始めるか、保存した状態から再開する
+enum Program<'a, C> {
+    Block(&'a Block),
+    Snapshot(Snapshot<C>),
+}
+
+fn run<C: Cell>(program: Program<'_, C>, arg: &ExecArg) -> anyhow::Result<usize> {
+    let len = arg.memory_len.get().unsigned_abs();
+    match arg.tape() {
+        Tape::Bounded => run_with(vec![C::default(); len], TapePolicy::Bounded, program, arg),
+        Tape::Wrap => run_with(vec![C::default(); len], TapePolicy::Wrap, program, arg),
+        Tape::Extend => run_with(
+            AutoExtendMemory::new(vec![C::default(); len]),
+            TapePolicy::Extend,
+            program,
+            arg,
+        ),
+        Tape::Bidirectional => run_with(
+            BidirectionalMemory::new(vec![C::default(); len]),
+            TapePolicy::Extend,
+            program,
+            arg,
+        ),
+        Tape::Paged => run_with(PagedMemory::new(), TapePolicy::Extend, program, arg),
+    }
+}
+
+fn run_with<M: Memory>(
+    memory: M,
+    tape: TapePolicy,
+    program: Program<'_, M::Cell>,
+    arg: &ExecArg,
+) -> anyhow::Result<usize> {
+    let builder = InterPreter::builder()
+        .input(io::stdin())
+        .output(io::stdout())
+        .memory(memory)
+        .tape(tape)
+        .eof(arg.eof)
+        .overflow(arg.overflow);
+    // スナップショットの命令数は通算なので、再開してからの数に直す
+    let (mut builder, start) = match program {
+        Program::Block(block) => (builder.root_node(block), 0),
+        Program::Snapshot(snapshot) => {
+            let start = snapshot.count;
+            (builder.snapshot(snapshot), start)
+        }
+    };
+    if let Some(max_steps) = arg.max_steps {
+        builder = builder.max_steps(start.saturating_add(max_steps));
+    }
+    if let Some(timeout) = arg.timeout {
+        builder = builder.deadline(Instant::now() + timeout);
+    }
+    let mut interpreter = builder.try_build()?;
+
+    let result = time!(interpreter.run());
+    save_on_exit(&interpreter.snapshot(), arg)?;
+    Ok(result?)
+}
+
+fn save_on_exit<C: Cell>(snapshot: &Snapshot<C>, arg: &ExecArg) -> anyhow::Result<()> {
+    let Some(path) = &arg.save_on_exit else {
+        return Ok(());
+    };
+    let mut writer = BufWriter::new(File::create(path)?);
+    snapshot.write(&mut writer)?;
+    writer.flush()?;
+    info!(
+        "saved {path:?} (pc: {}, step: {})",
+        snapshot.pc, snapshot.count
+    );
+    Ok(())
+}
+
+/// 保存した時点までに読み込んだ入力を標準入力から読み飛ばしてから、続きを実行する
+fn resume<C: Cell>(json: &str, arg: &ExecArg) -> anyhow::Result<usize> {
+    let snapshot = Snapshot::<C>::read(json.as_bytes())?;
+    let fixed = matches!(arg.tape(), Tape::Bounded | Tape::Wrap);
+    anyhow::ensure!(
+        !fixed || snapshot.memory_len() <= arg.memory_len.get() as usize,
+        "テープが短すぎる: スナップショットには {} セル必要",
+        snapshot.memory_len()
+    );
+
+    let skipped = io::copy(
+        &mut io::stdin().lock().take(snapshot.input_offset),
+        &mut io::sink(),
+    )?;
+    if skipped < snapshot.input_offset {
+        log::warn!(
+            "入力が {} byte しかない（保存時に {} byte 読み込み済み）",
+            skipped,
+            snapshot.input_offset
+        );
+    }
+
+    run(Program::Snapshot(snapshot), arg)
+}
+
+/// デバッガのREPL。プログラムの出力と混ざらないように、デバッガの表示は標準エラー出力に出す
+fn debug<C: Cell>(block: &Block, code: &str, arg: &DebugArg) -> anyhow::Result<()> {
+    let input: Box<dyn Read> = match &arg.input {
+        Some(path) => Box::new(File::open(path)?),
+        None => Box::new(io::empty()),
+    };
+    let interpreter = InterPreter::builder()
+        .input(input)
+        .output(io::stdout())
+        .root_node(block)
+        .memory(vec![C::default(); arg.memory_len])
+        .eof(arg.eof)
+        .overflow(arg.overflow)
+        .record(arg.history)
+        .build();
+    let mut debugger = Debugger::new(interpreter, code);
+
+    eprint!("{}", debugger.location());
+    let mut line = String::new();
+    loop {
+        eprint!("(bff) ");
+        line.clear();
+        if io::stdin().read_line(&mut line)? == 0 {
+            break;
+        }
+        let command = match line.parse() {
+            Ok(command) => command,
+            Err(e) => {
+                eprintln!("{e}");
+                continue;
+            }
+        };
+
+        let stop = match command {
+            DebugCommand::Step(n) => {
+                let mut stop = Ok(Stop::Step);
+                for _ in 0..n {
+                    stop = debugger.step();
+                    if !matches!(stop, Ok(Stop::Step)) {
+                        break;
+                    }
+                }
+                stop
+            }
+            DebugCommand::Next => debugger.step_over(),
+            DebugCommand::Continue => debugger.resume(),
+            DebugCommand::Finish => debugger.finish(),
+            DebugCommand::Back(n) => {
+                let mut stop = Stop::Step;
+                for _ in 0..n {
+                    stop = debugger.step_back();
+                    if stop != Stop::Step {
+                        break;
+                    }
+                }
+                Ok(stop)
+            }
+            DebugCommand::LastWrite(index) => Ok(debugger.reverse_to_write(index)),
+            DebugCommand::Rewind => Ok(debugger.rewind_to_output()),
+            DebugCommand::Break { line, column } => {
+                match debugger.add_breakpoint(line, column) {
+                    Some(id) => eprintln!("breakpoint {id} at {line}:{column}"),
+                    None => eprintln!("no instruction at {line}:{column}"),
+                }
+                continue;
+            }
+            DebugCommand::Delete(id) => {
+                if !debugger.remove_breakpoint(id) {
+                    eprintln!("no breakpoint {id}");
+                }
+                continue;
+            }
+            DebugCommand::Watch(index) => {
+                debugger.add_watchpoint(index);
+                continue;
+            }
+            DebugCommand::Unwatch(index) => {
+                if !debugger.remove_watchpoint(index) {
+                    eprintln!("no watchpoint [{index}]");
+                }
+                continue;
+            }
+            DebugCommand::Tape(radius) => {
+                eprintln!("{}", debugger.tape(radius));
+                continue;
+            }
+            DebugCommand::Where => {
+                eprint!("{}", debugger.location());
+                continue;
+            }
+            DebugCommand::Info => {
+                for (id, breakpoint) in debugger.breakpoints() {
+                    eprintln!(
+                        "breakpoint {id} at {}:{}",
+                        breakpoint.line, breakpoint.column
+                    );
+                }
+                for index in debugger.watchpoints() {
+                    eprintln!("watchpoint [{index}]");
+                }
+                continue;
+            }
+            DebugCommand::Help => {
+                eprintln!("{}", DebugCommand::HELP);
+                continue;
+            }
+            DebugCommand::Quit => break,
+        };
+
+        match stop {
+            Ok(Stop::Step) => {}
+            Ok(Stop::Breakpoint { id }) => eprintln!("breakpoint {id}"),
+            Ok(Stop::Watchpoint { index, old, new }) => {
+                eprintln!("watchpoint [{index}]: {old:?} -> {new:?}")
+            }
+            Ok(Stop::Finished) => eprintln!("finished"),
+            Ok(Stop::Beginning) => eprintln!("reached the beginning of the history"),
+            Err(e) => eprintln!("error: {e}"),
+        }
+        eprint!("{}", debugger.location());
+        match debugger.interpreter().pending_io() {
+            Some(PendingIo::Input { index }) => eprintln!("next: input into [{index}]"),
+            Some(PendingIo::Output { index, byte }) => {
+                eprintln!("next: output {:?} from [{index}]", byte as char)
+            }
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn run_jit<C: Cell>(block: &Block, memory_len: NonZeroIsize) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        memory_len.get() > 0,
+        "--jit はテープを伸ばせないので、--memory-len に正の値を指定する"
+    );
+
+    let jit = time!(bf::Jit::<C>::compile(block)?);
+    let mut memory = vec![C::default(); memory_len.get() as usize];
+    time!(jit.run(&mut memory, io::stdin().lock(), io::stdout().lock())?);
+    Ok(())
+}
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+fn run_jit<C: Cell>(_block: &Block, _memory_len: NonZeroIsize) -> anyhow::Result<()> {
+    anyhow::bail!("--jit は x86-64 Linux でのみ使える")
+}
@@ -0,0 +1,202 @@
+//! 最適化の前後で実行結果が変わらないかを、インタプリタで実際に実行して確かめる。
+
+use std::fmt;
+
+use thiserror::Error;
+
+use crate::{eof::EofBehavior, interpreter::InterPreter, ir::Block, utils::XorShift};
+
+use super::Pipeline;
+
+/// 1回の実行の結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Finished {
+        output: Vec<u8>,
+        memory: Vec<u8>,
+        pointer: usize,
+    },
+    /// エラーか命令数の上限で止まった
+    Failed(String),
+}
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Finished {
+                output, pointer, ..
+            } => write!(f, "output {output:?}, pointer {pointer}"),
+            Outcome::Failed(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+/// 最初に実行結果が変わったパス
+#[derive(Debug, Clone, Error)]
+#[error("{pass} (pass #{index}) changed the result for input {input:?}: expected {expected}, got {actual}")]
+pub struct Divergence {
+    pub pass: &'static str,
+    /// 何番目に実行したパスか
+    pub index: usize,
+    pub input: Vec<u8>,
+    pub expected: Outcome,
+    pub actual: Outcome,
+}
+
+/// 最適化前と、各パスを実行した後のプログラムを同じ入力で実行して比べる
+#[derive(Debug, Clone)]
+pub struct Differential {
+    /// ランダムに作る入力の数
+    pub inputs: usize,
+    /// 入力の最大の長さ。EOFの後は0を読む
+    pub max_input_len: usize,
+    pub seed: u64,
+    pub memory_len: usize,
+    /// 最適化前のプログラムの命令数の上限。超えた入力では比べない
+    pub max_steps: usize,
+}
+impl Default for Differential {
+    fn default() -> Self {
+        Self {
+            inputs: 8,
+            max_input_len: 16,
+            seed: 1,
+            memory_len: 1000,
+            max_steps: 100_000,
+        }
+    }
+}
+impl Differential {
+    /// `pipeline`のパスを順に実行し、実行結果が最初に変わったパスを返す。
+    /// 最適化前にエラーになったり止まらなかったりする入力は比べない
+    pub fn check(&self, block: &Block, pipeline: &Pipeline) -> Result<(), Box<Divergence>> {
+        let mut rng = XorShift::new(self.seed);
+        let inputs: Vec<Vec<u8>> = (0..self.inputs)
+            .map(|_| {
+                let len = rng.below(self.max_input_len as u64 + 1) as usize;
+                rng.bytes(len)
+            })
+            .collect();
+
+        let expected: Vec<_> = inputs
+            .iter()
+            .filter_map(|input| match self.run(block, input, self.max_steps) {
+                (outcome @ Outcome::Finished { .. }, steps) => Some((input, outcome, steps)),
+                (Outcome::Failed(_), _) => None,
+            })
+            .collect();
+        if expected.is_empty() {
+            return Ok(());
+        }
+
+        for (index, (pass, optimized)) in pipeline.stages(block).into_iter().enumerate() {
+            for (input, expected, steps) in &expected {
+                // パスによっては命令が一時的に増えるので、上限には余裕を持たせる
+                let (actual, _) = self.run(&optimized, input, steps * 4 + 64);
+                if actual != *expected {
+                    return Err(Box::new(Divergence {
+                        pass,
+                        index,
+                        input: input.to_vec(),
+                        expected: expected.clone(),
+                        actual,
+                    }));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn run(&self, block: &Block, input: &[u8], max_steps: usize) -> (Outcome, usize) {
+        run_interpreter(block, input, self.memory_len, max_steps)
+    }
+}
+
+/// 8bitのセルでEOFは0として実行し、`(結果, 実行した命令の数)`を返す
+pub(crate) fn run_interpreter(
+    block: &Block,
+    input: &[u8],
+    memory_len: usize,
+    max_steps: usize,
+) -> (Outcome, usize) {
+    let mut output = Vec::new();
+    let mut interpreter = InterPreter::builder()
+        .root_node(block)
+        .input(input)
+        .output(&mut output)
+        .memory(vec![0u8; memory_len])
+        .eof(EofBehavior::Zero)
+        .max_steps(max_steps)
+        .build();
+    let result = interpreter.run();
+    let (memory, pointer, steps) = (
+        interpreter.memory(),
+        interpreter.pointer(),
+        interpreter.count(),
+    );
+    drop(interpreter);
+    match result {
+        Ok(_) => (
+            Outcome::Finished {
+                output,
+                memory,
+                pointer,
+            },
+            steps,
+        ),
+        Err(e) => (Outcome::Failed(e.to_string()), steps),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        ir::{BlockItem, Op},
+        opt::{pipeline::Merge, OptLevel, Pass},
+        utils::bf_to_block,
+    };
+
+    use super::*;
+
+    /// `Add`を1つずつずらす、壊れたパス
+    struct Broken;
+    impl Pass for Broken {
+        fn name(&self) -> &'static str {
+            "broken"
+        }
+        fn run(&self, block: &mut Block) {
+            for item in &mut block.items {
+                if let BlockItem::Op(Op::Add(x, offset)) = item {
+                    *item = BlockItem::Op(Op::Add(*x + 1, *offset));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_differential() {
+        let differential = Differential::default();
+        for source in [
+            "+++[>+++<-]>.",
+            ",[.,]",
+            ",>,<[->+<]>.",
+            "+[-]-[-]+[+]",
+            "+++[[[[[>+++<-]]]]]>.",
+            ">>>+++>>>+++[-<+++>]",
+            ",[>+>+<<-]>[<+>-]>[-<<+>>]<<.",
+            "[-]>[<+>>+<-]>[-]<<[>>+<+<-]>[<+>-]>>++++[<<+++++>>-]<[-<[>>+>+<<<-]>>>[<<<+>>>-]+<[<<->>>-<[-]]>[<<[-]>>-]<<]<[>+<[-]]>",
+        ] {
+            let block = bf_to_block(source).unwrap();
+            for level in [OptLevel::O1, OptLevel::O2, OptLevel::O3] {
+                let result = differential.check(&block, &Pipeline::with_level(level));
+                assert!(result.is_ok(), "{source} -O{level}: {}", result.unwrap_err());
+            }
+        }
+
+        let block = bf_to_block(",+.").unwrap();
+        let pipeline = Pipeline::new()
+            .pass(Merge { is_top_level: true })
+            .pass(Broken);
+        let divergence = differential.check(&block, &pipeline).unwrap_err();
+        assert_eq!((divergence.pass, divergence.index), ("broken", 1));
+    }
+}
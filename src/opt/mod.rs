@@ -0,0 +1,708 @@
+use std::{collections::BTreeMap, ops::Add};
+
+use crate::{
+    cell::CellWidth,
+    ir::{Block, BlockItem, Op},
+    parse::Span,
+};
+
+pub(crate) use differential::run_interpreter;
+pub use differential::{Differential, Divergence, Outcome};
+pub use pipeline::{OptLevel, Pass, Pipeline};
+pub use stats::{IrCounts, OptStats, PassStats};
+pub use verify::{verify, Invariants, VerifyError};
+
+mod differential;
+pub mod pipeline;
+mod stats;
+mod verify;
+
+/// 決まった順にパスを実行する。パスを選びたいときは`Pipeline`を使う
+pub fn optimize(block: &Block, is_top_level: bool, non_negative_offset: bool) -> Block {
+    let merge = pipeline::Merge { is_top_level };
+    let mut pipeline = Pipeline::new()
+        .pass(merge)
+        .pass(pipeline::Unwrap)
+        .pass(pipeline::Clear)
+        .pass(pipeline::Mul::default())
+        .pass(merge)
+        .pass(pipeline::IfOpt)
+        .pass(pipeline::OffsetOpt);
+
+    if non_negative_offset {
+        pipeline = pipeline.pass(pipeline::NonNegativeOffset);
+    }
+
+    pipeline.pass(merge).pass(pipeline::RemoveNop).run(block)
+}
+
+pub fn optimize_for_interpreter(block: &mut Block) {
+    opt_lick(block)
+}
+
+impl Add for Op {
+    type Output = Option<Self>;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        match (self, rhs) {
+            (Op::Add(n, o), Op::Add(m, f)) if o == f => Some(Op::Add(n + m, o)),
+            (Op::MovePtr(n), Op::MovePtr(m)) => Some(Op::ptr(n + m)),
+            (Op::Set(0, o), Op::Mul(_, _, f)) if o == f => Some(Op::Set(0, o)),
+            (Op::Set(_, o), Op::Set(_, f)) if o == f => Some(rhs),
+            (Op::Add(_, o), Op::Set(_, f)) if o == f => Some(rhs),
+            (Op::Set(x, o), Op::Add(y, f)) if o == f => Some(Op::Set(x + y, o)),
+            // 0を足し引きするのは無駄なので、適当な機会に消滅してほしい。
+            (op, rhs) if rhs.is_nop() => Some(op),
+            (_, _) => None,
+        }
+    }
+}
+
+impl Add for &Op {
+    type Output = Option<Op>;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        (*self) + (*rhs)
+    }
+}
+
+fn remove_nop(block: &mut Block) {
+    block.retain(|item| !matches!(item, BlockItem::Op(op) if op.is_nop()));
+
+    block.items.iter_mut().for_each(|item| {
+        if let BlockItem::Loop(block) | BlockItem::If(block) = item {
+            remove_nop(block)
+        }
+    });
+}
+
+// 2回以上適用すると壊れる！（ポインターが負になる？）
+fn to_not_negative_offset(block: &Block) -> Block {
+    fn map_ops(ops: &mut Vec<(Op, Span)>, negative_offset: i32) {
+        ops.iter_mut().for_each(|(op, _)| {
+            *op = op
+                .map_offset(|offset| offset - negative_offset)
+                .unwrap_or(*op);
+        });
+
+        let span = ops
+            .iter()
+            .fold(Span::default(), |acc, (_, span)| acc.union(*span));
+        ops.insert(0, (Op::ptr(negative_offset), span));
+        ops.push((Op::ptr(-negative_offset), span));
+    }
+    let mut ops = vec![];
+
+    let mut offset = 0;
+    let mut min_offset = 0;
+
+    let mut new_block = Block::new();
+
+    for (item, span) in block.iter() {
+        match item {
+            BlockItem::Op(op) => {
+                if let Some(op_offset) = op.offset() {
+                    min_offset = min_offset.min(op_offset + offset);
+                }
+                if let Op::MovePtr(moving) = op {
+                    offset += moving;
+                }
+                ops.push((*op, span))
+            }
+            item @ (BlockItem::Loop(_) | BlockItem::If(_)) => {
+                if min_offset.is_negative() {
+                    map_ops(&mut ops, min_offset);
+                }
+                new_block.extend(ops.iter().map(|(op, span)| (BlockItem::Op(*op), *span)));
+
+                new_block.push_item(item.map_block(to_not_negative_offset).unwrap(), span);
+
+                ops.clear();
+
+                offset = 0;
+                min_offset = 0;
+            }
+        }
+    }
+
+    if min_offset.is_negative() {
+        map_ops(&mut ops, min_offset);
+    }
+    new_block.extend(ops.iter().map(|(op, span)| (BlockItem::Op(*op), *span)));
+
+    new_block
+}
+
+/// `block`から合体可能な命令を見つけて合体する。
+/// `is_top_level`を`true`にした場合、先頭に`Set(0, 0)`を追加して処理する。
+/// 合体した命令は、合体元の両方を含む範囲を持つ。
+pub(crate) fn merge(block: &Block, is_top_level: bool) -> Block {
+    let mut merged_block = Block::new();
+
+    if is_top_level {
+        merged_block.push_item(BlockItem::Op(Op::Set(0, 0)), Span::default());
+    }
+
+    for (item, span) in block.iter() {
+        let item = match item {
+            BlockItem::Loop(loop_block) => BlockItem::Loop(merge(loop_block, false)),
+            BlockItem::If(if_block) => BlockItem::If(merge(if_block, false)),
+            BlockItem::Op(op) => BlockItem::Op(*op),
+        };
+        merged_block.push_item(item, span);
+
+        // 連鎖的に消えるかもしれないのでwhile
+        while let Some(merged) = {
+            let lhs = merged_block
+                .items
+                .iter()
+                .nth_back(1)
+                .and_then(BlockItem::op);
+            let rhs = merged_block.items.last().and_then(BlockItem::op);
+
+            lhs.zip(rhs).and_then(|(lhs, rhs)| lhs + rhs)
+        } {
+            let (_, rhs_span) = merged_block.pop_item().unwrap();
+            let (_, lhs_span) = merged_block.pop_item().unwrap();
+            merged_block.push_item(BlockItem::Op(merged), lhs_span.union(rhs_span))
+        }
+    }
+
+    if is_top_level && Some(&BlockItem::Op(Op::Set(0, 0))) == merged_block.items.first() {
+        merged_block.items.remove(0);
+        merged_block.spans.remove(0);
+    }
+
+    merged_block
+}
+
+pub(crate) fn clear(block: &mut Block) {
+    for item in &mut block.items {
+        if let BlockItem::Loop(block) = item {
+            if let [BlockItem::Op(Op::Add(1, 0)) | BlockItem::Op(Op::Add(-1, 0))] =
+                block.items.as_slice()
+            {
+                *item = BlockItem::Op(Op::Set(0, 0));
+            } else {
+                clear(block);
+            }
+        }
+    }
+}
+
+pub(crate) fn unwrap(block: &mut Block) {
+    fn inner(item: &mut BlockItem) -> bool {
+        if let BlockItem::Loop(loop_block) = item {
+            if let [BlockItem::Loop(deep_loop_block)] = loop_block.items.as_slice() {
+                *loop_block = deep_loop_block.clone();
+                return true;
+            }
+        }
+        false
+    }
+    block.items.iter_mut().for_each(|item| {
+        while inner(item) {}
+        if let BlockItem::Loop(loop_block) = item {
+            unwrap(loop_block)
+        }
+    });
+}
+
+/// `step`ずつ変わるセルが0になるまでの回数を`n`として、`n * x`を
+/// `ループに入ったときのセルの値 * 戻り値`で表す。
+/// `step`が奇数なら、セル幅を法とした逆数で解ける。±1以外ではセル幅が必要で、
+/// 戻り値が`i32`に収まらなければ`None`
+fn loop_factor(step: i32, x: i32, cell_width: Option<CellWidth>) -> Option<i32> {
+    match step {
+        -1 => return Some(x),
+        1 => return x.checked_neg(),
+        _ if step % 2 == 0 => return None,
+        _ => {}
+    }
+    let bits = cell_width?.bits();
+    // ニュートン法で2^64を法とした逆数を求める。1回ごとに正しいbit数が倍になる
+    let step = step as i64 as u64;
+    let mut inverse = step;
+    for _ in 0..5 {
+        inverse = inverse.wrapping_mul(2u64.wrapping_sub(step.wrapping_mul(inverse)));
+    }
+    // c + n * step ≡ 0 より n ≡ -c * inverse
+    let factor = inverse.wrapping_mul(x as i64 as u64).wrapping_neg();
+    // セル幅より上のbitは結果に影響しないので、符号拡張して小さくする
+    let shift = 64 - bits;
+    i32::try_from(((factor << shift) as i64) >> shift).ok()
+}
+
+/// カウンタを1周ごとに奇数ずつ変えるループを`Mul`にする。
+/// ±1以外の場合はセルの値がwrapすることを使うので、`cell_width`がなければ最適化しない
+pub(crate) fn mul(block: &mut Block, cell_width: Option<CellWidth>) {
+    #[derive(Debug, PartialEq, Eq)]
+    enum OpType {
+        Mul(i32),
+        Set(i32),
+    }
+    impl OpType {
+        fn mul(&mut self, x: i32) {
+            match self {
+                OpType::Mul(y) | OpType::Set(y) => *y += x,
+            }
+        }
+    }
+
+    /// 最適化済みの内側のループ。`offset`のセルを配って0にする
+    struct Inner {
+        offset: i32,
+        /// `(to, x)`
+        muls: Vec<(i32, i32)>,
+        /// 外側のループに入る前の値も配る（外側で`Set`されていない）
+        from_initial: bool,
+    }
+
+    /// `[Mul(to, x, 0)..., Set(0, 0)]`の形のif
+    fn distribution(if_block: &Block) -> Option<Vec<(i32, i32)>> {
+        let (last, muls) = if_block.items.split_last()?;
+        if last != &BlockItem::Op(Op::Set(0, 0)) {
+            return None;
+        }
+        muls.iter()
+            .map(|item| match item {
+                BlockItem::Op(Op::Mul(to, x, 0)) if *to != 0 => Some((*to, *x)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn linear_loop(loop_block: &Block, span: Span, cell_width: Option<CellWidth>) -> Option<Block> {
+        let mut offset_op = BTreeMap::<_, OpType>::new();
+        let mut inners = Vec::<Inner>::new();
+        let mut ptr_offset = 0;
+
+        for item in &loop_block.items {
+            match item {
+                BlockItem::Op(Op::Add(v, of)) => {
+                    let offset = ptr_offset + *of;
+                    // 配った後のセルに触ると、次の周でまた配ることになる
+                    if inners.iter().any(|inner| inner.offset == offset) {
+                        return None;
+                    }
+                    offset_op
+                        .entry(offset)
+                        .and_modify(|x| x.mul(*v))
+                        .or_insert(OpType::Mul(*v));
+                }
+                BlockItem::Op(Op::Set(v, of)) => {
+                    let offset = ptr_offset + *of;
+                    if inners.iter().any(|inner| inner.offset == offset) {
+                        return None;
+                    }
+                    offset_op.insert(offset, OpType::Set(*v));
+                }
+                BlockItem::Op(Op::MovePtr(of)) => ptr_offset += *of,
+                // 最初の周ではループに入る前の値とその周で足した値を、
+                // 2周目からはその周で足した値だけを配る
+                BlockItem::If(if_block) => {
+                    let muls = distribution(if_block)?;
+                    let offset = ptr_offset;
+                    if inners.iter().any(|inner| inner.offset == offset) {
+                        return None;
+                    }
+                    let (from_initial, per_iteration) = match offset_op.get(&offset) {
+                        None => (true, 0),
+                        Some(OpType::Mul(x)) => (true, *x),
+                        Some(OpType::Set(x)) => (false, *x),
+                    };
+                    for (to, x) in &muls {
+                        offset_op
+                            .entry(offset + to)
+                            .or_insert(OpType::Mul(0))
+                            .mul(x.checked_mul(per_iteration)?);
+                    }
+                    offset_op.insert(offset, OpType::Set(0));
+                    inners.push(Inner {
+                        offset,
+                        muls,
+                        from_initial,
+                    });
+                }
+                // 最適化できないものが混じっていたらreturn
+                BlockItem::Loop(_)
+                | BlockItem::Op(Op::Mul(_, _, _) | Op::Lick(_) | Op::Out(_) | Op::Input(_)) => {
+                    return None
+                }
+            };
+        }
+
+        let Some(&OpType::Mul(step)) = offset_op.get(&0) else {
+            return None;
+        };
+        if ptr_offset != 0 {
+            return None;
+        }
+        // 配る先はカウンタや配る元であってはならず、`Set`すると順番が変わってしまう
+        for inner in &inners {
+            for (to, _) in &inner.muls {
+                let to = inner.offset + to;
+                if to == 0
+                    || inners.iter().any(|inner| inner.offset == to)
+                    || matches!(offset_op.get(&to), Some(OpType::Set(_)))
+                {
+                    return None;
+                }
+            }
+        }
+
+        let mut mul_ops = Block::new();
+        // 生成した命令はすべて元のループの範囲を持つ
+        for inner in inners.iter().filter(|inner| inner.from_initial) {
+            mul_ops.push_item(BlockItem::Op(Op::ptr(inner.offset)), span);
+            for (to, x) in &inner.muls {
+                mul_ops.push_item(BlockItem::Op(Op::Mul(*to, *x, 0)), span);
+            }
+            mul_ops.push_item(BlockItem::Op(Op::ptr(-inner.offset)), span);
+        }
+        for (offset, value) in offset_op {
+            // 0は最後に処理
+            if offset == 0 {
+                continue;
+            }
+            match value {
+                OpType::Mul(value) => {
+                    let factor = loop_factor(step, value, cell_width)?;
+                    mul_ops.push_item(BlockItem::Op(Op::Mul(offset, factor, 0)), span);
+                }
+                OpType::Set(value) => {
+                    mul_ops.push_item(BlockItem::Op(Op::ptr(offset)), span);
+                    mul_ops.push_item(BlockItem::Op(Op::Set(value, 0)), span);
+                    mul_ops.push_item(BlockItem::Op(Op::ptr(-offset)), span);
+                }
+            };
+        }
+        mul_ops.push_item(BlockItem::Op(Op::Set(0, 0)), span);
+        Some(mul_ops)
+    }
+
+    for (item, span) in block.iter_mut() {
+        match item {
+            BlockItem::Loop(loop_block) => {
+                // 内側のループを先に最適化すると、外側も最適化できることがある
+                mul(loop_block, cell_width);
+                if let Some(mul_ops) = linear_loop(loop_block, span, cell_width) {
+                    // 「このif、いらなくない？」と思うじゃろ？
+                    // ところがどっこい、このifがないと、配列外参照を起こす可能性があるぞい。
+                    // 例としては、`[<+>]`を最適化すると、C言語相当で
+                    // ```c
+                    // int idx = 0;
+                    // mem[idx-1] += mem[idx];
+                    // mem[idx] = 0;
+                    // ```
+                    // となり、mem[-1]にアクセスしてしまうというものがあるぞい。
+                    *item = BlockItem::If(mul_ops);
+                }
+            }
+            BlockItem::If(if_block) => mul(if_block, cell_width),
+            BlockItem::Op(_) => (),
+        };
+    }
+}
+
+pub(crate) fn offset_opt(block: &Block) -> Block {
+    // Loop | If | Lickで区切られた命令列の中で、ポインタの移動をoffsetに畳み込む。
+    // 区切りの直前でまとめてポインタを移動して帳尻を合わせる。
+    fn flush(
+        optimized_block: &mut Block,
+        offset_ops: &mut Vec<(Op, Span)>,
+        offset: &mut i32,
+        move_span: &mut Span,
+    ) {
+        optimized_block.extend(
+            offset_ops
+                .drain(..)
+                .map(|(op, span)| (BlockItem::Op(op), span)),
+        );
+        // 帳尻を合わせる
+        optimized_block.push_item(BlockItem::Op(Op::ptr(*offset)), *move_span);
+
+        *offset = 0;
+        *move_span = Span::default();
+    }
+
+    let mut optimized_block = Block::new();
+
+    let mut offset_ops = Vec::new();
+    let mut offset = 0;
+    let mut move_span = Span::default();
+
+    for (item, span) in block.iter() {
+        match item {
+            BlockItem::Op(Op::MovePtr(x)) => {
+                offset += *x;
+                move_span = move_span.union(span);
+            }
+            BlockItem::Op(op @ Op::Lick(_)) => {
+                flush(
+                    &mut optimized_block,
+                    &mut offset_ops,
+                    &mut offset,
+                    &mut move_span,
+                );
+                optimized_block.push_item(BlockItem::Op(*op), span);
+            }
+            BlockItem::Op(op) => offset_ops.push((op.map_offset(|of| of + offset).unwrap(), span)),
+            BlockItem::Loop(_) | BlockItem::If(_) => {
+                flush(
+                    &mut optimized_block,
+                    &mut offset_ops,
+                    &mut offset,
+                    &mut move_span,
+                );
+                optimized_block.push_item(item.map_block(offset_opt).unwrap(), span);
+            }
+        }
+    }
+    flush(
+        &mut optimized_block,
+        &mut offset_ops,
+        &mut offset,
+        &mut move_span,
+    );
+
+    optimized_block
+}
+
+pub fn if_opt(block: &mut Block) {
+    fn inner(loop_item: &mut BlockItem) {
+        match loop_item {
+            BlockItem::Loop(block) => {
+                if Some(&BlockItem::Op(Op::Set(0, 0))) == block.items.last() {
+                    if block.items.len() == 1 {
+                        *loop_item = BlockItem::Op(Op::Set(0, 0));
+                    } else {
+                        let mut if_block = block.clone();
+                        if_opt(&mut if_block);
+                        *loop_item = BlockItem::If(if_block);
+                    }
+                } else {
+                    if_opt(block);
+                }
+            }
+            BlockItem::If(block) => if_opt(block),
+            BlockItem::Op(_) => (),
+        }
+    }
+    block.items.iter_mut().for_each(inner);
+}
+
+fn opt_lick(block: &mut Block) {
+    for block_item in &mut block.items {
+        // 中がポインタ移動のみか判定する
+        fn is_only_move_ptr(block: &Block) -> i32 {
+            if let [BlockItem::Op(Op::MovePtr(x))] = block.items.as_slice() {
+                *x
+            } else {
+                0
+            }
+        }
+
+        if let BlockItem::Loop(loop_block) = block_item {
+            let offset = is_only_move_ptr(loop_block);
+            if offset != 0 {
+                log::debug!("lick: {offset}");
+                *block_item = BlockItem::Op(Op::Lick(offset));
+            }
+        }
+
+        if let BlockItem::Loop(block) | BlockItem::If(block) = block_item {
+            opt_lick(block)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use crate::{cell::Cell, utils::bf_to_block, InterPreter};
+
+    use super::*;
+
+    fn run(block: &Block) -> (Vec<u8>, usize) {
+        let mut interpreter = InterPreter::builder()
+            .memory(vec![0u8; 300000])
+            .input(io::empty())
+            .output(io::sink())
+            .root_node(block)
+            .build();
+
+        interpreter.run().unwrap();
+        let memory = interpreter.memory().to_vec();
+        let pointer = interpreter.pointer();
+        (memory, pointer)
+    }
+
+    #[test]
+    fn same_state() {
+        let block = bf_to_block("+++[>+++<-]>.").unwrap();
+        let optimized_block = optimize(&block, true, false);
+        assert_eq!(run(&block), run(&optimized_block));
+
+        let block = bf_to_block("+++++++>>>>>>>>>--------<<<<<<<<<++++++").unwrap();
+        let optimized_block = optimize(&block, true, false);
+        assert_eq!(run(&block), run(&optimized_block));
+
+        let block = bf_to_block("+[-]-[-]+[+]").unwrap();
+        let optimized_block = optimize(&block, true, false);
+        assert_eq!(run(&block), run(&optimized_block));
+
+        let block = bf_to_block("+++[[[[[>+++<-]]]]]>.").unwrap();
+        let optimized_block = optimize(&block, true, false);
+        assert_eq!(run(&block), run(&optimized_block));
+
+        let block = bf_to_block("+++++[[-]>++++++<]").unwrap();
+        let optimized_block = optimize(&block, true, false);
+        assert_eq!(run(&block), run(&optimized_block));
+
+        let block = bf_to_block(">>>+++>>>+++[-<+++>]").unwrap();
+        let optimized_block = optimize(&block, true, false);
+        assert_eq!(run(&block), run(&optimized_block));
+
+        let block = bf_to_block("[-]>[<+>>+<-]>[-]<<[>>+<+<-]>[<+>-]>>++++[<<+++++>>-]<[-<[>>+>+<<<-]>>>[<<<+>>>-]+<[<<->>>-<[-]]>[<<[-]>>-]<<]<[>+<[-]]>").unwrap();
+        let optimized_block = optimize(&block, true, false);
+        assert_eq!(run(&block), run(&optimized_block));
+    }
+
+    #[test]
+    fn test_offset_opt() {
+        let block = bf_to_block("+>+>+>+[-]<<<->>>").unwrap();
+        let mut optimized_block = block.clone();
+        clear(&mut optimized_block);
+        let optimized_block = offset_opt(&optimized_block);
+        assert_eq!(
+            &[
+                BlockItem::Op(Op::Add(1, 0)),
+                BlockItem::Op(Op::Add(1, 1)),
+                BlockItem::Op(Op::Add(1, 2)),
+                BlockItem::Op(Op::Add(1, 3)),
+                BlockItem::Op(Op::Set(0, 3)),
+                BlockItem::Op(Op::Add(-1, 0)),
+                BlockItem::Op(Op::ptr(3)),
+            ],
+            optimized_block.items.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_merge_span() {
+        let block = bf_to_block("+ +\n>>[-]").unwrap();
+        let merged = merge(&block, true);
+
+        assert_eq!(
+            merged.spans,
+            [Span::new(0, 3), Span::new(4, 6), Span::new(6, 9)]
+        );
+        // ループ内の命令も範囲を保つ
+        let BlockItem::Loop(loop_block) = &merged.items[2] else {
+            panic!()
+        };
+        assert_eq!(loop_block.spans, [Span::new(7, 8)]);
+    }
+
+    #[test]
+    fn test_unwrap() {
+        let mut block = bf_to_block("[[[[[-]]]]]").unwrap();
+        unwrap(&mut block);
+
+        assert_eq!(block, bf_to_block("[-]").unwrap());
+
+        let mut block = bf_to_block("[[[+][[[-]]]]]").unwrap();
+        unwrap(&mut block);
+
+        assert_eq!(block, bf_to_block("[[+][-]]").unwrap());
+    }
+
+    #[test]
+    fn test_mul_step() {
+        fn run<C: Cell>(block: &Block) -> Vec<C> {
+            let mut interpreter = InterPreter::builder()
+                .root_node(block)
+                .input(io::empty())
+                .output(io::sink())
+                .memory(vec![C::default(); 8])
+                .build();
+            interpreter.run().unwrap();
+            interpreter.memory()
+        }
+        fn optimized(block: &Block, cell_width: CellWidth) -> Block {
+            Pipeline::with_level(OptLevel::O2)
+                .cell_width(cell_width)
+                .run(block)
+        }
+        fn has_loop(block: &Block) -> bool {
+            block
+                .items
+                .iter()
+                .any(|item| matches!(item, BlockItem::Loop(_)))
+        }
+
+        assert_eq!(loop_factor(-1, 5, None), Some(5));
+        assert_eq!(loop_factor(1, 5, None), Some(-5));
+        assert_eq!(loop_factor(-3, 1, None), None);
+        assert_eq!(loop_factor(-3, 1, Some(CellWidth::W8)), Some(-85));
+        assert_eq!(loop_factor(-2, 1, Some(CellWidth::W8)), None);
+
+        let optimizable = [
+            "+++++[--->+<]",
+            "++[--->+++>-<<]",
+            "+++++++[+++>++>-<<]",
+            "+++[+>+<]",
+            "++[>+++[>++<-]<-]",
+            "+++>+++++<[>[->+<]<-]",
+            "++[>[-]+++[>+++<-]<-]",
+            "++[>+++++[--->+<]<-]",
+        ];
+        let not_optimizable = [
+            "++[-->+++<]",
+            "++[>+[->+<]>[-<+>]<<-]",
+            "++[>[->+<]+<-]",
+            "+++>>+<<[>>[-<<+>>]<<-]",
+        ];
+        for source in optimizable.into_iter().chain(not_optimizable) {
+            let block = bf_to_block(source).unwrap();
+            let optimized_8 = optimized(&block, CellWidth::W8);
+            let optimized_16 = optimized(&block, CellWidth::W16);
+            assert_eq!(run::<u8>(&block), run::<u8>(&optimized_8), "{source}");
+            assert_eq!(run::<u16>(&block), run::<u16>(&optimized_16), "{source}");
+            assert_eq!(
+                has_loop(&optimized_8),
+                not_optimizable.contains(&source),
+                "{source}"
+            );
+        }
+
+        // 2^32を法とした3の逆数だけ回る
+        let block = bf_to_block("+[--->+<]").unwrap();
+        let optimized_32 = optimized(&block, CellWidth::W32);
+        assert!(!has_loop(&optimized_32));
+        assert_eq!(run::<u32>(&optimized_32)[..2], [0, 0xaaaa_aaab]);
+        // 2^64を法とした逆数は`i32`に収まらない
+        assert!(has_loop(&optimized(&block, CellWidth::W64)));
+    }
+
+    #[test]
+    fn test_to_not_negative_offset() {
+        let block = Block::from_items(vec![
+            BlockItem::Op(Op::Add(1, -5)),
+            BlockItem::Op(Op::ptr(-5)),
+        ]);
+        let block = to_not_negative_offset(&block);
+
+        assert_eq!(
+            block.items,
+            vec![
+                BlockItem::Op(Op::ptr(-5)),
+                BlockItem::Op(Op::Add(1, 0)),
+                BlockItem::Op(Op::ptr(-5)),
+                BlockItem::Op(Op::ptr(5)),
+            ]
+        );
+    }
+}
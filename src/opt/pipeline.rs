@@ -0,0 +1,487 @@
+//! 最適化パスを名前で並べて実行する。
+
+use std::{fmt, str::FromStr, time::Instant};
+
+use log::debug;
+
+use crate::{
+    cell::{CellWidth, OverflowPolicy},
+    ir::Block,
+};
+
+use super::{
+    stats::{IrCounts, OptStats, PassStats},
+    verify::{verify, Invariants},
+};
+
+/// 1つの最適化
+pub trait Pass {
+    /// `--passes`で指定する名前
+    fn name(&self) -> &'static str;
+    fn run(&self, block: &mut Block);
+    /// 何度実行しても壊れないか。
+    /// falseなら、固定点まで繰り返すときも変化がなくなった後に1回だけ実行する
+    fn repeatable(&self) -> bool {
+        true
+    }
+    /// 実行後に成り立つ性質に更新する。
+    /// デフォルトでは、offsetに関する性質は崩れるものとする
+    fn update_invariants(&self, invariants: &mut Invariants) {
+        invariants.non_negative_offset = false;
+        invariants.folded_offsets = false;
+    }
+    /// `Pipeline::cell_width`で呼ばれる。セル幅によって結果が変わるパスだけが使う
+    fn set_cell_width(&mut self, _cell_width: CellWidth) {}
+}
+
+/// 合体できる命令を合体する。`is_top_level`ならテープが0で始まることも使う
+#[derive(Debug, Clone, Copy)]
+pub struct Merge {
+    pub is_top_level: bool,
+}
+impl Pass for Merge {
+    fn name(&self) -> &'static str {
+        "merge"
+    }
+    fn run(&self, block: &mut Block) {
+        *block = super::merge(block, self.is_top_level);
+    }
+    fn update_invariants(&self, _invariants: &mut Invariants) {}
+}
+
+/// 入れ子になっただけのループを外す
+#[derive(Debug, Clone, Copy)]
+pub struct Unwrap;
+impl Pass for Unwrap {
+    fn name(&self) -> &'static str {
+        "unwrap"
+    }
+    fn run(&self, block: &mut Block) {
+        super::unwrap(block);
+    }
+    fn update_invariants(&self, _invariants: &mut Invariants) {}
+}
+
+/// `[-]`を`Set(0)`にする
+#[derive(Debug, Clone, Copy)]
+pub struct Clear;
+impl Pass for Clear {
+    fn name(&self) -> &'static str {
+        "clear"
+    }
+    fn run(&self, block: &mut Block) {
+        super::clear(block);
+    }
+    fn update_invariants(&self, invariants: &mut Invariants) {
+        // ループの直前にあった`MovePtr`が`Set`の直前になる
+        invariants.folded_offsets = false;
+    }
+}
+
+/// 掛け算のループを`Mul`にする。
+/// `cell_width`があれば、カウンタが±1以外の奇数ずつ変わるループも最適化する（セルの値はwrapするものとする）
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Mul {
+    pub cell_width: Option<CellWidth>,
+}
+impl Pass for Mul {
+    fn name(&self) -> &'static str {
+        "mul"
+    }
+    fn run(&self, block: &mut Block) {
+        super::mul(block, self.cell_width);
+    }
+    fn set_cell_width(&mut self, cell_width: CellWidth) {
+        self.cell_width = Some(cell_width);
+    }
+}
+
+/// 最後に0にするループをifにする
+#[derive(Debug, Clone, Copy)]
+pub struct IfOpt;
+impl Pass for IfOpt {
+    fn name(&self) -> &'static str {
+        "if-opt"
+    }
+    fn run(&self, block: &mut Block) {
+        super::if_opt(block);
+    }
+    fn update_invariants(&self, _invariants: &mut Invariants) {}
+}
+
+/// ポインタの移動を命令のoffsetに畳み込む
+#[derive(Debug, Clone, Copy)]
+pub struct OffsetOpt;
+impl Pass for OffsetOpt {
+    fn name(&self) -> &'static str {
+        "offset-opt"
+    }
+    fn run(&self, block: &mut Block) {
+        *block = super::offset_opt(block);
+    }
+    fn update_invariants(&self, invariants: &mut Invariants) {
+        invariants.non_negative_offset = false;
+        invariants.folded_offsets = true;
+    }
+}
+
+/// 何もしない命令を消す
+#[derive(Debug, Clone, Copy)]
+pub struct RemoveNop;
+impl Pass for RemoveNop {
+    fn name(&self) -> &'static str {
+        "remove-nop"
+    }
+    fn run(&self, block: &mut Block) {
+        super::remove_nop(block);
+    }
+    fn update_invariants(&self, _invariants: &mut Invariants) {}
+}
+
+/// ポインタを動かすだけのループを`Lick`にする
+#[derive(Debug, Clone, Copy)]
+pub struct Lick;
+impl Pass for Lick {
+    fn name(&self) -> &'static str {
+        "lick"
+    }
+    fn run(&self, block: &mut Block) {
+        super::opt_lick(block);
+    }
+    fn update_invariants(&self, invariants: &mut Invariants) {
+        invariants.allow_lick = true;
+    }
+}
+
+/// 負のoffsetを使わないようにする。
+/// `offset-opt`の直後の形を前提にしているので、2回以上適用すると壊れる
+#[derive(Debug, Clone, Copy)]
+pub struct NonNegativeOffset;
+impl Pass for NonNegativeOffset {
+    fn name(&self) -> &'static str {
+        "non-negative-offset"
+    }
+    fn run(&self, block: &mut Block) {
+        *block = super::to_not_negative_offset(block);
+    }
+    fn repeatable(&self) -> bool {
+        false
+    }
+    fn update_invariants(&self, invariants: &mut Invariants) {
+        // 区切りの先頭にも`MovePtr`を置く
+        invariants.non_negative_offset = true;
+        invariants.folded_offsets = false;
+    }
+}
+
+/// 名前からパスを作る。`merge`はトップレベル用
+pub fn pass_by_name(name: &str) -> Option<Box<dyn Pass>> {
+    let pass: Box<dyn Pass> = match name {
+        "merge" => Box::new(Merge { is_top_level: true }),
+        "unwrap" => Box::new(Unwrap),
+        "clear" => Box::new(Clear),
+        "mul" => Box::new(Mul::default()),
+        "if-opt" => Box::new(IfOpt),
+        "offset-opt" => Box::new(OffsetOpt),
+        "non-negative-offset" => Box::new(NonNegativeOffset),
+        "remove-nop" => Box::new(RemoveNop),
+        "lick" => Box::new(Lick),
+        _ => return None,
+    };
+    Some(pass)
+}
+
+/// 最適化の強さ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum OptLevel {
+    /// 最適化しない
+    #[default]
+    O0,
+    /// 隣り合う命令の合体と`[-]`だけ
+    O1,
+    /// すべてのパスを1回ずつ
+    O2,
+    /// すべてのパスを変化がなくなるまで繰り返す
+    O3,
+}
+
+impl FromStr for OptLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(OptLevel::O0),
+            "1" => Ok(OptLevel::O1),
+            "2" => Ok(OptLevel::O2),
+            "3" => Ok(OptLevel::O3),
+            _ => Err(format!("invalid optimization level: {s} (0, 1, 2, 3)")),
+        }
+    }
+}
+
+impl fmt::Display for OptLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let level = match self {
+            OptLevel::O0 => 0,
+            OptLevel::O1 => 1,
+            OptLevel::O2 => 2,
+            OptLevel::O3 => 3,
+        };
+        write!(f, "{level}")
+    }
+}
+
+/// 固定点まで繰り返すときの上限。パスの組み合わせによっては変化し続けることがある
+const MAX_ITERATIONS: usize = 16;
+
+/// 最適化パスの列
+#[derive(Default)]
+pub struct Pipeline {
+    passes: Vec<Box<dyn Pass>>,
+    fixed_point: bool,
+    cell_width: Option<CellWidth>,
+}
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_level(level: OptLevel) -> Self {
+        let top = Merge { is_top_level: true };
+        match level {
+            OptLevel::O0 => Self::new(),
+            OptLevel::O1 => Self::new()
+                .pass(top)
+                .pass(Unwrap)
+                .pass(Clear)
+                .pass(top)
+                .pass(RemoveNop),
+            OptLevel::O2 => Self::new()
+                .pass(top)
+                .pass(Unwrap)
+                .pass(Clear)
+                .pass(Mul::default())
+                .pass(top)
+                .pass(IfOpt)
+                .pass(OffsetOpt)
+                .pass(top)
+                .pass(RemoveNop)
+                .pass(Lick),
+            OptLevel::O3 => Self::with_level(OptLevel::O2).fixed_point(true),
+        }
+    }
+    /// `merge,mul,...`のように、カンマ区切りの名前から作る
+    pub fn from_names(names: &str) -> Result<Self, String> {
+        let mut pipeline = Self::new();
+        for name in names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            let pass = pass_by_name(name).ok_or_else(|| format!("unknown pass: {name}"))?;
+            pipeline.passes.push(pass);
+        }
+        Ok(pipeline)
+    }
+    pub fn pass(mut self, pass: impl Pass + 'static) -> Self {
+        let mut pass: Box<dyn Pass> = Box::new(pass);
+        if let Some(cell_width) = self.cell_width {
+            pass.set_cell_width(cell_width);
+        }
+        self.passes.push(pass);
+        self
+    }
+    /// 実行するときのセル幅を教える。セルの値がwrapしない場合は指定しない
+    pub fn cell_width(mut self, cell_width: CellWidth) -> Self {
+        for pass in &mut self.passes {
+            pass.set_cell_width(cell_width);
+        }
+        self.cell_width = Some(cell_width);
+        self
+    }
+    /// 変化がなくなるまで繰り返す
+    pub fn fixed_point(self, fixed_point: bool) -> Self {
+        Self {
+            fixed_point,
+            ..self
+        }
+    }
+    pub fn names(&self) -> Vec<&'static str> {
+        self.passes.iter().map(|pass| pass.name()).collect()
+    }
+    /// セルの値がwrapしないと、`-+`を消すなどの最適化で結果が変わる。
+    /// そのため`wrap`以外では、パスが1つもない場合だけ使える
+    pub fn supports(&self, overflow: OverflowPolicy) -> bool {
+        overflow == OverflowPolicy::Wrap || self.passes.is_empty()
+    }
+
+    pub fn run(&self, block: &Block) -> Block {
+        self.run_with_stats(block).0
+    }
+    /// パスごとにIRがどう変わったかも返す
+    pub fn run_with_stats(&self, block: &Block) -> (Block, OptStats) {
+        self.run_inner(block, |_, _| {})
+    }
+    /// 各パスを実行した直後のIRを、実行した順に返す
+    pub fn stages(&self, block: &Block) -> Vec<(&'static str, Block)> {
+        let mut stages = Vec::new();
+        self.run_inner(block, |pass, block| {
+            stages.push((pass.name(), block.clone()))
+        });
+        stages
+    }
+
+    fn run_inner(
+        &self,
+        block: &Block,
+        mut after_pass: impl FnMut(&dyn Pass, &Block),
+    ) -> (Block, OptStats) {
+        let mut block = block.clone();
+        let mut stats = OptStats {
+            before: IrCounts::of(&block),
+            ..Default::default()
+        };
+        let mut invariants = Invariants::default();
+        let mut run = |pass: &dyn Pass, block: &mut Block| {
+            stats.passes.push(run_pass(pass, block, &mut invariants));
+            after_pass(pass, block);
+        };
+        if !self.fixed_point {
+            for pass in &self.passes {
+                run(pass.as_ref(), &mut block);
+            }
+        } else {
+            for iteration in 1..=MAX_ITERATIONS {
+                let before = block.clone();
+                for pass in self.passes.iter().filter(|pass| pass.repeatable()) {
+                    run(pass.as_ref(), &mut block);
+                }
+                if block == before {
+                    debug!("fixed point after {iteration} iterations");
+                    break;
+                }
+            }
+            for pass in self.passes.iter().filter(|pass| !pass.repeatable()) {
+                run(pass.as_ref(), &mut block);
+            }
+        }
+        stats.after = IrCounts::of(&block);
+        (block, stats)
+    }
+}
+
+/// パスを1つ実行する。デバッグビルドでは、実行後のIRが`invariants`を満たすか確かめる
+fn run_pass(pass: &dyn Pass, block: &mut Block, invariants: &mut Invariants) -> PassStats {
+    let before = IrCounts::of(block);
+    let size = block.size();
+    let instant = Instant::now();
+    pass.run(block);
+    let elapsed = instant.elapsed();
+    debug!(
+        "{}: {size} -> {} items, {elapsed:?}",
+        pass.name(),
+        block.size(),
+    );
+
+    pass.update_invariants(invariants);
+    if cfg!(debug_assertions) {
+        if let Err(e) = verify(block, *invariants) {
+            panic!("IR is broken after {}: {e}", pass.name());
+        }
+    }
+    PassStats::new(pass.name(), before, IrCounts::of(block), elapsed)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::{opt::optimize, utils::bf_to_block, InterPreter};
+
+    use super::*;
+
+    fn run(block: &Block) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut interpreter = InterPreter::builder()
+            .root_node(block)
+            .input(io::empty())
+            .output(&mut output)
+            .memory(vec![0u8; 30000])
+            .build();
+        interpreter.run().unwrap();
+        output
+    }
+
+    #[test]
+    fn test_levels() {
+        for (source, expected) in [
+            (
+                include_str!("../../bf_codes/hello_world.bf"),
+                include_str!("../../bf_codes/hello_world.out"),
+            ),
+            (
+                "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.",
+                "Hello World!\n",
+            ),
+        ] {
+            let block = bf_to_block(source).unwrap();
+            let mut sizes = Vec::new();
+            for level in [OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3] {
+                let optimized = Pipeline::with_level(level).run(&block);
+                assert_eq!(run(&optimized), expected.as_bytes(), "-O{level}");
+                sizes.push(optimized.size());
+            }
+            assert_eq!(sizes[0], block.size());
+            assert!(sizes.windows(2).all(|w| w[0] >= w[1]), "{sizes:?}");
+        }
+    }
+
+    #[test]
+    fn test_same_as_optimize() {
+        let block = bf_to_block("+++[>+++<-]>[-]<<+[>]").unwrap();
+        let pipeline = Pipeline::from_names(
+            "merge,unwrap,clear,mul,merge,if-opt,offset-opt,non-negative-offset,merge,remove-nop",
+        )
+        .unwrap();
+        assert_eq!(pipeline.run(&block), optimize(&block, true, true));
+    }
+
+    #[test]
+    fn test_overflow() {
+        fn run(block: &Block, input: &[u8], overflow: OverflowPolicy) -> Option<Vec<u8>> {
+            let mut output = Vec::new();
+            let mut interpreter = InterPreter::builder()
+                .root_node(block)
+                .input(input)
+                .output(&mut output)
+                .memory(vec![0u8; 10])
+                .overflow(overflow)
+                .build();
+            let result = interpreter.run();
+            drop(interpreter);
+            result.ok().map(|_| output)
+        }
+
+        for (source, input) in [("-+.", &[][..]), (",+-.", &[0xff])] {
+            let block = bf_to_block(source).unwrap();
+            for overflow in [OverflowPolicy::Saturate, OverflowPolicy::Trap] {
+                let expected = run(&block, input, overflow);
+                let o0 = Pipeline::with_level(OptLevel::O0);
+                assert!(o0.supports(overflow));
+                assert_eq!(run(&o0.run(&block), input, overflow), expected);
+                // `-+`を消すと結果が変わるので、-O2は使えない
+                let o2 = Pipeline::with_level(OptLevel::O2);
+                assert!(!o2.supports(overflow));
+                assert_ne!(run(&o2.run(&block), input, overflow), expected, "{source}");
+            }
+            assert!(Pipeline::with_level(OptLevel::O2).supports(OverflowPolicy::Wrap));
+        }
+    }
+
+    #[test]
+    fn test_from_names() {
+        let pipeline = Pipeline::from_names("merge, mul,,lick").unwrap();
+        assert_eq!(pipeline.names(), ["merge", "mul", "lick"]);
+        assert!(Pipeline::from_names("merge,nope").is_err());
+        assert_eq!("3".parse(), Ok(OptLevel::O3));
+    }
+}
@@ -0,0 +1,167 @@
+//! 最適化パスごとに、IRがどう変わったかを数える。
+
+use std::{fmt, time::Duration};
+
+use crate::ir::{Block, BlockItem, Op};
+
+/// IRに含まれる要素の数。ループやifの中も数える
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+pub struct IrCounts {
+    /// `BlockItem::Op`の数
+    pub ops: usize,
+    pub loops: usize,
+    pub ifs: usize,
+    pub sets: usize,
+    pub muls: usize,
+    pub licks: usize,
+}
+impl IrCounts {
+    pub fn of(block: &Block) -> Self {
+        let mut counts = Self::default();
+        counts.add(block);
+        counts
+    }
+    fn add(&mut self, block: &Block) {
+        for item in &block.items {
+            match item {
+                BlockItem::Op(op) => {
+                    self.ops += 1;
+                    match op {
+                        Op::Set(..) => self.sets += 1,
+                        Op::Mul(..) => self.muls += 1,
+                        Op::Lick(_) => self.licks += 1,
+                        _ => {}
+                    }
+                }
+                BlockItem::Loop(block) => {
+                    self.loops += 1;
+                    self.add(block);
+                }
+                BlockItem::If(block) => {
+                    self.ifs += 1;
+                    self.add(block);
+                }
+            }
+        }
+    }
+}
+
+/// 1回のパスの実行で変わった数。
+/// 前後の`IrCounts`の差なので、例えば`sets`は`[-]`以外から作られた`Set`も含む
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct PassStats {
+    pub name: &'static str,
+    pub before: IrCounts,
+    pub after: IrCounts,
+    /// 消えたループの数
+    pub loops_removed: usize,
+    /// 増えた`Set`, `Mul`, `If`, `Lick`の数
+    pub sets_added: usize,
+    pub muls_added: usize,
+    pub ifs_added: usize,
+    pub licks_added: usize,
+    /// 合体したり消えたりして減った命令の数
+    pub ops_removed: usize,
+    #[serde(rename = "elapsed_us", serialize_with = "serialize_micros")]
+    pub elapsed: Duration,
+}
+impl PassStats {
+    pub fn new(name: &'static str, before: IrCounts, after: IrCounts, elapsed: Duration) -> Self {
+        Self {
+            name,
+            before,
+            after,
+            loops_removed: before.loops.saturating_sub(after.loops),
+            sets_added: after.sets.saturating_sub(before.sets),
+            muls_added: after.muls.saturating_sub(before.muls),
+            ifs_added: after.ifs.saturating_sub(before.ifs),
+            licks_added: after.licks.saturating_sub(before.licks),
+            ops_removed: before.ops.saturating_sub(after.ops),
+            elapsed,
+        }
+    }
+}
+
+fn serialize_micros<S: serde::Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u128(duration.as_micros())
+}
+
+/// `Pipeline::run_with_stats`の結果
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct OptStats {
+    pub before: IrCounts,
+    pub after: IrCounts,
+    /// 実行した順。固定点まで繰り返した場合は同じパスが何度も現れる
+    pub passes: Vec<PassStats>,
+}
+
+impl fmt::Display for OptStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<20} {:>15} {:>13} {:>6} {:>6} {:>6} {:>6} {:>10}",
+            "pass", "ops", "loops", "+set", "+mul", "+if", "+lick", "time"
+        )?;
+        let arrow = |before: usize, after: usize| format!("{before} -> {after}");
+        for pass in &self.passes {
+            writeln!(
+                f,
+                "{:<20} {:>15} {:>13} {:>6} {:>6} {:>6} {:>6} {:>10}",
+                pass.name,
+                arrow(pass.before.ops, pass.after.ops),
+                arrow(pass.before.loops, pass.after.loops),
+                pass.sets_added,
+                pass.muls_added,
+                pass.ifs_added,
+                pass.licks_added,
+                format!("{:.1?}", pass.elapsed),
+            )?;
+        }
+        writeln!(
+            f,
+            "{:<20} {:>15} {:>13}",
+            "total",
+            arrow(self.before.ops, self.after.ops),
+            arrow(self.before.loops, self.after.loops),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        opt::{OptLevel, Pipeline},
+        utils::bf_to_block,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let block = bf_to_block("++[-]>+++[>++<-]>[<]").unwrap();
+        let (optimized, stats) = Pipeline::with_level(OptLevel::O2).run_with_stats(&block);
+
+        assert_eq!(stats.before, IrCounts::of(&block));
+        assert_eq!(stats.after, IrCounts::of(&optimized));
+        assert_eq!(stats.before.loops, 3);
+        assert_eq!(stats.after.loops, 0);
+        assert_eq!(stats.passes.len(), 10);
+
+        let pass = |name| stats.passes.iter().find(|pass| pass.name == name).unwrap();
+        assert_eq!(pass("clear").loops_removed, 1);
+        assert_eq!(pass("clear").sets_added, 1);
+        assert_eq!(pass("mul").muls_added, 1);
+        assert_eq!(pass("mul").ifs_added, 1);
+        assert_eq!(pass("lick").licks_added, 1);
+        assert!(pass("merge").ops_removed > 0);
+
+        let text = stats.to_string();
+        assert_eq!(text.lines().count(), 12);
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["passes"][2]["name"], "clear");
+        assert!(json["passes"][2]["elapsed_us"].is_u64());
+    }
+}
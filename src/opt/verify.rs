@@ -0,0 +1,125 @@
+//! 最適化後のIRが満たすべき性質を確かめる。
+//! デバッグビルドでは`Pipeline`がパスを実行するたびに確かめる。
+
+use thiserror::Error;
+
+use crate::{
+    ir::{Block, BlockItem, Op},
+    parse::Span,
+};
+
+/// パスを実行した後に成り立っているはずの性質。`Pass::update_invariants`で更新する
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Invariants {
+    /// 命令のoffsetが負でない（`Mul`の書き込み先は含めない）
+    pub non_negative_offset: bool,
+    /// `MovePtr`はLoop, If, Lickの直前か、ブロックの最後にしかない
+    pub folded_offsets: bool,
+    /// `Op::Lick`を含んでいても良い（インタプリタ向けのIR）
+    pub allow_lick: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum VerifyError {
+    #[error("{items} items but {spans} spans")]
+    SpanMismatch { items: usize, spans: usize },
+    #[error("negative offset in {op:?} (at {span})")]
+    NegativeOffset { op: Op, span: Span },
+    #[error("{op:?} in IR not for the interpreter (at {span})")]
+    UnexpectedLick { op: Op, span: Span },
+    #[error("Lick(0) never stops (at {span})")]
+    ZeroLick { span: Span },
+    #[error("MovePtr in the middle of a block (at {span})")]
+    UnfoldedMove { span: Span },
+}
+
+pub fn verify(block: &Block, invariants: Invariants) -> Result<(), VerifyError> {
+    if block.items.len() != block.spans.len() {
+        return Err(VerifyError::SpanMismatch {
+            items: block.items.len(),
+            spans: block.spans.len(),
+        });
+    }
+    let mut items = block.iter().peekable();
+    while let Some((item, span)) = items.next() {
+        let op = match item {
+            BlockItem::Loop(block) | BlockItem::If(block) => {
+                verify(block, invariants)?;
+                continue;
+            }
+            BlockItem::Op(op) => *op,
+        };
+        match op {
+            Op::Lick(_) if !invariants.allow_lick => {
+                return Err(VerifyError::UnexpectedLick { op, span })
+            }
+            Op::Lick(0) => return Err(VerifyError::ZeroLick { span }),
+            Op::MovePtr(_) if invariants.folded_offsets => {
+                let at_end = match items.peek() {
+                    None => true,
+                    Some((BlockItem::Op(next), _)) => matches!(next, Op::Lick(_)),
+                    Some(_) => true,
+                };
+                if !at_end {
+                    return Err(VerifyError::UnfoldedMove { span });
+                }
+            }
+            _ => {}
+        }
+        if invariants.non_negative_offset && op.offset().is_some_and(|offset| offset < 0) {
+            return Err(VerifyError::NegativeOffset { op, span });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::bf_to_block;
+
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let block = bf_to_block("+>+[-<]").unwrap();
+        assert_eq!(verify(&block, Invariants::default()), Ok(()));
+        assert_eq!(
+            verify(
+                &block,
+                Invariants {
+                    folded_offsets: true,
+                    ..Default::default()
+                }
+            ),
+            Err(VerifyError::UnfoldedMove {
+                span: Span::new(1, 2)
+            })
+        );
+
+        let mut block = Block::from_items(vec![
+            BlockItem::Op(Op::Add(1, -1)),
+            BlockItem::Op(Op::Lick(1)),
+        ]);
+        let invariants = Invariants {
+            non_negative_offset: true,
+            allow_lick: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            verify(&block, invariants),
+            Err(VerifyError::NegativeOffset { .. })
+        ));
+        block.items[0] = BlockItem::Op(Op::Add(1, 1));
+        assert_eq!(verify(&block, invariants), Ok(()));
+        assert!(matches!(
+            verify(&block, Invariants::default()),
+            Err(VerifyError::UnexpectedLick { .. })
+        ));
+
+        block.spans.pop();
+        assert_eq!(
+            verify(&block, invariants),
+            Err(VerifyError::SpanMismatch { items: 2, spans: 1 })
+        );
+    }
+}
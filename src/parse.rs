@@ -1,3 +1,5 @@
+use std::fmt;
+
 use chumsky::prelude::*;
 
 use crate::Error;
@@ -11,21 +13,162 @@ pub enum Ast {
     Dec,      // -
     Read,     // ,
     Write,    // .
-    Loop(Vec<Self>),
+    Loop(Vec<(Self, Span)>),
+}
+
+/// ソースコード上の範囲（バイト単位, `start..end`）
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize,
+)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+    /// 空の範囲は「ソースコード上の位置を持たない」ことを表す
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+    /// 両方を含む最小の範囲。空の範囲は無視する。
+    pub fn union(self, other: Self) -> Self {
+        if self.is_empty() {
+            other
+        } else if other.is_empty() {
+            self
+        } else {
+            Self::new(self.start.min(other.start), self.end.max(other.end))
+        }
+    }
+    /// 開始位置の1始まりの行番号と列番号（文字単位）
+    pub fn line_column(&self, source: &str) -> (usize, usize) {
+        line_column(source, self.start)
+    }
+}
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}..{}", self.start, self.end)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub enum SyntaxErrorKind {
+    /// 閉じられていない`[`
+    UnclosedBracket,
+    /// 対応する`[`がない`]`
+    UnexpectedCloseBracket,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct SyntaxError {
+    pub kind: SyntaxErrorKind,
+    pub span: Span,
+    /// 1始まりの行番号
+    pub line: usize,
+    /// 1始まりの列番号（文字単位）
+    pub column: usize,
+}
+impl SyntaxError {
+    fn new(kind: SyntaxErrorKind, source: &str, offset: usize) -> Self {
+        let (line, column) = line_column(source, offset);
+        Self {
+            kind,
+            span: Span::new(offset, offset + 1),
+            line,
+            column,
+        }
+    }
+    pub fn message(&self) -> &'static str {
+        match self.kind {
+            SyntaxErrorKind::UnclosedBracket => "unclosed '['",
+            SyntaxErrorKind::UnexpectedCloseBracket => "unexpected ']'",
+        }
+    }
+    /// エラー箇所をソースコードの抜粋つきで表示する
+    pub fn render(&self, source: &str) -> String {
+        let line_text = source.lines().nth(self.line - 1).unwrap_or_default();
+        let line_number = self.line.to_string();
+        let padding = " ".repeat(line_number.len());
+
+        format!(
+            "error: {}\n{padding}--> {}:{}\n{padding} |\n{line_number} | {line_text}\n{padding} | {}^\n",
+            self.message(),
+            self.line,
+            self.column,
+            " ".repeat(self.column - 1),
+        )
+    }
+}
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message())
+    }
+}
+
+/// 1始まりの行番号と列番号（文字単位）から、バイト単位の位置を求める。
+/// 列番号が行の長さを越えていれば、行末の位置を返す
+pub fn offset_of(source: &str, line: usize, column: usize) -> Option<usize> {
+    let line_start = if line == 1 {
+        0
+    } else {
+        source.match_indices('\n').nth(line.checked_sub(2)?)?.0 + 1
+    };
+    let line_text = source[line_start..].split('\n').next().unwrap_or_default();
+    let offset = line_text
+        .char_indices()
+        .nth(column.checked_sub(1)?)
+        .map_or(line_text.len(), |(i, _)| i);
+    Some(line_start + offset)
 }
 
-pub fn parse(code: &str) -> Result<Vec<Ast>, Error> {
+fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let column = before[line_start..].chars().count() + 1;
+    (line, column)
+}
+
+/// 対応の取れていない括弧をすべて探す
+fn unmatched_brackets(source: &str) -> Vec<SyntaxError> {
+    let mut errors = Vec::new();
+    let mut open_brackets = Vec::new();
+
+    for (offset, c) in source.char_indices() {
+        match c {
+            '[' => open_brackets.push(offset),
+            ']' if open_brackets.pop().is_none() => {
+                errors.push(SyntaxError::new(
+                    SyntaxErrorKind::UnexpectedCloseBracket,
+                    source,
+                    offset,
+                ));
+            }
+            _ => (),
+        }
+    }
+    errors.extend(
+        open_brackets
+            .into_iter()
+            .map(|offset| SyntaxError::new(SyntaxErrorKind::UnclosedBracket, source, offset)),
+    );
+    errors.sort_by_key(|error| error.span.start);
+
+    errors
+}
+
+pub fn parse(code: &str) -> Result<Vec<(Ast, Span)>, Error> {
     let ast = bf_parser()
         .parse(code)
         .into_result()
-        .map_err(|_| Error::InvalidSyntax {
-            msg: "The brackets are not corresponding.",
-        })?;
+        .map_err(|_| Error::InvalidSyntax(unmatched_brackets(code)))?;
 
     Ok(ast)
 }
 
-fn bf_parser<'a>() -> impl Parser<'a, &'a str, Vec<Ast>, extra::Err<EmptyErr>> {
+fn bf_parser<'a>() -> impl Parser<'a, &'a str, Vec<(Ast, Span)>, extra::Err<EmptyErr>> {
     use Ast::*;
 
     let bf_chars = "+-><.,[]";
@@ -41,10 +184,70 @@ fn bf_parser<'a>() -> impl Parser<'a, &'a str, Vec<Ast>, extra::Err<EmptyErr>> {
             just('.').to(Write),
             bf.delimited_by(just('['), just(']')).map(Loop),
         ))
+        .map_with_span(|ast, span: SimpleSpan| (ast, Span::new(span.start, span.end)))
         .padded_by(any().filter(is_other_char).repeated())
-        .recover_with(via_parser(nested_delimiters('[', ']', [], |_| _Invalid)))
+        .recover_with(via_parser(nested_delimiters(
+            '[',
+            ']',
+            [],
+            |span: SimpleSpan| (_Invalid, Span::new(span.start, span.end)),
+        )))
         .repeated()
         .collect()
     })
     .then_ignore(end())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(source: &str) -> Vec<(SyntaxErrorKind, usize, usize)> {
+        match parse(source) {
+            Err(Error::InvalidSyntax(errors)) => errors
+                .into_iter()
+                .map(|e| (e.kind, e.line, e.column))
+                .collect(),
+            _ => panic!("expected syntax error"),
+        }
+    }
+
+    #[test]
+    fn test_unmatched_brackets() {
+        use SyntaxErrorKind::*;
+
+        assert_eq!(errors("+[-"), [(UnclosedBracket, 1, 2)]);
+        assert_eq!(errors("+]-"), [(UnexpectedCloseBracket, 1, 2)]);
+        assert_eq!(
+            errors("[[\n]]]\nあ[+"),
+            [(UnexpectedCloseBracket, 2, 3), (UnclosedBracket, 3, 2)]
+        );
+        assert!(parse("[[]][]").is_ok());
+    }
+
+    #[test]
+    fn test_offset_of() {
+        let source = "++\nあ+[\n";
+        for offset in [0, 1, 3, 6, 7] {
+            let (line, column) = line_column(source, offset);
+            assert_eq!(offset_of(source, line, column), Some(offset));
+        }
+        // 行末を越えた列は行末に丸める
+        assert_eq!(offset_of(source, 1, 10), Some(2));
+        assert_eq!(offset_of(source, 4, 1), None);
+        assert_eq!(offset_of(source, 0, 1), None);
+    }
+
+    #[test]
+    fn test_render() {
+        let source = "++\n+[>+";
+        let Err(Error::InvalidSyntax(errors)) = parse(source) else {
+            panic!("expected syntax error")
+        };
+
+        assert_eq!(
+            errors[0].render(source),
+            "error: unclosed '['\n --> 2:2\n  |\n2 | +[>+\n  |  ^\n"
+        );
+    }
+}
@@ -0,0 +1,495 @@
+use std::fmt::Write;
+
+use crate::{
+    cell::{CellWidth, OverflowPolicy},
+    eof::EofBehavior,
+    ir::{Block, BlockItem, Op},
+};
+
+const PTR_NAME: &str = "p";
+const INDENT: &str = "    ";
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// テープの長さ（セル数）
+    pub memory_len: usize,
+    /// ポインタの初期位置。テープの左側の`origin`個のセルにも動ける
+    pub origin: usize,
+    pub cell_width: CellWidth,
+    /// ポインタがテープの外に出たら、メッセージを出して終了する
+    pub checked: bool,
+    /// `getchar()`がEOFを返したときの動作
+    pub eof: EofBehavior,
+    /// セルの値が収まらなくなったときの動作。`Trap`ならメッセージを出して終了する
+    pub overflow: OverflowPolicy,
+    /// 終了時に、ポインタの位置（1行）とテープの中身を標準エラー出力に書き出す。
+    /// 他のバックエンドと結果を比べるのに使う
+    pub dump_memory: bool,
+}
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            memory_len: 30000,
+            origin: 0,
+            cell_width: CellWidth::default(),
+            checked: false,
+            eof: EofBehavior::default(),
+            overflow: OverflowPolicy::default(),
+            dump_memory: false,
+        }
+    }
+}
+
+struct Emitter<'a> {
+    config: &'a Config,
+    code: String,
+    depth: usize,
+}
+impl Emitter<'_> {
+    fn line(&mut self, line: impl AsRef<str>) {
+        for _ in 0..self.depth {
+            self.code.push_str(INDENT);
+        }
+        self.code.push_str(line.as_ref());
+        self.code.push('\n');
+    }
+
+    /// `offset`の位置のセルを指す式
+    fn cell(&self, offset: i32) -> String {
+        // checkedの場合、ポインタ自体は常にテープ内にあるので`p[0]`は確認しなくて良い
+        if self.config.checked && offset != 0 {
+            format!("*bf_at({PTR_NAME}, {offset})")
+        } else {
+            format!("{PTR_NAME}[{offset}]")
+        }
+    }
+
+    /// セルに入る最大の値
+    fn max(&self) -> i64 {
+        self.config.cell_width.max().min(i64::MAX as u64) as i64
+    }
+
+    fn move_ptr(&mut self, x: i32) {
+        if self.config.checked {
+            self.line(format!("{PTR_NAME} = bf_at({PTR_NAME}, {x});"));
+        } else if x < 0 {
+            self.line(format!("{PTR_NAME} -= {};", x.unsigned_abs()));
+        } else {
+            self.line(format!("{PTR_NAME} += {x};"));
+        }
+    }
+
+    fn block(&mut self, block: &Block) {
+        for item in &block.items {
+            match item {
+                BlockItem::Loop(loop_block) => {
+                    self.line(format!("while ({}) {{", self.cell(0)));
+                    self.nested(loop_block);
+                }
+                BlockItem::If(if_block) => {
+                    self.line(format!("if ({}) {{", self.cell(0)));
+                    self.nested(if_block);
+                }
+                BlockItem::Op(op) => self.op(*op),
+            }
+        }
+    }
+    fn nested(&mut self, block: &Block) {
+        self.depth += 1;
+        self.block(block);
+        self.depth -= 1;
+        self.line("}");
+    }
+
+    fn op(&mut self, op: Op) {
+        // 符号なし整数として計算すれば、どのセル幅でもwrapしてくれる
+        let checked = self.config.overflow != OverflowPolicy::Wrap;
+        match op {
+            Op::Add(x, offset) if checked => {
+                let f = if x < 0 { "bf_sub" } else { "bf_add" };
+                self.line(format!(
+                    "{f}(&{}, {}u);",
+                    self.cell(offset),
+                    x.unsigned_abs()
+                ))
+            }
+            Op::Add(x, offset) if x < 0 => {
+                self.line(format!("{} -= {}u;", self.cell(offset), x.unsigned_abs()))
+            }
+            Op::Add(x, offset) => self.line(format!("{} += {x}u;", self.cell(offset))),
+            Op::MovePtr(x) => self.move_ptr(x),
+            Op::Mul(to, x, offset) if checked => {
+                let to = self.cell(offset + to);
+                let from = self.cell(offset);
+                let f = if x < 0 { "bf_sub" } else { "bf_add" };
+                self.line(format!(
+                    "{f}(&{to}, bf_mul(&{to}, {from}, {}u));",
+                    x.unsigned_abs()
+                ));
+            }
+            Op::Mul(to, x, offset) => {
+                let to = self.cell(offset + to);
+                let from = self.cell(offset);
+                match x {
+                    1 => self.line(format!("{to} += {from};")),
+                    -1 => self.line(format!("{to} -= {from};")),
+                    x if x < 0 => self.line(format!("{to} -= {from} * {}u;", x.unsigned_abs())),
+                    x => self.line(format!("{to} += {from} * {x}u;")),
+                }
+            }
+            Op::Set(x, offset) if checked && !(0..=self.max()).contains(&(x as i64)) => {
+                let cell = self.cell(offset);
+                match self.config.overflow {
+                    OverflowPolicy::Trap => self.line(format!("bf_overflow(&{cell});")),
+                    _ if x < 0 => self.line(format!("{cell} = 0;")),
+                    _ => self.line(format!("{cell} = CELL_MAX;")),
+                }
+            }
+            Op::Set(x, offset) => self.line(format!("{} = {x};", self.cell(offset))),
+            Op::Out(offset) => {
+                if self.config.cell_width == CellWidth::W8 {
+                    self.line(format!("putchar({});", self.cell(offset)))
+                } else {
+                    self.line(format!("putchar((unsigned char){});", self.cell(offset)))
+                }
+            }
+            Op::Input(offset) => {
+                let cell = self.cell(offset);
+                self.line(format!("{cell} = bf_input({cell});"));
+            }
+            // 8bitで1つずつ右に進む場合は、memchrで0を探せる
+            Op::Lick(1) if self.config.cell_width == CellWidth::W8 => {
+                self.line(format!(
+                    "{PTR_NAME} = memchr({PTR_NAME}, 0, mem + MEMORY_LEN - {PTR_NAME});"
+                ));
+                if self.config.checked {
+                    self.line(format!("if (!{PTR_NAME}) bf_out_of_range(MEMORY_LEN);"));
+                }
+            }
+            Op::Lick(x) => {
+                self.line(format!("while ({}) {{", self.cell(0)));
+                self.depth += 1;
+                self.move_ptr(x);
+                self.depth -= 1;
+                self.line("}");
+            }
+        }
+    }
+}
+
+pub fn block_to_c(block: &Block, config: &Config) -> String {
+    let mut c_code = String::new();
+
+    let bits = config.cell_width.bits();
+    let memory_len = config.memory_len;
+    let origin = config.origin;
+    writeln!(
+        c_code,
+        "#include <stddef.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#define MEMORY_LEN {memory_len}
+#define ORIGIN {origin}
+
+#if ORIGIN >= MEMORY_LEN
+#error \"ORIGIN must be less than MEMORY_LEN\"
+#endif
+
+typedef uint{bits}_t cell;
+
+static cell mem[MEMORY_LEN];
+
+static inline void bf_out_of_range(ptrdiff_t index) {{
+    fprintf(stderr, \"error: pointer is out of range: %td\\n\", index - ORIGIN);
+    exit(1);
+}}
+
+static inline cell *bf_at(cell *q, ptrdiff_t offset) {{
+    ptrdiff_t index = q - mem + offset;
+    if (index < 0 || index >= MEMORY_LEN) {{
+        bf_out_of_range(index);
+    }}
+    return mem + index;
+}}
+
+#define CELL_MAX ((unsigned long long)(cell)-1)
+"
+    )
+    .unwrap();
+    if config.overflow != OverflowPolicy::Wrap {
+        let (on_max, on_min, on_mul) = match config.overflow {
+            OverflowPolicy::Trap => ("bf_overflow(c);", "bf_overflow(c);", "bf_overflow(c);"),
+            _ => ("*c = CELL_MAX;", "*c = 0;", "(void)c;"),
+        };
+        writeln!(
+            c_code,
+            "static inline void bf_overflow(cell *c) {{
+    fprintf(stderr, \"error: cell overflow at %td\\n\", c - mem - ORIGIN);
+    exit(1);
+}}
+
+static inline void bf_add(cell *c, unsigned long long x) {{
+    if (x > CELL_MAX - *c) {{
+        {on_max}
+    }} else {{
+        *c += x;
+    }}
+}}
+
+static inline void bf_sub(cell *c, unsigned long long x) {{
+    if (x > *c) {{
+        {on_min}
+    }} else {{
+        *c -= x;
+    }}
+}}
+
+/* from * x。セル幅に収まらなければ、足しても引いてもcに収まらない */
+static inline unsigned long long bf_mul(cell *c, cell from, unsigned long long x) {{
+    if (from != 0 && x > CELL_MAX / from) {{
+        {on_mul}
+        return CELL_MAX;
+    }}
+    return (unsigned long long)from * x;
+}}
+"
+        )
+        .unwrap();
+    }
+    writeln!(
+        c_code,
+        "static inline cell bf_input(cell old) {{
+    int c = getchar();
+    if (c == EOF) {{"
+    )
+    .unwrap();
+    let on_eof = match config.eof {
+        EofBehavior::Unchanged => "return old;",
+        EofBehavior::Zero => "return 0;",
+        EofBehavior::MinusOne => "return (cell)-1;",
+        EofBehavior::Error => "fputs(\"error: unexpected EOF\\n\", stderr);\n        exit(1);",
+    };
+    writeln!(
+        c_code,
+        "        {on_eof}
+    }}
+    (void)old;
+    return (cell)c;
+}}
+
+int main(void) {{
+    cell *{PTR_NAME} = mem + ORIGIN;"
+    )
+    .unwrap();
+
+    let mut emitter = Emitter {
+        config,
+        code: c_code,
+        depth: 1,
+    };
+    emitter.block(block);
+    if config.dump_memory {
+        emitter.line(format!(
+            "fprintf(stderr, \"%td\\n\", {PTR_NAME} - mem - ORIGIN);"
+        ));
+        emitter.line("fwrite(mem, sizeof(cell), MEMORY_LEN, stderr);");
+    }
+    emitter.line("return 0;");
+
+    let mut c_code = emitter.code;
+    c_code.push_str("}\n");
+    c_code
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        io::Write,
+        process::{Command, Stdio},
+    };
+
+    use crate::{
+        opt::{optimize, optimize_for_interpreter},
+        utils::bf_to_block,
+    };
+
+    use super::*;
+
+    fn has_cc() -> bool {
+        Command::new("cc").arg("--version").output().is_ok()
+    }
+
+    /// Cコンパイラでコンパイルして実行し、(終了コード, 標準出力, 標準エラー出力)を返す
+    fn run_c(name: &str, source: &str, config: &Config, input: &[u8]) -> (i32, Vec<u8>, Vec<u8>) {
+        let mut block = optimize(&bf_to_block(source).unwrap(), true, false);
+        optimize_for_interpreter(&mut block);
+        let c_code = block_to_c(&block, config);
+
+        let dir = std::env::temp_dir();
+        let c_path = dir.join(format!("bf_test_{}_{name}.c", std::process::id()));
+        let exe_path = c_path.with_extension("out");
+        fs::write(&c_path, c_code).unwrap();
+
+        let status = Command::new("cc")
+            .args(["-O1", "-Wall", "-Werror", "-o"])
+            .arg(&exe_path)
+            .arg(&c_path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let mut child = Command::new(&exe_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        let output = child.wait_with_output().unwrap();
+
+        fs::remove_file(&c_path).unwrap();
+        fs::remove_file(&exe_path).unwrap();
+
+        (output.status.code().unwrap(), output.stdout, output.stderr)
+    }
+
+    #[test]
+    fn test_c() {
+        if !has_cc() {
+            return;
+        }
+
+        let hello_world_code = include_str!("../../bf_codes/hello_world.bf");
+        let hello_world = include_str!("../../bf_codes/hello_world.out");
+
+        for checked in [false, true] {
+            let config = Config {
+                checked,
+                ..Default::default()
+            };
+            let (code, output, _) = run_c("hello_world", hello_world_code, &config, b"");
+            assert_eq!(code, 0);
+            assert_eq!(output, hello_world.as_bytes());
+
+            // Lick
+            let (code, output, _) = run_c("lick", ">+>+>+>>+<<<<[>]<[<]>.", &config, b"");
+            assert_eq!(code, 0);
+            assert_eq!(output, [1]);
+        }
+    }
+
+    #[test]
+    fn test_c_eof() {
+        if !has_cc() {
+            return;
+        }
+
+        let source = "+,.";
+        for (eof, expected_code, expected_output) in [
+            (EofBehavior::Unchanged, 0, &[1][..]),
+            (EofBehavior::Zero, 0, &[0]),
+            (EofBehavior::MinusOne, 0, &[255]),
+            (EofBehavior::Error, 1, &[]),
+        ] {
+            let config = Config {
+                eof,
+                ..Default::default()
+            };
+            let (code, output, _) = run_c("eof", source, &config, b"");
+            assert_eq!(code, expected_code);
+            assert_eq!(output, expected_output);
+        }
+    }
+
+    #[test]
+    fn test_c_overflow() {
+        if !has_cc() {
+            return;
+        }
+
+        let add = "-.";
+        let mul = format!("{}[->+++<]>.", "+".repeat(100));
+        let set = "+[-]-.";
+        for (overflow, expected) in [
+            (OverflowPolicy::Wrap, [Some(255), Some(44), Some(255)]),
+            (OverflowPolicy::Saturate, [Some(0), Some(255), Some(0)]),
+            (OverflowPolicy::Trap, [None, None, None]),
+        ] {
+            let config = Config {
+                overflow,
+                ..Default::default()
+            };
+            for (source, expected) in [add, &mul, set].into_iter().zip(expected) {
+                let (code, output, stderr) = run_c("overflow", source, &config, b"");
+                match expected {
+                    Some(cell) => {
+                        assert_eq!(code, 0);
+                        assert_eq!(output, [cell]);
+                    }
+                    None => {
+                        assert_eq!(code, 1);
+                        assert!(String::from_utf8_lossy(&stderr).contains("cell overflow"));
+                    }
+                }
+            }
+        }
+
+        // 16bitなら収まる
+        let config = Config {
+            cell_width: CellWidth::W16,
+            overflow: OverflowPolicy::Trap,
+            ..Default::default()
+        };
+        let (code, output, _) = run_c("overflow16", &mul, &config, b"");
+        assert_eq!(code, 0);
+        assert_eq!(output, [44]);
+    }
+
+    #[test]
+    fn test_c_checked() {
+        if !has_cc() {
+            return;
+        }
+
+        let config = Config {
+            memory_len: 10,
+            checked: true,
+            ..Default::default()
+        };
+        let (code, _, stderr) = run_c("checked", "+[<+]", &config, b"");
+        assert_eq!(code, 1);
+        assert_eq!(stderr, b"error: pointer is out of range: -1\n");
+
+        // すべてのセルが0でなければ、memchrは0を見つけられない
+        let source = format!("{}+{}[>]", "+>".repeat(9), "<".repeat(9));
+        let (code, _, stderr) = run_c("checked_lick", &source, &config, b"");
+        assert_eq!(code, 1);
+        assert_eq!(stderr, b"error: pointer is out of range: 10\n");
+    }
+
+    #[test]
+    fn test_c_origin() {
+        if !has_cc() {
+            return;
+        }
+
+        let config = Config {
+            memory_len: 10,
+            origin: 5,
+            checked: true,
+            ..Default::default()
+        };
+        let (code, output, _) = run_c("origin", "+++[<<++>>-]<<.", &config, b"");
+        assert_eq!(code, 0);
+        assert_eq!(output, [6]);
+
+        // 範囲外の位置は初期位置からの相対位置で表示する
+        let (code, _, stderr) = run_c("origin_checked", "+[<+]", &config, b"");
+        assert_eq!(code, 1);
+        assert_eq!(stderr, b"error: pointer is out of range: -6\n");
+    }
+}
@@ -0,0 +1,166 @@
+//! x86-64 Linux向けの静的なELF実行ファイルを出力する。
+//!
+//! 2つのPT_LOADからなる。
+//! - コード: ELFヘッダとプログラムヘッダも含めて、ファイル全体をそのままR+Xで読み込む
+//! - テープ: ファイル上の中身を持たない（bss）R+Wの領域。カーネルが0で埋めてくれる
+//!
+//! テープは最も高いアドレスに置く。古いカーネルはbssを最後のPT_LOADにしか用意しないため。
+
+use std::io;
+
+use crate::{cell::CellWidth, ir::Block};
+
+use super::x86_64::{Codegen, IoMode};
+
+const CODE_ADDRESS: u64 = 0x40_0000;
+const TAPE_ADDRESS: u64 = 0x1_0000_0000;
+const PAGE_SIZE: u64 = 0x1000;
+
+const ELF_HEADER_SIZE: u16 = 64;
+const PROGRAM_HEADER_SIZE: u16 = 56;
+const PROGRAM_HEADER_COUNT: u16 = 2;
+const HEADERS_SIZE: u64 =
+    ELF_HEADER_SIZE as u64 + (PROGRAM_HEADER_SIZE * PROGRAM_HEADER_COUNT) as u64;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+pub fn block_to_elf(
+    block: &Block,
+    memory_len: usize,
+    cell_width: CellWidth,
+    mut out: impl io::Write,
+) -> io::Result<()> {
+    if memory_len == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "memory_len must be positive",
+        ));
+    }
+
+    let cell_bytes = cell_width.bytes() as u64;
+    let tape_begin = TAPE_ADDRESS;
+    let tape_end = tape_begin + memory_len as u64 * cell_bytes;
+    let tape_size = (tape_end - TAPE_ADDRESS).next_multiple_of(PAGE_SIZE);
+
+    let code =
+        Codegen::new(cell_width, IoMode::Syscall).compile_program(block, tape_begin, tape_end)?;
+    let file_size = HEADERS_SIZE + code.len() as u64;
+
+    let mut elf = Vec::with_capacity(file_size as usize);
+    elf_header(&mut elf, CODE_ADDRESS + HEADERS_SIZE);
+    program_header(&mut elf, PF_R | PF_X, CODE_ADDRESS, file_size, file_size);
+    program_header(&mut elf, PF_R | PF_W, TAPE_ADDRESS, 0, tape_size);
+    debug_assert_eq!(elf.len() as u64, HEADERS_SIZE);
+    elf.extend_from_slice(&code);
+
+    out.write_all(&elf)
+}
+
+fn elf_header(elf: &mut Vec<u8>, entry: u64) {
+    // e_ident: マジックナンバー, 64bit, リトルエンディアン, バージョン1, System V ABI
+    elf.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    elf.extend_from_slice(&[0; 8]);
+    // e_type: ET_EXEC
+    elf.extend_from_slice(&2u16.to_le_bytes());
+    // e_machine: EM_X86_64
+    elf.extend_from_slice(&62u16.to_le_bytes());
+    // e_version
+    elf.extend_from_slice(&1u32.to_le_bytes());
+    // e_entry
+    elf.extend_from_slice(&entry.to_le_bytes());
+    // e_phoff: プログラムヘッダはELFヘッダの直後
+    elf.extend_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes());
+    // e_shoff: セクションヘッダはなし
+    elf.extend_from_slice(&0u64.to_le_bytes());
+    // e_flags
+    elf.extend_from_slice(&0u32.to_le_bytes());
+    // e_ehsize, e_phentsize, e_phnum
+    elf.extend_from_slice(&ELF_HEADER_SIZE.to_le_bytes());
+    elf.extend_from_slice(&PROGRAM_HEADER_SIZE.to_le_bytes());
+    elf.extend_from_slice(&PROGRAM_HEADER_COUNT.to_le_bytes());
+    // e_shentsize, e_shnum, e_shstrndx
+    elf.extend_from_slice(&[0; 6]);
+}
+
+/// ファイル先頭から`file_size`byteを、`address`から`memory_size`byteの領域に読み込むPT_LOAD
+fn program_header(elf: &mut Vec<u8>, flags: u32, address: u64, file_size: u64, memory_size: u64) {
+    elf.extend_from_slice(&PT_LOAD.to_le_bytes());
+    elf.extend_from_slice(&flags.to_le_bytes());
+    // p_offset
+    elf.extend_from_slice(&0u64.to_le_bytes());
+    // p_vaddr, p_paddr
+    elf.extend_from_slice(&address.to_le_bytes());
+    elf.extend_from_slice(&address.to_le_bytes());
+    elf.extend_from_slice(&file_size.to_le_bytes());
+    elf.extend_from_slice(&memory_size.to_le_bytes());
+    // p_align
+    elf.extend_from_slice(&PAGE_SIZE.to_le_bytes());
+}
+
+#[cfg(all(test, target_arch = "x86_64", target_os = "linux"))]
+mod test {
+    use std::{
+        fs,
+        io::Write,
+        os::unix::fs::PermissionsExt,
+        process::{Command, Output, Stdio},
+    };
+
+    use crate::{opt::optimize, utils::bf_to_block};
+
+    use super::*;
+
+    fn run_elf(name: &str, source: &str, cell_width: CellWidth, input: &[u8]) -> Output {
+        let block = optimize(&bf_to_block(source).unwrap(), true, false);
+
+        let path = std::env::temp_dir().join(format!("bf_test_{}_{name}", std::process::id()));
+        let mut elf = Vec::new();
+        block_to_elf(&block, 100, cell_width, &mut elf).unwrap();
+        fs::write(&path, elf).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut child = Command::new(&path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        let output = child.wait_with_output().unwrap();
+        fs::remove_file(&path).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_hello_world_elf() {
+        let hello_world_code = include_str!("../../bf_codes/hello_world.bf");
+        let hello_world = include_str!("../../bf_codes/hello_world.out");
+
+        let output = run_elf("hello_world", hello_world_code, CellWidth::W8, b"");
+
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), hello_world);
+    }
+
+    #[test]
+    fn test_io_elf() {
+        let output = run_elf("echo", ",[.,]", CellWidth::W16, b"hello\0");
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"hello");
+
+        // EOFはエラー
+        let output = run_elf("eof", ",,", CellWidth::W8, b"a");
+        assert_eq!(output.status.code(), Some(1));
+        assert_eq!(output.stderr, b"error: failed to read or write\n");
+    }
+
+    #[test]
+    fn test_pointer_out_of_range_elf() {
+        let output = run_elf("out_of_range", "+[<+]", CellWidth::W8, b"");
+        assert_eq!(output.status.code(), Some(1));
+        assert_eq!(output.stderr, b"error: pointer is out of range\n");
+    }
+}
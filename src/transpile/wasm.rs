@@ -8,155 +8,354 @@ use wasm_binary::{
     Function, Import, Memory, ModuleBuilder,
 };
 
-use crate::ir::{Block, BlockItem, Op};
+use crate::{
+    cell::CellWidth,
+    eof::EofBehavior,
+    ir::{Block, BlockItem, Op},
+};
 
 use self::wasm_binary::type_::{FuncSignature, ValueType};
 
-fn op_to_wop(op: Op, wops: &mut Vec<WOp>) {
-    if let Some(offset) = op.offset() {
-        if offset.is_negative() {
-            panic!();
+const WASI_MODULE: &str = "wasi_snapshot_preview1";
+/// importする関数の(名前, 引数の数, 返り値があるか)。引数と返り値はすべてi32
+const IMPORTS: [(&str, usize, bool); 3] = [
+    ("fd_write", 4, true),
+    ("fd_read", 4, true),
+    ("proc_exit", 1, false),
+];
+
+// 関数のインデックス（0..3はimportした関数）
+const FD_WRITE: u32 = 0;
+const FD_READ: u32 = 1;
+const PROC_EXIT: u32 = 2;
+const FLUSH: u32 = 3;
+const PRINT_CHAR: u32 = 4;
+const INPUT_CHAR: u32 = 5;
+const ERROR: u32 = 6;
+const CHECK_POINTER: u32 = 7;
+
+const PAGE_SIZE: u64 = 65536;
+
+// 0..20はI/Oで使う。その後ろにエラーメッセージ、出力バッファを置く。
+/// fd_write, fd_readに渡すiovec（buf, len）
+const IOV_ADDRESS: u32 = 0;
+/// fd_write, fd_readが書き込んだbyte数
+const NBYTES_ADDRESS: u32 = 8;
+/// 出力バッファに溜まっているbyte数
+const OUTPUT_LEN_ADDRESS: u32 = 12;
+/// 読み込んだ1byte
+const INPUT_ADDRESS: u32 = 16;
+const MESSAGE_ADDRESS: u32 = 20;
+const OUTPUT_BUFFER_SIZE: u32 = 4096;
+
+#[derive(Debug, Clone, Copy)]
+enum Message {
+    OutOfRange,
+    OutOfMemory,
+    UnexpectedEof,
+    Io,
+}
+impl Message {
+    const ALL: [Message; 4] = [
+        Message::OutOfRange,
+        Message::OutOfMemory,
+        Message::UnexpectedEof,
+        Message::Io,
+    ];
+
+    fn text(self) -> &'static [u8] {
+        match self {
+            Message::OutOfRange => b"error: pointer is out of range\n",
+            Message::OutOfMemory => b"error: out of memory\n",
+            Message::UnexpectedEof => b"error: unexpected EOF\n",
+            Message::Io => b"error: failed to read or write\n",
         }
     }
-    match op {
-        Op::Add(value, offset) => {
-            let add_ops = [
-                WOp::GetLocal { local_index: 0 },
-                WOp::GetLocal { local_index: 0 },
-                WOp::I32Load8U(MemoryImmediate::i8(offset as u32)),
-                WOp::I32Const(value),
-                WOp::I32Add,
-                WOp::I32Store8(MemoryImmediate::i8(offset as u32)),
-            ];
 
-            wops.extend(add_ops);
+    fn address(self) -> u32 {
+        let before = &Self::ALL[..self as usize];
+        MESSAGE_ADDRESS + before.iter().map(|m| m.text().len() as u32).sum::<u32>()
+    }
+
+    fn all_texts() -> Vec<u8> {
+        Self::ALL.iter().flat_map(|m| m.text()).copied().collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// テープの長さ（セル数）。初期のページ数はこれで決まる
+    pub memory_len: usize,
+    /// ポインタの初期位置。テープの左側の`origin`個のセルにも動ける
+    pub origin: usize,
+    pub cell_width: CellWidth,
+    /// ポインタがメモリの末尾を越えたら、`memory.grow`で拡張する
+    pub growable: bool,
+    /// ポインタがテープの外に出たら、メッセージを出して終了する
+    pub checked: bool,
+    /// `fd_read`がEOF（`nread == 0`）を返したときの動作
+    pub eof: EofBehavior,
+}
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            memory_len: 30000,
+            origin: 0,
+            cell_width: CellWidth::default(),
+            growable: false,
+            checked: false,
+            eof: EofBehavior::default(),
         }
-        Op::MovePtr(offset) => {
-            let ptr_add_ops = [
-                WOp::GetLocal { local_index: 0 },
-                WOp::I32Const(offset),
-                WOp::I32Add,
-                WOp::SetLocal { local_index: 0 },
-            ];
+    }
+}
 
-            wops.extend(ptr_add_ops);
+/// 線形メモリの配置（byte単位）
+///
+/// `[I/O用の領域][メッセージ][出力バッファ][余白][テープ][余白]`
+struct Layout {
+    output_buffer: u32,
+    tape_begin: u32,
+    tape_end: u32,
+    /// ポインタの初期値
+    start: u32,
+    /// offset付きのアクセスのために、テープの両端に取る余白
+    margin: u32,
+    pages: u32,
+}
+impl Layout {
+    fn new(block: &Block, config: &Config) -> io::Result<Self> {
+        let bytes = config.cell_width.bytes() as u64;
+        let margin = block.max_offset() as u64 * bytes;
+        let output_buffer =
+            (MESSAGE_ADDRESS as u64 + Message::all_texts().len() as u64).next_multiple_of(8);
+        let header_end = output_buffer + OUTPUT_BUFFER_SIZE as u64;
+        // 64bitセルでも揃うように8byte境界から始める
+        let tape_begin = (header_end + margin).next_multiple_of(8);
+        let tape_end = tape_begin + config.memory_len as u64 * bytes;
+        let pages = (tape_end + margin).div_ceil(PAGE_SIZE).max(1);
+
+        if config.origin > 0 && config.origin >= config.memory_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "origin must be less than memory_len",
+            ));
         }
+        // wasm32のメモリは最大4GiB
+        if tape_end + margin > u32::MAX as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "memory_len is too large",
+            ));
+        }
+
+        Ok(Self {
+            output_buffer: output_buffer as u32,
+            tape_begin: tape_begin as u32,
+            tape_end: tape_end as u32,
+            start: (tape_begin + config.origin as u64 * bytes) as u32,
+            margin: margin as u32,
+            pages: pages as u32,
+        })
+    }
+}
+
+fn load(cell_width: CellWidth, offset: u32) -> WOp {
+    match cell_width {
+        CellWidth::W8 => WOp::I32Load8U(MemoryImmediate::i8(offset)),
+        CellWidth::W16 => WOp::I32Load16U(MemoryImmediate::i16(offset)),
+        CellWidth::W32 => WOp::I32Load(MemoryImmediate::i32(offset)),
+        CellWidth::W64 => WOp::I64Load(MemoryImmediate::i64(offset)),
+    }
+}
+
+fn store(cell_width: CellWidth, offset: u32) -> WOp {
+    match cell_width {
+        CellWidth::W8 => WOp::I32Store8(MemoryImmediate::i8(offset)),
+        CellWidth::W16 => WOp::I32Store16(MemoryImmediate::i16(offset)),
+        CellWidth::W32 => WOp::I32Store(MemoryImmediate::i32(offset)),
+        CellWidth::W64 => WOp::I64Store(MemoryImmediate::i64(offset)),
+    }
+}
+
+// 64bitセルだけi64で計算する。それ以外はi32で計算してstoreで切り詰める。
+fn cell_const(cell_width: CellWidth, value: i32) -> WOp {
+    match cell_width {
+        CellWidth::W64 => WOp::I64Const(value as i64),
+        _ => WOp::I32Const(value),
+    }
+}
+
+fn cell_add(cell_width: CellWidth) -> WOp {
+    match cell_width {
+        CellWidth::W64 => WOp::I64Add,
+        _ => WOp::I32Add,
+    }
+}
+
+fn cell_sub(cell_width: CellWidth) -> WOp {
+    match cell_width {
+        CellWidth::W64 => WOp::I64Sub,
+        _ => WOp::I32Sub,
+    }
+}
+
+fn cell_mul(cell_width: CellWidth) -> WOp {
+    match cell_width {
+        CellWidth::W64 => WOp::I64Mul,
+        _ => WOp::I32Mul,
+    }
+}
+
+/// ポインタが指すセルを読み、0でなければ非0のi32を積む。
+fn load_condition(cell_width: CellWidth, wops: &mut Vec<WOp>) {
+    wops.extend([WOp::GetLocal { local_index: 0 }, load(cell_width, 0)]);
+    if cell_width == CellWidth::W64 {
+        wops.extend([WOp::I64Eqz, WOp::I32Eqz]);
+    }
+}
+
+/// `offset`個先のセルのアドレスを積み、load/storeに渡すoffsetを返す。
+/// load/storeのoffsetは符号なしなので、負の場合はアドレスを計算する。
+fn push_address(offset: i32, cell_width: CellWidth, wops: &mut Vec<WOp>) -> u32 {
+    let offset = offset * cell_width.bytes() as i32;
+
+    wops.push(WOp::GetLocal { local_index: 0 });
+    if offset.is_negative() {
+        wops.extend([WOp::I32Const(offset), WOp::I32Add]);
+        0
+    } else {
+        offset as u32
+    }
+}
+
+fn move_ptr(offset: i32, config: &Config, wops: &mut Vec<WOp>) {
+    let ptr_add_ops = [
+        WOp::GetLocal { local_index: 0 },
+        WOp::I32Const(offset * config.cell_width.bytes() as i32),
+        WOp::I32Add,
+        WOp::SetLocal { local_index: 0 },
+    ];
+
+    wops.extend(ptr_add_ops);
+
+    // 拡張するだけなら、左に動いたときは確認しなくて良い
+    if config.checked || (config.growable && offset > 0) {
+        wops.extend([
+            WOp::GetLocal { local_index: 0 },
+            WOp::Call {
+                function_index: CHECK_POINTER,
+            },
+        ]);
+    }
+}
+
+fn op_to_wop(op: Op, config: &Config, wops: &mut Vec<WOp>) {
+    let cell_width = config.cell_width;
+
+    match op {
+        Op::Add(value, offset) => {
+            let store_offset = push_address(offset, cell_width, wops);
+            let load_offset = push_address(offset, cell_width, wops);
+            wops.extend([
+                load(cell_width, load_offset),
+                cell_const(cell_width, value),
+                cell_add(cell_width),
+                store(cell_width, store_offset),
+            ]);
+        }
+        Op::MovePtr(offset) => move_ptr(offset, config, wops),
         Op::Mul(x, y, offset) => {
+            let store_offset = push_address(offset + x, cell_width, wops);
+            let to_offset = push_address(offset + x, cell_width, wops);
+            wops.push(load(cell_width, to_offset));
+            let from_offset = push_address(offset, cell_width, wops);
+            wops.push(load(cell_width, from_offset));
+
             if y == 1 {
-                let mul_ops = [
-                    WOp::GetLocal { local_index: 0 },
-                    WOp::I32Const(x),
-                    WOp::I32Add,
-                    WOp::TeeLocal { local_index: 1 },
-                    WOp::GetLocal { local_index: 1 },
-                    WOp::I32Load8U(MemoryImmediate::i8(offset as u32)),
-                    WOp::GetLocal { local_index: 0 },
-                    WOp::I32Load8U(MemoryImmediate::i8(offset as u32)),
-                    WOp::I32Add,
-                    WOp::I32Store8(MemoryImmediate::i8(offset as u32)),
-                ];
-                wops.extend(mul_ops);
+                wops.push(cell_add(cell_width));
             } else if y == -1 {
-                let mul_ops = [
-                    WOp::GetLocal { local_index: 0 },
-                    WOp::I32Const(x),
-                    WOp::I32Add,
-                    WOp::TeeLocal { local_index: 1 },
-                    WOp::GetLocal { local_index: 1 },
-                    WOp::I32Load8U(MemoryImmediate::i8(offset as u32)),
-                    WOp::GetLocal { local_index: 0 },
-                    WOp::I32Load8U(MemoryImmediate::i8(offset as u32)),
-                    WOp::I32Sub,
-                    WOp::I32Store8(MemoryImmediate::i8(offset as u32)),
-                ];
-                wops.extend(mul_ops);
+                wops.push(cell_sub(cell_width));
             } else {
-                let mul_ops = [
-                    WOp::GetLocal { local_index: 0 },
-                    WOp::I32Const(x),
-                    WOp::I32Add,
-                    WOp::TeeLocal { local_index: 1 },
-                    WOp::GetLocal { local_index: 1 },
-                    WOp::I32Load8U(MemoryImmediate::i8(offset as u32)),
-                    WOp::GetLocal { local_index: 0 },
-                    WOp::I32Load8U(MemoryImmediate::i8(offset as u32)),
-                    WOp::I32Const(y),
-                    WOp::I32Mul,
-                    WOp::I32Add,
-                    WOp::I32Store8(MemoryImmediate::i8(offset as u32)),
-                ];
-                wops.extend(mul_ops);
+                wops.extend([
+                    cell_const(cell_width, y),
+                    cell_mul(cell_width),
+                    cell_add(cell_width),
+                ]);
             }
+            wops.push(store(cell_width, store_offset));
         }
         Op::Set(value, offset) => {
-            let clear_ops = [
-                WOp::GetLocal { local_index: 0 },
-                WOp::I32Const(value),
-                WOp::I32Store8(MemoryImmediate::i8(offset as u32)),
-            ];
-
-            wops.extend(clear_ops);
+            let offset = push_address(offset, cell_width, wops);
+            wops.extend([cell_const(cell_width, value), store(cell_width, offset)]);
         }
         Op::Out(offset) => {
-            let out_ops = [
-                WOp::GetLocal { local_index: 0 },
-                WOp::I32Load8U(MemoryImmediate::i8(offset as u32)),
-                WOp::Call { function_index: 2 },
-            ];
-
-            wops.extend(out_ops);
+            let offset = push_address(offset, cell_width, wops);
+            wops.push(load(cell_width, offset));
+            if cell_width == CellWidth::W64 {
+                wops.push(WOp::I32WrapI64);
+            }
+            wops.push(WOp::Call {
+                function_index: PRINT_CHAR,
+            });
         }
         Op::Input(offset) => {
-            let input_ops = [
-                WOp::GetLocal { local_index: 0 },
-                WOp::Call { function_index: 3 },
-                WOp::I32Store8(MemoryImmediate::i8(offset as u32)),
-            ];
-
-            wops.extend(input_ops)
+            // `$input_char`はEOFのときにセルを変更しないこともあるので、アドレスを渡す
+            let offset = push_address(offset, cell_width, wops);
+            if offset != 0 {
+                wops.extend([WOp::I32Const(offset as i32), WOp::I32Add]);
+            }
+            wops.push(WOp::Call {
+                function_index: INPUT_CHAR,
+            });
+        }
+        Op::Lick(x) => {
+            // 0のセルが見つかるまでx個ずつ進む
+            wops.extend([
+                WOp::Block {
+                    block_type: ValueType::Void,
+                },
+                WOp::Loop {
+                    block_type: ValueType::Void,
+                },
+            ]);
+            load_condition(cell_width, wops);
+            wops.extend([WOp::I32Eqz, WOp::BrIf { relative_depth: 1 }]);
+            move_ptr(x, config, wops);
+            wops.extend([WOp::Br { relative_depth: 0 }, WOp::End, WOp::End]);
         }
-        Op::Lick(_) => unimplemented!(),
     }
 }
 
-fn block_to_wop(block: &Block, wops: &mut Vec<WOp>) {
+fn block_to_wop(block: &Block, config: &Config, wops: &mut Vec<WOp>) {
+    let cell_width = config.cell_width;
+
     for item in &block.items {
         match item {
             BlockItem::Op(op) => {
-                op_to_wop(*op, wops);
+                op_to_wop(*op, config, wops);
             }
             BlockItem::Loop(loop_block) => {
-                let loop_ops = [
-                    WOp::Loop {
-                        block_type: ValueType::Void,
-                    },
-                    WOp::GetLocal { local_index: 0 },
-                    WOp::I32Load8U(MemoryImmediate::i8(0)),
-                    WOp::If {
-                        block_type: ValueType::Void,
-                    },
-                ];
+                wops.push(WOp::Loop {
+                    block_type: ValueType::Void,
+                });
+                load_condition(cell_width, wops);
+                wops.push(WOp::If {
+                    block_type: ValueType::Void,
+                });
 
-                wops.extend(loop_ops);
-
-                block_to_wop(loop_block, wops);
+                block_to_wop(loop_block, config, wops);
 
                 let loop_ops = [WOp::Br { relative_depth: 1 }, WOp::End, WOp::End];
 
                 wops.extend(loop_ops);
             }
             BlockItem::If(if_block) => {
-                let if_ops = [
-                    WOp::GetLocal { local_index: 0 },
-                    WOp::I32Load8U(MemoryImmediate::i8(0)),
-                    WOp::If {
-                        block_type: ValueType::Void,
-                    },
-                ];
-
-                wops.extend(if_ops);
+                load_condition(cell_width, wops);
+                wops.push(WOp::If {
+                    block_type: ValueType::Void,
+                });
 
-                block_to_wop(if_block, wops);
+                block_to_wop(if_block, config, wops);
 
                 wops.push(WOp::End);
             }
@@ -164,202 +363,737 @@ fn block_to_wop(block: &Block, wops: &mut Vec<WOp>) {
     }
 }
 
-pub fn block_to_wat(block: &Block, mut out: impl io::Write) -> io::Result<()> {
-    // Base Wasmer
-    // https://github.com/wasmerio/wasmer/blob/75a98ab171bee010b9a7cd0f836919dc4519dcaf/lib/wasi/tests/stdio.rs
-    writeln!(
-        out,
-        r#"(module
-    (import "wasi_unstable" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
-    (import "wasi_unstable" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
-    (memory (export "memory") 1)
-    (func $print_char (param $char i32)
-    i32.const 0
-    local.get $char
-    i32.store8
-
-    ;; Creating a new io vector within linear memory
-    (i32.store (i32.const 4) (i32.const 0))  ;; iov.iov_base - This is a pointer to the start of the 'hello world\n' string
-    (i32.store (i32.const 8) (i32.const 1))  ;; iov.iov_len - The length of the 'hello world\n' string
-
-    (call $fd_write
-        (i32.const 1) ;; file_descriptor - 1 for stdout
-        (i32.const 4) ;; *iovs - The pointer to the iov array, which is stored at memory location 0
-        (i32.const 1) ;; iovs_len - We're printing 1 string stored in an iov - so one.
-        (i32.const 12) ;; nwritten - A place in memory to store the number of bytes written
-    )
-    drop ;; Discard the number of bytes written from the top of the stack
-    )
-    (func $input_char (result i32)
-        (i32.store (i32.const 4) (i32.const 0))
-        (i32.store (i32.const 8) (i32.const 1))
-
-        (call $fd_read
-            (i32.const 0)
-            (i32.const 4)
-            (i32.const 1)
-            (i32.const 12)
-        )
-        drop
-
-        i32.const 0
-        i32.load8_u
-    )
-    (func $main (export "_start") (local i32 i32)"#,
-    )?;
+/// WATとバイナリの両方から使う関数の定義。引数とローカル変数はすべてi32で、返り値はない
+struct FunctionDef {
+    name: &'static str,
+    params: usize,
+    locals: u32,
+    export_name: Option<&'static str>,
+    ops: Vec<WOp>,
+}
 
-    let mut main = Vec::new();
-    main.extend([WOp::I32Const(40), WOp::SetLocal { local_index: 0 }]);
-    block_to_wop(block, &mut main);
-    // テキスト形式だといらない
-    // ops.push(WOp::End);
+/// iovecを1つだけ設定して`fd_write`か`fd_read`を呼び、errnoを積む
+fn call_io(function_index: u32, fd: i32, buf: &[WOp], len: &[WOp], wops: &mut Vec<WOp>) {
+    wops.push(WOp::I32Const(IOV_ADDRESS as i32));
+    wops.extend_from_slice(buf);
+    wops.extend([
+        WOp::I32Store(MemoryImmediate::i32(0)),
+        WOp::I32Const(IOV_ADDRESS as i32),
+    ]);
+    wops.extend_from_slice(len);
+    wops.extend([
+        WOp::I32Store(MemoryImmediate::i32(4)),
+        WOp::I32Const(fd),
+        WOp::I32Const(IOV_ADDRESS as i32),
+        WOp::I32Const(1),
+        WOp::I32Const(NBYTES_ADDRESS as i32),
+        WOp::Call { function_index },
+    ]);
+}
 
-    main.write_str(2, &mut out)?;
-    writeln!(
-        out,
-        "    )
-)"
-    )
+fn call_error(message: Message) -> [WOp; 3] {
+    [
+        WOp::I32Const(message.address() as i32),
+        WOp::I32Const(message.text().len() as i32),
+        WOp::Call {
+            function_index: ERROR,
+        },
+    ]
+}
 
-    // let body = block.to_wat(40);
+fn output_len() -> [WOp; 2] {
+    [
+        WOp::I32Const(OUTPUT_LEN_ADDRESS as i32),
+        WOp::I32Load(MemoryImmediate::i32(0)),
+    ]
 }
 
-fn print_char(wd_write_index: u32) -> Function {
-    let print_char_ops = [
+/// 出力バッファをすべて標準出力に書き出す関数。一度に書ききれなければ繰り返す
+fn flush_ops(layout: &Layout) -> Vec<WOp> {
+    let mut wops = vec![
+        WOp::Block {
+            block_type: ValueType::Void,
+        },
+        WOp::Loop {
+            block_type: ValueType::Void,
+        },
+        // 書き出したbyte数 >= 溜まっているbyte数
+        WOp::GetLocal { local_index: 0 },
+    ];
+    wops.extend(output_len());
+    wops.extend([WOp::I32GeU, WOp::BrIf { relative_depth: 1 }]);
+
+    let mut len = output_len().to_vec();
+    len.extend([WOp::GetLocal { local_index: 0 }, WOp::I32Sub]);
+    call_io(
+        FD_WRITE,
+        1,
+        &[
+            WOp::GetLocal { local_index: 0 },
+            WOp::I32Const(layout.output_buffer as i32),
+            WOp::I32Add,
+        ],
+        &len,
+        &mut wops,
+    );
+    wops.extend([
+        WOp::If {
+            block_type: ValueType::Void,
+        },
+        // `$error`の中でもう一度書き出そうとしないように空にする
+        WOp::I32Const(OUTPUT_LEN_ADDRESS as i32),
         WOp::I32Const(0),
+        WOp::I32Store(MemoryImmediate::i32(0)),
+    ]);
+    wops.extend(call_error(Message::Io));
+    wops.extend([
+        WOp::End,
         WOp::GetLocal { local_index: 0 },
-        WOp::I32Store8(MemoryImmediate::i8(0)),
-        WOp::I32Const(4),
+        WOp::I32Const(NBYTES_ADDRESS as i32),
+        WOp::I32Load(MemoryImmediate::i32(0)),
+        WOp::I32Add,
+        WOp::SetLocal { local_index: 0 },
+        WOp::Br { relative_depth: 0 },
+        WOp::End,
+        WOp::End,
+        WOp::I32Const(OUTPUT_LEN_ADDRESS as i32),
         WOp::I32Const(0),
         WOp::I32Store(MemoryImmediate::i32(0)),
-        WOp::I32Const(8),
+    ]);
+    wops
+}
+
+/// 1byteを出力バッファに追加し、いっぱいになったら書き出す関数
+fn print_char_ops(layout: &Layout) -> Vec<WOp> {
+    let mut wops = output_len().to_vec();
+    wops.extend([
+        WOp::GetLocal { local_index: 0 },
+        WOp::I32Store8(MemoryImmediate::i8(layout.output_buffer)),
+        WOp::I32Const(OUTPUT_LEN_ADDRESS as i32),
+    ]);
+    wops.extend(output_len());
+    wops.extend([
         WOp::I32Const(1),
+        WOp::I32Add,
         WOp::I32Store(MemoryImmediate::i32(0)),
-        WOp::I32Const(1),
-        WOp::I32Const(4),
-        WOp::I32Const(1),
-        WOp::I32Const(12),
+    ]);
+    wops.extend(output_len());
+    wops.extend([
+        WOp::I32Const(OUTPUT_BUFFER_SIZE as i32),
+        WOp::I32Eq,
+        WOp::If {
+            block_type: ValueType::Void,
+        },
         WOp::Call {
-            function_index: wd_write_index,
+            function_index: FLUSH,
         },
-        WOp::Drop,
         WOp::End,
-    ];
+    ]);
+    wops
+}
+
+/// 1byte読み込んで、引数のアドレスのセルに書き込む関数
+fn input_char_ops(config: &Config) -> Vec<WOp> {
+    let cell_width = config.cell_width;
+
+    // 対話的に使うときのために、読む前に出力を書き出す
+    let mut wops = vec![WOp::Call {
+        function_index: FLUSH,
+    }];
+    call_io(
+        FD_READ,
+        0,
+        &[WOp::I32Const(INPUT_ADDRESS as i32)],
+        &[WOp::I32Const(1)],
+        &mut wops,
+    );
+    wops.push(WOp::If {
+        block_type: ValueType::Void,
+    });
+    wops.extend(call_error(Message::Io));
+    wops.push(WOp::End);
 
-    Function {
-        signature: FuncSignature {
-            params: vec![ValueType::I32],
-            result: None,
+    // nread == 0ならEOF
+    wops.extend([
+        WOp::I32Const(NBYTES_ADDRESS as i32),
+        WOp::I32Load(MemoryImmediate::i32(0)),
+        WOp::I32Eqz,
+        WOp::If {
+            block_type: ValueType::Void,
         },
-        body: FunctionBody::from_ops(print_char_ops.to_vec()),
-        export_name: None,
+    ]);
+    match config.eof {
+        EofBehavior::Unchanged => {}
+        EofBehavior::Zero | EofBehavior::MinusOne => {
+            let value = if config.eof == EofBehavior::Zero {
+                0
+            } else {
+                -1
+            };
+            wops.extend([
+                WOp::GetLocal { local_index: 0 },
+                cell_const(cell_width, value),
+                store(cell_width, 0),
+            ]);
+        }
+        EofBehavior::Error => wops.extend(call_error(Message::UnexpectedEof)),
     }
+    wops.extend([WOp::Return, WOp::End]);
+
+    wops.extend([
+        WOp::GetLocal { local_index: 0 },
+        WOp::I32Const(INPUT_ADDRESS as i32),
+        WOp::I32Load8U(MemoryImmediate::i8(0)),
+    ]);
+    if cell_width == CellWidth::W64 {
+        wops.push(WOp::I64ExtendI32U);
+    }
+    wops.push(store(cell_width, 0));
+    wops
 }
 
-fn input_char(fd_read_index: u32) -> Function {
-    let input_char_ops = [
-        WOp::I32Const(4),
-        WOp::I32Const(0),
-        WOp::I32Store(MemoryImmediate::i32(0)),
-        WOp::I32Const(8),
-        WOp::I32Const(1),
-        WOp::I32Store8(MemoryImmediate::i8(0)),
-        WOp::I32Const(0),
-        WOp::I32Const(4),
+/// 出力を書き出してから、メッセージを標準エラー出力に書いて終了コード1で終了する関数
+fn error_ops() -> Vec<WOp> {
+    let mut wops = vec![WOp::Call {
+        function_index: FLUSH,
+    }];
+    call_io(
+        FD_WRITE,
+        2,
+        &[WOp::GetLocal { local_index: 0 }],
+        &[WOp::GetLocal { local_index: 1 }],
+        &mut wops,
+    );
+    wops.extend([
+        WOp::Drop,
         WOp::I32Const(1),
-        WOp::I32Const(12),
         WOp::Call {
-            function_index: fd_read_index,
+            function_index: PROC_EXIT,
         },
-        WOp::Drop,
-        WOp::I32Const(0),
-        WOp::I32Load8U(MemoryImmediate::i8(0)),
-        WOp::End,
-    ];
+        WOp::Unreachable,
+    ]);
+    wops
+}
+
+/// 移動後のポインタを受け取り、範囲外なら終了し、必要ならメモリを拡張する関数
+fn check_pointer_ops(config: &Config, layout: &Layout) -> Vec<WOp> {
+    let out_of_range = call_error(Message::OutOfRange);
+    let out_of_memory = call_error(Message::OutOfMemory);
+    let if_ = WOp::If {
+        block_type: ValueType::Void,
+    };
+
+    let mut wops = Vec::new();
+    if config.checked {
+        // ptr < tape_begin
+        wops.extend([
+            WOp::GetLocal { local_index: 0 },
+            WOp::I32Const(layout.tape_begin as i32),
+            WOp::I32LtS,
+            if_.clone(),
+        ]);
+        wops.extend(out_of_range.clone());
+        wops.push(WOp::End);
+    }
+    if config.growable {
+        // 余白も含めてアクセスする範囲の末尾が、メモリの末尾を越えたら拡張する
+        wops.extend([
+            WOp::GetLocal { local_index: 0 },
+            WOp::I32Const((layout.margin + config.cell_width.bytes()) as i32),
+            WOp::I32Add,
+            WOp::TeeLocal { local_index: 1 },
+            WOp::MemorySize,
+            WOp::I32Const(16),
+            WOp::I32Shl,
+            WOp::I32GtU,
+            if_.clone(),
+            // 必要なページ数 - 今のページ数
+            WOp::GetLocal { local_index: 1 },
+            WOp::I32Const(PAGE_SIZE as i32 - 1),
+            WOp::I32Add,
+            WOp::I32Const(16),
+            WOp::I32ShrU,
+            WOp::MemorySize,
+            WOp::I32Sub,
+            WOp::MemoryGrow,
+            WOp::I32Const(-1),
+            WOp::I32Eq,
+            if_,
+        ]);
+        wops.extend(out_of_memory);
+        wops.extend([WOp::End, WOp::End]);
+    } else if config.checked {
+        // ptr >= tape_end
+        wops.extend([
+            WOp::GetLocal { local_index: 0 },
+            WOp::I32Const(layout.tape_end as i32),
+            WOp::I32GeU,
+            if_,
+        ]);
+        wops.extend(out_of_range);
+        wops.push(WOp::End);
+    }
+    wops
+}
 
-    Function {
-        signature: FuncSignature {
-            params: vec![],
-            result: Some(ValueType::I32),
+fn main_ops(block: &Block, config: &Config, layout: &Layout) -> Vec<WOp> {
+    // テープより前はI/Oなどで使う。
+    // checkedでない場合、テープより前をいじったときの動作は未定義（I/O関連がこわれるかも？）
+    let mut wops = vec![
+        WOp::I32Const(layout.start as i32),
+        WOp::SetLocal { local_index: 0 },
+    ];
+    block_to_wop(block, config, &mut wops);
+    wops.extend([
+        WOp::Call {
+            function_index: FLUSH,
+        },
+        WOp::I32Const(0),
+        WOp::Call {
+            function_index: PROC_EXIT,
         },
-        body: FunctionBody::from_ops(input_char_ops.to_vec()),
+    ]);
+    wops
+}
+
+/// importした関数の後ろに並ぶ関数。インデックスの順に並べる
+fn functions(block: &Block, config: &Config, layout: &Layout) -> Vec<FunctionDef> {
+    let function = |name, params, locals, ops| FunctionDef {
+        name,
+        params,
+        locals,
         export_name: None,
+        ops,
+    };
+
+    vec![
+        function("flush", 0, 1, flush_ops(layout)),
+        function("print_char", 1, 0, print_char_ops(layout)),
+        function("input_char", 1, 0, input_char_ops(config)),
+        function("error", 2, 0, error_ops()),
+        function("check_pointer", 1, 1, check_pointer_ops(config, layout)),
+        FunctionDef {
+            export_name: Some("_start"),
+            ..function("main", 0, 1, main_ops(block, config, layout))
+        },
+    ]
+}
+
+/// WATの文字列リテラル
+fn wat_string(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&b| match b {
+            b'"' | b'\\' => format!("\\{}", b as char),
+            0x20..=0x7e => (b as char).to_string(),
+            _ => format!("\\{b:02x}"),
+        })
+        .collect()
+}
+
+fn wat_types(kind: &str, count: usize) -> String {
+    if count == 0 {
+        String::new()
+    } else {
+        format!(" ({kind}{})", " i32".repeat(count))
     }
 }
 
-pub fn block_to_wasm(block: &Block, mut buffer: impl io::Write) -> io::Result<()> {
+pub fn block_to_wat(block: &Block, config: &Config, mut out: impl io::Write) -> io::Result<()> {
+    let layout = Layout::new(block, config)?;
+
+    writeln!(out, "(module")?;
+    for (name, params, result) in IMPORTS {
+        writeln!(
+            out,
+            r#"    (import "{WASI_MODULE}" "{name}" (func ${name}{}{}))"#,
+            wat_types("param", params),
+            wat_types("result", result as usize),
+        )?;
+    }
+    writeln!(
+        out,
+        r#"    (memory (export "memory") {pages})
+    (data (i32.const {MESSAGE_ADDRESS}) "{messages}")"#,
+        pages = layout.pages,
+        messages = wat_string(&Message::all_texts()),
+    )?;
+
+    for function in functions(block, config, &layout) {
+        let export = match function.export_name {
+            Some(name) => format!(r#" (export "{name}")"#),
+            None => String::new(),
+        };
+        writeln!(
+            out,
+            "    (func ${}{export}{}{}",
+            function.name,
+            wat_types("param", function.params),
+            wat_types("local", function.locals as usize),
+        )?;
+        // テキスト形式だと最後のendはいらない
+        function.ops.write_str(2, &mut out)?;
+        writeln!(out, "    )")?;
+    }
+    writeln!(out, ")")
+}
+
+pub fn block_to_wasm(block: &Block, config: &Config, mut buffer: impl io::Write) -> io::Result<()> {
+    let layout = Layout::new(block, config)?;
+
     let mut module_builder = ModuleBuilder::new(Memory {
         mem_type: MemoryType {
             limits: ResizableLimits {
-                initial: 1,
+                initial: layout.pages,
                 maximum: None,
             },
         },
         export_name: Some("memory".to_string()),
     });
+    module_builder.push_data(MESSAGE_ADDRESS, Message::all_texts());
 
-    let import_fd_write = Import::Function {
-        module_name: "wasi_unstable".to_string(),
-        field_name: "fd_write".to_string(),
-        signature: FuncSignature {
-            params: vec![
-                ValueType::I32,
-                ValueType::I32,
-                ValueType::I32,
-                ValueType::I32,
-            ],
-            result: Some(ValueType::I32),
-        },
-    };
+    for (name, params, result) in IMPORTS {
+        module_builder.push_import(Import::Function {
+            module_name: WASI_MODULE.to_string(),
+            field_name: name.to_string(),
+            signature: FuncSignature {
+                params: vec![ValueType::I32; params],
+                result: result.then_some(ValueType::I32),
+            },
+        });
+    }
 
-    let fd_write = module_builder.push_import(import_fd_write);
-
-    let import_fd_read = Import::Function {
-        module_name: "wasi_unstable".to_string(),
-        field_name: "fd_read".to_string(),
-        signature: FuncSignature {
-            params: vec![
-                ValueType::I32,
-                ValueType::I32,
-                ValueType::I32,
-                ValueType::I32,
-            ],
-            result: Some(ValueType::I32),
-        },
-    };
+    for definition in functions(block, config, &layout) {
+        let mut ops = definition.ops;
+        ops.push(WOp::End);
 
-    let fd_read = module_builder.push_import(import_fd_read);
+        let mut function = Function {
+            signature: FuncSignature {
+                params: vec![ValueType::I32; definition.params],
+                result: None,
+            },
+            body: FunctionBody::from_ops(ops),
+            export_name: definition.export_name.map(str::to_string),
+        };
+        if definition.locals > 0 {
+            function.push_local(LocalEntry {
+                count: definition.locals,
+                type_: ValueType::I32,
+            });
+        }
+        module_builder.push_function(function);
+    }
 
-    module_builder.push_function(print_char(fd_write));
-    module_builder.push_function(input_char(fd_read));
+    let module = module_builder.into_module();
+    module.write(&mut buffer)
+}
 
-    let mut main = Function {
-        signature: FuncSignature {
-            params: vec![],
-            result: None,
-        },
-        body: FunctionBody::new(),
-        export_name: Some("_start".to_string()),
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        io::Write,
+        path::Path,
+        process::{Command, Stdio},
     };
 
-    let ptr = LocalEntry {
-        count: 2,
-        type_: ValueType::I32,
+    use crate::{
+        cell::Cell,
+        interpreter::InterPreter,
+        opt::{optimize, optimize_for_interpreter},
+        utils::bf_to_block,
     };
-    main.push_local(ptr);
 
-    // ポインタの初期値を40に設定する。40未満はI/Oで使うために確保する。
-    // 40未満をいじった場合の動作は未定義（I/O関連がこわれるかも？）
-    main.body
-        .code
-        .extend([WOp::I32Const(40), WOp::SetLocal { local_index: 0 }]);
+    use super::*;
 
-    block_to_wop(block, &mut main.body.code);
+    /// nodeでWASIのモジュールを実行するスクリプト。引数はwasmファイルのパス
+    const NODE_RUNNER: &str = r#"
+import { readFileSync } from 'node:fs';
+import { WASI } from 'node:wasi';
+const wasi = new WASI({ version: 'preview1', args: [], env: {}, returnOnExit: true });
+const module = await WebAssembly.compile(readFileSync(process.argv[2]));
+const instance = await WebAssembly.instantiate(module, wasi.getImportObject());
+process.exitCode = wasi.start(instance);
+"#;
 
-    main.body.code.push(WOp::End);
+    /// WASIのランタイム（wasmtimeかnode）で`path`を実行するコマンド。どちらもなければNone。
+    /// nodeの場合は`runner`にスクリプトを書き出す
+    fn wasi_command(path: &Path, runner: &Path) -> Option<Command> {
+        if Command::new("wasmtime").arg("--version").output().is_ok() {
+            let mut command = Command::new("wasmtime");
+            command.arg("run").arg(path);
+            return Some(command);
+        }
 
-    module_builder.push_function(main);
+        let node_args = ["--no-warnings", "--experimental-wasi-unstable-preview1"];
+        let has_wasi = Command::new("node")
+            .args(node_args)
+            .args([
+                "-e",
+                "new (require('node:wasi').WASI)({ version: 'preview1' })",
+            ])
+            .output()
+            .is_ok_and(|output| output.status.success());
+        if !has_wasi {
+            return None;
+        }
+        fs::write(runner, NODE_RUNNER).unwrap();
+        let mut command = Command::new("node");
+        command.args(node_args).arg(runner).arg(path);
+        Some(command)
+    }
 
-    let module = module_builder.into_module();
-    module.write(&mut buffer)
+    /// wasmにして実行し、(終了コード, 標準出力, 標準エラー出力)を返す。
+    /// ランタイムがなければNone
+    fn run_wasm(
+        name: &str,
+        block: &Block,
+        config: &Config,
+        input: &[u8],
+    ) -> Option<(i32, Vec<u8>, Vec<u8>)> {
+        let path = std::env::temp_dir().join(format!("bf_test_{}_{name}.wasm", std::process::id()));
+        let runner = path.with_extension("mjs");
+        let mut wasm = Vec::new();
+        block_to_wasm(block, config, &mut wasm).unwrap();
+        fs::write(&path, wasm).unwrap();
+
+        let Some(mut command) = wasi_command(&path, &runner) else {
+            fs::remove_file(&path).unwrap();
+            return None;
+        };
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        let output = child.wait_with_output().unwrap();
+
+        fs::remove_file(&path).unwrap();
+        let _ = fs::remove_file(&runner);
+
+        Some((output.status.code().unwrap(), output.stdout, output.stderr))
+    }
+
+    fn run_interpreter<C: Cell>(block: &Block, input: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        InterPreter::builder()
+            .root_node(block)
+            .input(input)
+            .output(&mut output)
+            .memory(vec![C::default(); 30000])
+            .build()
+            .run()
+            .unwrap();
+        output
+    }
+
+    #[test]
+    fn test_negative_offset_and_lick() {
+        let block = bf_to_block(">>>>>>+>+>+<<[>]<[<]>.<<<++[->--<]>.").unwrap();
+        let mut block = optimize(&block, true, false);
+        optimize_for_interpreter(&mut block);
+        assert!(block
+            .items
+            .iter()
+            .any(|item| matches!(item, BlockItem::Op(Op::Lick(_)))));
+
+        for cell_width in [
+            CellWidth::W8,
+            CellWidth::W16,
+            CellWidth::W32,
+            CellWidth::W64,
+        ] {
+            let config = Config {
+                cell_width,
+                ..Default::default()
+            };
+            let mut wat = Vec::new();
+            block_to_wat(&block, &config, &mut wat).unwrap();
+            let wat = String::from_utf8(wat).unwrap();
+            assert!(wat.contains("br_if 1"));
+
+            let expected = match cell_width {
+                CellWidth::W8 => run_interpreter::<u8>(&block, b""),
+                CellWidth::W16 => run_interpreter::<u16>(&block, b""),
+                CellWidth::W32 => run_interpreter::<u32>(&block, b""),
+                CellWidth::W64 => run_interpreter::<u64>(&block, b""),
+            };
+            let name = format!("lick_{cell_width}");
+            let Some((code, output, _)) = run_wasm(&name, &block, &config, b"") else {
+                continue;
+            };
+            assert_eq!(code, 0);
+            assert_eq!(output, expected);
+        }
+    }
+
+    #[test]
+    fn test_layout() {
+        let block = bf_to_block("+[>+]").unwrap();
+
+        let config = Config {
+            memory_len: 100000,
+            ..Default::default()
+        };
+        let layout = Layout::new(&block, &config).unwrap();
+        assert_eq!(layout.pages, 2);
+        assert_eq!(layout.tape_end - layout.tape_begin, 100000);
+
+        let config = Config {
+            cell_width: CellWidth::W64,
+            ..config
+        };
+        let layout = Layout::new(&block, &config).unwrap();
+        assert_eq!(layout.pages, 13);
+        assert_eq!(layout.tape_begin % 8, 0);
+
+        let config = Config {
+            origin: 10,
+            ..config
+        };
+        let layout = Layout::new(&block, &config).unwrap();
+        assert_eq!(layout.start - layout.tape_begin, 80);
+        let config = Config {
+            origin: 100000,
+            ..config
+        };
+        assert!(Layout::new(&block, &config).is_err());
+    }
+
+    #[test]
+    fn test_wasi_and_eof() {
+        let block = bf_to_block(",.").unwrap();
+
+        for (eof, cell_width, expected) in [
+            (EofBehavior::Unchanged, CellWidth::W8, None),
+            (EofBehavior::Zero, CellWidth::W8, Some("i32.const 0\n")),
+            (EofBehavior::MinusOne, CellWidth::W8, Some("i32.const -1\n")),
+            (
+                EofBehavior::MinusOne,
+                CellWidth::W64,
+                Some("i64.const -1\n"),
+            ),
+        ] {
+            let config = Config {
+                eof,
+                cell_width,
+                ..Default::default()
+            };
+            let mut wat = Vec::new();
+            block_to_wat(&block, &config, &mut wat).unwrap();
+            let wat = String::from_utf8(wat).unwrap();
+
+            assert!(!wat.contains("wasi_unstable"));
+            assert!(wat.contains(r#"(import "wasi_snapshot_preview1" "proc_exit""#));
+            // EOFのときだけreturnする
+            let input_char = wat.split("(func $input_char").nth(1).unwrap();
+            let input_char = input_char.split("(func").next().unwrap();
+            let on_eof = input_char.split("i32.eqz").nth(1).unwrap();
+            let on_eof = on_eof.split("return").next().unwrap();
+            match expected {
+                Some(value) => assert!(on_eof.contains(value)),
+                None => assert!(!on_eof.contains("store")),
+            }
+
+            let mut wasm = Vec::new();
+            block_to_wasm(&block, &config, &mut wasm).unwrap();
+        }
+
+        // 実際に動かす。importが解決できなければ、ランタイムがエラーで終了する
+        let block = bf_to_block("+,.").unwrap();
+        for (eof, expected_code, expected_output) in [
+            (EofBehavior::Unchanged, 0, &[1][..]),
+            (EofBehavior::Zero, 0, &[0]),
+            (EofBehavior::MinusOne, 0, &[255]),
+            (EofBehavior::Error, 1, &[]),
+        ] {
+            for cell_width in [CellWidth::W8, CellWidth::W64] {
+                let config = Config {
+                    eof,
+                    cell_width,
+                    ..Default::default()
+                };
+                let name = format!("eof_{eof:?}_{cell_width}");
+
+                let Some((code, output, error)) = run_wasm(&name, &block, &config, b"a") else {
+                    return;
+                };
+                assert_eq!((code, output), (0, b"a".to_vec()));
+                assert!(error.is_empty());
+
+                // fd_readがnread == 0を返す
+                let (code, output, error) = run_wasm(&name, &block, &config, b"").unwrap();
+                assert_eq!(code, expected_code);
+                assert_eq!(output, expected_output);
+                if expected_code == 0 {
+                    assert!(error.is_empty());
+                } else {
+                    assert_eq!(error, Message::UnexpectedEof.text());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_check_pointer() {
+        let block = bf_to_block("+[>+]").unwrap();
+
+        for (growable, checked) in [(false, false), (true, false), (false, true), (true, true)] {
+            let config = Config {
+                growable,
+                checked,
+                ..Default::default()
+            };
+            let mut wat = Vec::new();
+            block_to_wat(&block, &config, &mut wat).unwrap();
+            let wat = String::from_utf8(wat).unwrap();
+
+            assert_eq!(wat.contains("memory.grow"), growable);
+            assert_eq!(wat.contains("i32.lt_s"), checked);
+            assert_eq!(
+                wat.contains(&format!("call {CHECK_POINTER}")),
+                growable || checked
+            );
+        }
+
+        // 範囲外に出たら、メッセージを出して終了する。
+        // growableだと右にはメモリを使い切るまで進めるので、左にはみ出す場合だけ試す
+        for (growable, source) in [(false, "+[>+]"), (false, "+<"), (true, "+<")] {
+            let config = Config {
+                memory_len: 10,
+                growable,
+                checked: true,
+                ..Default::default()
+            };
+            let block = bf_to_block(source).unwrap();
+            let Some((code, output, error)) = run_wasm("check_pointer", &block, &config, b"")
+            else {
+                return;
+            };
+            assert_eq!(code, 1);
+            assert!(output.is_empty());
+            assert_eq!(error, Message::OutOfRange.text());
+        }
+
+        // 最初のページを越えて動く
+        let block = bf_to_block(&format!("{}+.", ">".repeat(70000))).unwrap();
+        for (growable, checked, expected_code) in
+            [(true, false, 0), (true, true, 0), (false, true, 1)]
+        {
+            let config = Config {
+                memory_len: 10,
+                growable,
+                checked,
+                ..Default::default()
+            };
+            let name = format!("grow_{growable}_{checked}");
+            let Some((code, output, _)) = run_wasm(&name, &block, &config, b"") else {
+                return;
+            };
+            assert_eq!(code, expected_code);
+            assert_eq!(output, if expected_code == 0 { &[1][..] } else { &[] });
+        }
+    }
 }
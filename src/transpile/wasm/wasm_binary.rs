@@ -11,8 +11,9 @@ use std::io::{self, Write};
 use self::{
     code::{FunctionBody, LocalEntry},
     section::{
-        CodeSection, ExportEntry, ExportSection, ExternalKind, FunctionSection, ImportEntry,
-        ImportSection, MemorySection, MemoryType, Section, TypeSection,
+        CodeSection, DataSection, DataSegment, ExportEntry, ExportSection, ExternalKind,
+        FunctionSection, ImportEntry, ImportSection, MemorySection, MemoryType, Section,
+        TypeSection,
     },
     type_::{FuncSignature, Type},
 };
@@ -24,6 +25,7 @@ pub struct Module {
     code_section: CodeSection,
     memory_section: MemorySection,
     export_section: ExportSection,
+    data_section: DataSection,
     // 他にもsectionはあるが、上記のものだけで十分
 }
 impl Module {
@@ -37,6 +39,7 @@ impl Module {
         self.memory_section.write_section(&mut w)?;
         self.export_section.write_section(&mut w)?;
         self.code_section.write_section(&mut w)?;
+        self.data_section.write_section(&mut w)?;
 
         Ok(())
     }
@@ -46,6 +49,7 @@ pub struct ModuleBuilder {
     imports: Vec<Import>,
     functions: Vec<Function>,
     memory: Memory,
+    data: Vec<DataSegment>,
 }
 
 impl ModuleBuilder {
@@ -55,6 +59,7 @@ impl ModuleBuilder {
             functions: Vec::new(),
             imports: Vec::new(),
             memory,
+            data: Vec::new(),
         }
     }
     /// `push_function`の前に行う
@@ -68,6 +73,10 @@ impl ModuleBuilder {
     pub fn push_function(&mut self, function: Function) {
         self.functions.push(function);
     }
+    /// メモリの`offset`から`data`を書き込んだ状態で始める
+    pub fn push_data(&mut self, offset: u32, data: Vec<u8>) {
+        self.data.push(DataSegment { offset, data });
+    }
 
     pub fn into_module(self) -> Module {
         let mut type_section = TypeSection::new();
@@ -76,6 +85,7 @@ impl ModuleBuilder {
         let mut code_section = CodeSection::new();
         let mut export_section = ExportSection::new();
         let mut memory_section = MemorySection::new();
+        let mut data_section = DataSection::new();
 
         // Importをゴニョゴニョ
         for import in self.imports {
@@ -128,6 +138,10 @@ impl ModuleBuilder {
                 export_section.push(export_entry);
             }
         }
+        for segment in self.data {
+            data_section.push(segment);
+        }
+
         Module {
             type_section,
             import_section,
@@ -135,6 +149,7 @@ impl ModuleBuilder {
             code_section,
             memory_section,
             export_section,
+            data_section,
         }
     }
 }
@@ -2,6 +2,7 @@ use std::io::{self, Write};
 
 use super::{leb128::WriteLeb128, type_::ValueType};
 
+#[derive(Default)]
 pub struct FunctionBody {
     locals: Vec<LocalEntry>,
     pub code: Vec<Op>,
@@ -55,10 +56,14 @@ impl LocalEntry {
 #[derive(Debug, Clone)]
 pub enum Op {
     _Nop,
+    Unreachable,
     End,
+    Block { block_type: ValueType },
     Loop { block_type: ValueType },
     If { block_type: ValueType },
     Br { relative_depth: u32 },
+    BrIf { relative_depth: u32 },
+    Return,
 
     Call { function_index: u32 },
 
@@ -68,22 +73,51 @@ pub enum Op {
     SetLocal { local_index: u32 },
     TeeLocal { local_index: u32 },
 
+    I32Load(MemoryImmediate),
+    I64Load(MemoryImmediate),
     I32Load8U(MemoryImmediate),
+    I32Load16U(MemoryImmediate),
     I32Store(MemoryImmediate),
+    I64Store(MemoryImmediate),
     I32Store8(MemoryImmediate),
+    I32Store16(MemoryImmediate),
+
+    MemorySize,
+    MemoryGrow,
 
     I32Const(i32),
+    I64Const(i64),
+
+    I32Eqz,
+    I32Eq,
+    I32LtS,
+    I32GtU,
+    I32GeU,
+    I64Eqz,
 
     I32Add,
     I32Sub,
     I32Mul,
+    I32Shl,
+    I32ShrU,
+    I64Add,
+    I64Sub,
+    I64Mul,
+
+    I32WrapI64,
+    I64ExtendI32U,
 }
 
 impl Op {
     pub fn write_str(&self, mut s: impl io::Write) -> io::Result<()> {
         match self {
             Op::_Nop => Ok(()),
+            Op::Unreachable => write!(s, "unreachable"),
             Op::End => write!(s, "end"),
+            Op::Block { block_type } => {
+                assert_eq!(block_type, &ValueType::Void);
+                write!(s, "block")
+            }
             Op::Loop { block_type } => {
                 assert_eq!(block_type, &ValueType::Void);
                 write!(s, "loop")
@@ -93,24 +127,52 @@ impl Op {
                 write!(s, "if")
             }
             Op::Br { relative_depth } => write!(s, "br {}", relative_depth),
+            Op::BrIf { relative_depth } => write!(s, "br_if {}", relative_depth),
+            Op::Return => write!(s, "return"),
             Op::Call { function_index } => write!(s, "call {}", function_index),
             Op::Drop => write!(s, "drop"),
             Op::GetLocal { local_index } => write!(s, "local.get {}", local_index),
             Op::SetLocal { local_index } => write!(s, "local.set {}", local_index),
             Op::TeeLocal { local_index } => write!(s, "local.tee {}", local_index),
+            Op::I32Load(offset) => write!(s, "i32.load offset={}", offset.offset),
+            Op::I64Load(offset) => write!(s, "i64.load offset={}", offset.offset),
             Op::I32Load8U(offset) => write!(s, "i32.load8_u offset={}", offset.offset),
+            Op::I32Load16U(offset) => write!(s, "i32.load16_u offset={}", offset.offset),
             Op::I32Store(offset) => write!(s, "i32.store offset={}", offset.offset),
+            Op::I64Store(offset) => write!(s, "i64.store offset={}", offset.offset),
             Op::I32Store8(offset) => write!(s, "i32.store8 offset={}", offset.offset),
+            Op::I32Store16(offset) => write!(s, "i32.store16 offset={}", offset.offset),
+            Op::MemorySize => write!(s, "memory.size"),
+            Op::MemoryGrow => write!(s, "memory.grow"),
             Op::I32Const(var) => write!(s, "i32.const {}", var),
+            Op::I64Const(var) => write!(s, "i64.const {}", var),
+            Op::I32Eqz => write!(s, "i32.eqz"),
+            Op::I32Eq => write!(s, "i32.eq"),
+            Op::I32LtS => write!(s, "i32.lt_s"),
+            Op::I32GtU => write!(s, "i32.gt_u"),
+            Op::I32GeU => write!(s, "i32.ge_u"),
+            Op::I64Eqz => write!(s, "i64.eqz"),
             Op::I32Add => write!(s, "i32.add"),
             Op::I32Sub => write!(s, "i32.sub"),
             Op::I32Mul => write!(s, "i32.mul"),
+            Op::I32Shl => write!(s, "i32.shl"),
+            Op::I32ShrU => write!(s, "i32.shr_u"),
+            Op::I64Add => write!(s, "i64.add"),
+            Op::I64Sub => write!(s, "i64.sub"),
+            Op::I64Mul => write!(s, "i64.mul"),
+            Op::I32WrapI64 => write!(s, "i32.wrap_i64"),
+            Op::I64ExtendI32U => write!(s, "i64.extend_i32_u"),
         }
     }
     pub fn write(&self, mut w: impl Write) -> io::Result<()> {
         match self {
             Op::_Nop => w.write_all(&[0x01]),
+            Op::Unreachable => w.write_all(&[0x00]),
             Op::End => w.write_all(&[0x0b]),
+            Op::Block { block_type } => {
+                w.write_all(&[0x02])?;
+                block_type.write(&mut w)
+            }
             Op::Loop { block_type } => {
                 w.write_all(&[0x03])?;
                 block_type.write(&mut w)
@@ -123,6 +185,11 @@ impl Op {
                 w.write_all(&[0x0c])?;
                 relative_depth.write_leb128(&mut w)
             }
+            Op::BrIf { relative_depth } => {
+                w.write_all(&[0x0d])?;
+                relative_depth.write_leb128(&mut w)
+            }
+            Op::Return => w.write_all(&[0x0f]),
             Op::Call { function_index } => {
                 w.write_all(&[0x10])?;
                 function_index.write_leb128(&mut w)
@@ -140,25 +207,65 @@ impl Op {
                 w.write_all(&[0x22])?;
                 local_index.write_leb128(w)
             }
+            Op::I32Load(memory_immediate) => {
+                w.write_all(&[0x28])?;
+                memory_immediate.write(w)
+            }
+            Op::I64Load(memory_immediate) => {
+                w.write_all(&[0x29])?;
+                memory_immediate.write(w)
+            }
             Op::I32Load8U(memory_immediate) => {
                 w.write_all(&[0x2d])?;
                 memory_immediate.write(w)
             }
+            Op::I32Load16U(memory_immediate) => {
+                w.write_all(&[0x2f])?;
+                memory_immediate.write(w)
+            }
             Op::I32Store(memory_immediate) => {
                 w.write_all(&[0x36])?;
                 memory_immediate.write(w)
             }
+            Op::I64Store(memory_immediate) => {
+                w.write_all(&[0x37])?;
+                memory_immediate.write(w)
+            }
             Op::I32Store8(memory_immediate) => {
                 w.write_all(&[0x3a])?;
                 memory_immediate.write(w)
             }
+            Op::I32Store16(memory_immediate) => {
+                w.write_all(&[0x3b])?;
+                memory_immediate.write(w)
+            }
+            // 2byte目はメモリのインデックス（常に0）
+            Op::MemorySize => w.write_all(&[0x3f, 0x00]),
+            Op::MemoryGrow => w.write_all(&[0x40, 0x00]),
             Op::I32Const(literal) => {
                 w.write_all(&[0x41])?;
                 literal.write_leb128(w)
             }
+            Op::I64Const(literal) => {
+                w.write_all(&[0x42])?;
+                literal.write_leb128(w)
+            }
+            Op::I32Eqz => w.write_all(&[0x45]),
+            Op::I32Eq => w.write_all(&[0x46]),
+            Op::I32LtS => w.write_all(&[0x48]),
+            Op::I32GtU => w.write_all(&[0x4b]),
+            Op::I32GeU => w.write_all(&[0x4f]),
+            Op::I64Eqz => w.write_all(&[0x50]),
             Op::I32Add => w.write_all(&[0x6a]),
             Op::I32Sub => w.write_all(&[0x6b]),
             Op::I32Mul => w.write_all(&[0x6c]),
+            Op::I32Shl => w.write_all(&[0x74]),
+            Op::I32ShrU => w.write_all(&[0x76]),
+            Op::I64Add => w.write_all(&[0x7c]),
+            Op::I64Sub => w.write_all(&[0x7d]),
+            Op::I64Mul => w.write_all(&[0x7e]),
+            Op::I32WrapI64 => w.write_all(&[0xa7]),
+            Op::I64ExtendI32U => w.write_all(&[0xad]),
         }
     }
 }
@@ -172,9 +279,15 @@ impl MemoryImmediate {
     pub fn i8(offset: u32) -> Self {
         Self { flags: 0, offset }
     }
+    pub fn i16(offset: u32) -> Self {
+        Self { flags: 1, offset }
+    }
     pub fn i32(offset: u32) -> Self {
         Self { flags: 2, offset }
     }
+    pub fn i64(offset: u32) -> Self {
+        Self { flags: 3, offset }
+    }
     fn write(&self, mut w: impl Write) -> io::Result<()> {
         self.flags.write_leb128(&mut w)?;
         self.offset.write_leb128(&mut w)
@@ -197,7 +310,7 @@ impl OpSlice for [Op] {
     fn write_str(&self, mut indent: u32, mut s: impl Write) -> io::Result<()> {
         for op in self {
             match op {
-                Op::Loop { .. } | Op::If { .. } => indent += 1,
+                Op::Block { .. } | Op::Loop { .. } | Op::If { .. } => indent += 1,
                 Op::End => indent -= 1,
                 _ => (),
             }
@@ -24,6 +24,7 @@ pub(crate) trait Section {
     }
 }
 
+#[derive(Default)]
 pub struct TypeSection {
     types: Vec<Type>,
 }
@@ -53,6 +54,7 @@ impl Section for TypeSection {
     }
 }
 
+#[derive(Default)]
 pub struct ImportSection {
     pub import_entries: Vec<ImportEntry>,
 }
@@ -139,6 +141,7 @@ pub enum ExternalKind {
     // Global = 3,
 }
 
+#[derive(Default)]
 pub struct FunctionSection {
     types: Vec<u32>,
 }
@@ -167,6 +170,7 @@ impl Section for FunctionSection {
     }
 }
 
+#[derive(Default)]
 pub struct MemorySection {
     entries: Vec<MemoryType>,
 }
@@ -226,6 +230,7 @@ impl ResizableLimits {
     }
 }
 
+#[derive(Default)]
 pub struct ExportSection {
     entries: Vec<ExportEntry>,
 }
@@ -274,6 +279,7 @@ impl ExportEntry {
     }
 }
 
+#[derive(Default)]
 pub struct CodeSection {
     function_bodies: Vec<FunctionBody>,
 }
@@ -302,3 +308,52 @@ impl Section for CodeSection {
         Ok(())
     }
 }
+
+/// メモリの初期値
+pub struct DataSegment {
+    pub offset: u32,
+    pub data: Vec<u8>,
+}
+impl DataSegment {
+    fn write(&self, mut w: impl Write) -> io::Result<()> {
+        // メモリのインデックス
+        0u32.write_leb128(&mut w)?;
+        // 書き込み先: i32.const offset; end
+        w.write_all(&[0x41])?;
+        (self.offset as i32).write_leb128(&mut w)?;
+        w.write_all(&[0x0b])?;
+
+        let size = self.data.len() as u32;
+        size.write_leb128(&mut w)?;
+        w.write_all(&self.data)
+    }
+}
+
+#[derive(Default)]
+pub struct DataSection {
+    entries: Vec<DataSegment>,
+}
+impl DataSection {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+    pub fn push(&mut self, segment: DataSegment) {
+        self.entries.push(segment)
+    }
+}
+impl Section for DataSection {
+    fn section_id(&self) -> u8 {
+        11
+    }
+
+    fn write(&self, mut w: impl Write) -> io::Result<()> {
+        let count = self.entries.len() as u32;
+        count.write_leb128(&mut w)?;
+        for entry in &self.entries {
+            entry.write(&mut w)?
+        }
+        Ok(())
+    }
+}
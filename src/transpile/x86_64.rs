@@ -0,0 +1,488 @@
+//! `Block`からx86-64の機械語を生成する。JITとELF出力で共有する。
+//!
+//! レジスタの割り当て
+//! - rbx: データポインタ（アドレス）
+//! - r12: `Context`へのポインタ（JITのみ）
+//! - r13: テープの先頭アドレス
+//! - r14: テープの末尾アドレス（これ自体は範囲外）
+//!
+//! どれもcallee-savedなので、I/O関数を呼んでも壊れない。
+//! syscall命令が壊すのはrcxとr11だけなので、こちらも問題ない。
+
+use std::io;
+
+use crate::{
+    cell::CellWidth,
+    ir::{Block, BlockItem, Op},
+};
+
+// 生成した関数の戻り値
+pub(crate) const STATUS_OK: u32 = 0;
+pub(crate) const STATUS_POINTER_OUT_OF_RANGE: u32 = 1;
+pub(crate) const STATUS_IO_ERROR: u32 = 2;
+
+// Linuxのシステムコール番号
+const SYS_READ: i32 = 0;
+const SYS_WRITE: i32 = 1;
+const SYS_EXIT: i32 = 60;
+
+/// 生成コードから呼び出すI/O関数のアドレス
+pub(crate) struct IoFunctions {
+    /// `extern "C" fn(ctx, value: u8) -> u32` 成功したら0
+    pub putchar: u64,
+    /// `extern "C" fn(ctx) -> u32` 256以上ならエラー
+    pub getchar: u64,
+}
+
+/// I/Oの方法
+pub(crate) enum IoMode {
+    /// Rustの関数を呼び出す（JIT）
+    Call(IoFunctions),
+    /// `read`/`write`システムコールを直接呼ぶ（ELF）
+    Syscall,
+}
+
+// Jcc rel32の条件コード（0x0f 0x8? の下位4bit）
+#[derive(Clone, Copy)]
+enum Cond {
+    Below = 0x2,
+    AboveEqual = 0x3,
+    Equal = 0x4,
+    NotEqual = 0x5,
+}
+
+struct Assembler {
+    code: Vec<u8>,
+    cell_width: CellWidth,
+}
+impl Assembler {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+    fn emit_i32(&mut self, value: i32) {
+        self.emit(&value.to_le_bytes());
+    }
+    /// `[rbx + disp32]`を指すModR/M
+    fn rbx_disp(&mut self, reg: u8, disp: i32) {
+        self.emit(&[0b1000_0011 | (reg << 3)]);
+        self.emit_i32(disp);
+    }
+    /// セル幅に応じたオペランドサイズprefixとopcodeを出す。
+    /// `byte_opcode`は8bit版、`opcode`は16/32/64bit版。
+    fn cell_opcode(&mut self, byte_opcode: u8, opcode: u8) {
+        match self.cell_width {
+            CellWidth::W8 => self.emit(&[byte_opcode]),
+            CellWidth::W16 => self.emit(&[0x66, opcode]),
+            CellWidth::W32 => self.emit(&[opcode]),
+            CellWidth::W64 => self.emit(&[0x48, opcode]),
+        }
+    }
+    /// セル幅の即値。64bitの場合は符号拡張される32bit即値になる。
+    fn cell_imm(&mut self, value: i32) {
+        match self.cell_width {
+            CellWidth::W8 => self.emit(&[value as u8]),
+            CellWidth::W16 => self.emit(&(value as u16).to_le_bytes()),
+            CellWidth::W32 | CellWidth::W64 => self.emit_i32(value),
+        }
+    }
+
+    /// `cmp cell [rbx + disp], 0`
+    fn cmp_cell_zero(&mut self, disp: i32) {
+        self.cell_opcode(0x80, 0x83);
+        self.rbx_disp(7, disp);
+        self.emit(&[0]);
+    }
+    /// `add cell [rbx + disp], imm`
+    fn add_cell_imm(&mut self, disp: i32, value: i32) {
+        self.cell_opcode(0x80, 0x81);
+        self.rbx_disp(0, disp);
+        self.cell_imm(value);
+    }
+    /// `mov cell [rbx + disp], imm`
+    fn mov_cell_imm(&mut self, disp: i32, value: i32) {
+        self.cell_opcode(0xc6, 0xc7);
+        self.rbx_disp(0, disp);
+        self.cell_imm(value);
+    }
+    /// セルの値をゼロ拡張してraxに読み込む
+    fn load_cell_rax(&mut self, disp: i32) {
+        match self.cell_width {
+            CellWidth::W8 => self.emit(&[0x0f, 0xb6]),
+            CellWidth::W16 => self.emit(&[0x0f, 0xb7]),
+            CellWidth::W32 => self.emit(&[0x8b]),
+            CellWidth::W64 => self.emit(&[0x48, 0x8b]),
+        }
+        self.rbx_disp(0, disp);
+    }
+    /// `imul rax, rax, imm32`
+    fn imul_rax_imm(&mut self, value: i32) {
+        if self.cell_width == CellWidth::W64 {
+            self.emit(&[0x48]);
+        }
+        self.emit(&[0x69, 0xc0]);
+        self.emit_i32(value);
+    }
+    /// `add cell [rbx + disp], al/ax/eax/rax`
+    fn add_cell_rax(&mut self, disp: i32) {
+        self.cell_opcode(0x00, 0x01);
+        self.rbx_disp(0, disp);
+    }
+    /// `sub cell [rbx + disp], al/ax/eax/rax`
+    fn sub_cell_rax(&mut self, disp: i32) {
+        self.cell_opcode(0x28, 0x29);
+        self.rbx_disp(0, disp);
+    }
+    /// `mov cell [rbx + disp], al/ax/eax/rax`
+    fn store_cell_rax(&mut self, disp: i32) {
+        self.cell_opcode(0x88, 0x89);
+        self.rbx_disp(0, disp);
+    }
+
+    /// `lea rax, [rbx + disp]`
+    fn lea_rax_rbx(&mut self, disp: i32) {
+        self.emit(&[0x48, 0x8d]);
+        self.rbx_disp(0, disp);
+    }
+    /// `add rbx, imm32`
+    fn add_rbx_imm(&mut self, value: i32) {
+        self.emit(&[0x48, 0x81, 0xc3]);
+        self.emit_i32(value);
+    }
+    /// `lea rsi, [rbx + disp]`
+    fn lea_rsi_rbx(&mut self, disp: i32) {
+        self.emit(&[0x48, 0x8d]);
+        self.rbx_disp(6, disp);
+    }
+    /// `mov r32, imm32`（`reg`はeax=0, edx=2, esi=6, edi=7など）
+    fn mov_r32_imm(&mut self, reg: u8, value: i32) {
+        self.emit(&[0xb8 | reg]);
+        self.emit_i32(value);
+    }
+    fn syscall(&mut self) {
+        self.emit(&[0x0f, 0x05]);
+    }
+    /// `mov rdi, r12; mov rax, imm64; call rax`
+    fn call_with_ctx(&mut self, function: u64) {
+        self.emit(&[0x4c, 0x89, 0xe7]);
+        self.emit(&[0x48, 0xb8]);
+        self.emit(&function.to_le_bytes());
+        self.emit(&[0xff, 0xd0]);
+    }
+
+    /// 飛び先未定のJcc rel32を出し、あとで`patch`するための位置を返す
+    fn jcc_forward(&mut self, cond: Cond) -> usize {
+        self.emit(&[0x0f, 0x80 | cond as u8]);
+        self.emit_i32(0);
+        self.code.len() - 4
+    }
+    fn jcc_backward(&mut self, cond: Cond, target: usize) {
+        self.emit(&[0x0f, 0x80 | cond as u8]);
+        let rel = target as isize - (self.code.len() + 4) as isize;
+        self.emit_i32(rel as i32);
+    }
+    fn jmp_backward(&mut self, target: usize) {
+        self.emit(&[0xe9]);
+        let rel = target as isize - (self.code.len() + 4) as isize;
+        self.emit_i32(rel as i32);
+    }
+    /// `at`の位置にあるrel32を、現在位置へのジャンプに書き換える
+    fn patch_here(&mut self, at: usize) {
+        self.patch(at, self.code.len());
+    }
+    fn patch(&mut self, at: usize, target: usize) {
+        let rel = (target as isize - (at + 4) as isize) as i32;
+        self.code[at..at + 4].copy_from_slice(&rel.to_le_bytes());
+    }
+}
+
+pub(crate) struct Codegen {
+    asm: Assembler,
+    io: IoMode,
+    pointer_errors: Vec<usize>,
+    io_errors: Vec<usize>,
+}
+impl Codegen {
+    pub fn new(cell_width: CellWidth, io: IoMode) -> Self {
+        Self {
+            asm: Assembler {
+                code: Vec::new(),
+                cell_width,
+            },
+            io,
+            pointer_errors: Vec::new(),
+            io_errors: Vec::new(),
+        }
+    }
+
+    /// `extern "C" fn(pointer, ctx, tape_begin, tape_end) -> u32`な関数を生成する
+    pub fn compile(mut self, block: &Block) -> io::Result<Vec<u8>> {
+        // push rbp; push rbx; push r12; push r13; push r14
+        // 5回pushするとcall時にrspが16byte境界に揃う
+        self.asm
+            .emit(&[0x55, 0x53, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56]);
+        // mov rbx, rdi; mov r12, rsi; mov r13, rdx; mov r14, rcx
+        self.asm.emit(&[
+            0x48, 0x89, 0xfb, 0x49, 0x89, 0xf4, 0x49, 0x89, 0xd5, 0x49, 0x89, 0xce,
+        ]);
+
+        self.block(block)?;
+
+        // xor eax, eax
+        self.asm.emit(&[0x31, 0xc0]);
+        let epilogue = self.asm.code.len();
+        // mov [r12], rbx
+        self.asm.emit(&[0x49, 0x89, 0x1c, 0x24]);
+        // pop r14; pop r13; pop r12; pop rbx; pop rbp; ret
+        self.asm
+            .emit(&[0x41, 0x5e, 0x41, 0x5d, 0x41, 0x5c, 0x5b, 0x5d, 0xc3]);
+
+        for (status, patches) in [
+            (STATUS_POINTER_OUT_OF_RANGE, &self.pointer_errors),
+            (STATUS_IO_ERROR, &self.io_errors),
+        ] {
+            let handler = self.asm.code.len();
+            // mov eax, status
+            self.asm.emit(&[0xb8]);
+            self.asm.emit_i32(status as i32);
+            self.asm.jmp_backward(epilogue);
+
+            for &at in patches {
+                self.asm.patch(at, handler);
+            }
+        }
+
+        Ok(self.asm.code)
+    }
+
+    /// `[tape_begin, tape_end)`をテープとして実行し、終了したら`exit`するプログラムを生成する。
+    /// エラー時はメッセージを標準エラー出力に書いて、終了コード1で終わる。
+    pub fn compile_program(
+        mut self,
+        block: &Block,
+        tape_begin: u64,
+        tape_end: u64,
+    ) -> io::Result<Vec<u8>> {
+        // mov rbx, imm64; mov r13, imm64; mov r14, imm64
+        self.asm.emit(&[0x48, 0xbb]);
+        self.asm.emit(&tape_begin.to_le_bytes());
+        self.asm.emit(&[0x49, 0xbd]);
+        self.asm.emit(&tape_begin.to_le_bytes());
+        self.asm.emit(&[0x49, 0xbe]);
+        self.asm.emit(&tape_end.to_le_bytes());
+
+        self.block(block)?;
+
+        self.exit(0);
+
+        let mut messages = Vec::new();
+        for (message, patches) in [
+            (
+                &b"error: pointer is out of range\n"[..],
+                std::mem::take(&mut self.pointer_errors),
+            ),
+            (
+                &b"error: failed to read or write\n"[..],
+                std::mem::take(&mut self.io_errors),
+            ),
+        ] {
+            let handler = self.asm.code.len();
+            // lea rsi, [rip + message]
+            self.asm.emit(&[0x48, 0x8d, 0x35]);
+            self.asm.emit_i32(0);
+            messages.push((self.asm.code.len() - 4, message));
+            self.asm.mov_r32_imm(2, message.len() as i32);
+            self.asm.mov_r32_imm(7, 2);
+            self.asm.mov_r32_imm(0, SYS_WRITE);
+            self.asm.syscall();
+            self.exit(1);
+
+            for at in patches {
+                self.asm.patch(at, handler);
+            }
+        }
+        for (at, message) in messages {
+            self.asm.patch_here(at);
+            self.asm.emit(message);
+        }
+
+        Ok(self.asm.code)
+    }
+    fn exit(&mut self, code: i32) {
+        self.asm.mov_r32_imm(7, code);
+        self.asm.mov_r32_imm(0, SYS_EXIT);
+        self.asm.syscall();
+    }
+
+    fn disp(&self, offset: i32) -> io::Result<i32> {
+        offset
+            .checked_mul(self.asm.cell_width.bytes() as i32)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "offset is too large"))
+    }
+
+    /// ポインタがテープ内にあるか確認する
+    fn check_pointer(&mut self) {
+        // cmp rbx, r13; jb error
+        self.asm.emit(&[0x4c, 0x39, 0xeb]);
+        let at = self.asm.jcc_forward(Cond::Below);
+        self.pointer_errors.push(at);
+        // cmp rbx, r14; jae error
+        self.asm.emit(&[0x4c, 0x39, 0xf3]);
+        let at = self.asm.jcc_forward(Cond::AboveEqual);
+        self.pointer_errors.push(at);
+    }
+
+    /// `rbx + disp`のセルがテープ内にあるか確認する。raxを壊す。
+    fn check_offset(&mut self, disp: i32) {
+        if disp == 0 {
+            // ポインタ自体は移動時に確認済み
+            return;
+        }
+        self.asm.lea_rax_rbx(disp);
+        // cmp rax, r13; jb error
+        self.asm.emit(&[0x4c, 0x39, 0xe8]);
+        let at = self.asm.jcc_forward(Cond::Below);
+        self.pointer_errors.push(at);
+        // cmp rax, r14; jae error
+        self.asm.emit(&[0x4c, 0x39, 0xf0]);
+        let at = self.asm.jcc_forward(Cond::AboveEqual);
+        self.pointer_errors.push(at);
+    }
+
+    fn block(&mut self, block: &Block) -> io::Result<()> {
+        for item in &block.items {
+            match item {
+                BlockItem::Op(op) => self.op(*op)?,
+                BlockItem::Loop(loop_block) => {
+                    self.asm.cmp_cell_zero(0);
+                    let exit = self.asm.jcc_forward(Cond::Equal);
+                    let top = self.asm.code.len();
+
+                    self.block(loop_block)?;
+
+                    self.asm.cmp_cell_zero(0);
+                    self.asm.jcc_backward(Cond::NotEqual, top);
+                    self.asm.patch_here(exit);
+                }
+                BlockItem::If(if_block) => {
+                    self.asm.cmp_cell_zero(0);
+                    let exit = self.asm.jcc_forward(Cond::Equal);
+
+                    self.block(if_block)?;
+
+                    self.asm.patch_here(exit);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn op(&mut self, op: Op) -> io::Result<()> {
+        match op {
+            Op::Add(value, offset) => {
+                let disp = self.disp(offset)?;
+                self.check_offset(disp);
+                self.asm.add_cell_imm(disp, value);
+            }
+            Op::MovePtr(offset) => {
+                let disp = self.disp(offset)?;
+                self.asm.add_rbx_imm(disp);
+                self.check_pointer();
+            }
+            Op::Mul(to, x, offset) => {
+                let from = self.disp(offset)?;
+                let to = self.disp(offset + to)?;
+                self.check_offset(from);
+                self.check_offset(to);
+
+                self.asm.load_cell_rax(from);
+                match x {
+                    1 => self.asm.add_cell_rax(to),
+                    -1 => self.asm.sub_cell_rax(to),
+                    x => {
+                        self.asm.imul_rax_imm(x);
+                        self.asm.add_cell_rax(to);
+                    }
+                }
+            }
+            Op::Set(value, offset) => {
+                let disp = self.disp(offset)?;
+                self.check_offset(disp);
+                self.asm.mov_cell_imm(disp, value);
+            }
+            Op::Out(offset) => {
+                let disp = self.disp(offset)?;
+                self.check_offset(disp);
+                // リトルエンディアンなので、セルの先頭1byteが下位8bit
+                match &self.io {
+                    IoMode::Call(io) => {
+                        let putchar = io.putchar;
+                        // movzx esi, byte [rbx + disp]
+                        self.asm.emit(&[0x0f, 0xb6]);
+                        self.asm.rbx_disp(6, disp);
+                        self.asm.call_with_ctx(putchar);
+                        // test eax, eax; jnz error
+                        self.asm.emit(&[0x85, 0xc0]);
+                        let at = self.asm.jcc_forward(Cond::NotEqual);
+                        self.io_errors.push(at);
+                    }
+                    IoMode::Syscall => {
+                        // write(1, rbx + disp, 1)
+                        self.asm.lea_rsi_rbx(disp);
+                        self.asm.mov_r32_imm(7, 1);
+                        self.syscall_1byte(SYS_WRITE);
+                    }
+                }
+            }
+            Op::Input(offset) => {
+                let disp = self.disp(offset)?;
+                self.check_offset(disp);
+                match &self.io {
+                    IoMode::Call(io) => {
+                        let getchar = io.getchar;
+                        self.asm.call_with_ctx(getchar);
+                        // cmp eax, 0x100; jae error
+                        self.asm.emit(&[0x3d]);
+                        self.asm.emit_i32(0x100);
+                        let at = self.asm.jcc_forward(Cond::AboveEqual);
+                        self.io_errors.push(at);
+                        // mov eax, eax（raxの上位32bitをクリアする）
+                        self.asm.emit(&[0x89, 0xc0]);
+                        self.asm.store_cell_rax(disp);
+                    }
+                    IoMode::Syscall => {
+                        // 読んだ1byteがそのままセルの値になるように、先にセルを0にしておく
+                        self.asm.mov_cell_imm(disp, 0);
+                        // read(0, rbx + disp, 1)
+                        self.asm.lea_rsi_rbx(disp);
+                        self.asm.mov_r32_imm(7, 0);
+                        self.syscall_1byte(SYS_READ);
+                    }
+                }
+            }
+            Op::Lick(x) => {
+                let disp = self.disp(x)?;
+
+                let top = self.asm.code.len();
+                self.asm.cmp_cell_zero(0);
+                let exit = self.asm.jcc_forward(Cond::Equal);
+                self.asm.add_rbx_imm(disp);
+                self.check_pointer();
+                self.asm.jmp_backward(top);
+                self.asm.patch_here(exit);
+            }
+        }
+        Ok(())
+    }
+
+    /// 1byteだけ読み書きするシステムコールを呼び、1byte以外ならエラーにする。
+    /// EOFもエラーとして扱う。
+    fn syscall_1byte(&mut self, number: i32) {
+        self.asm.mov_r32_imm(2, 1);
+        self.asm.mov_r32_imm(0, number);
+        self.asm.syscall();
+        // cmp rax, 1; jne error
+        self.asm.emit(&[0x48, 0x83, 0xf8, 0x01]);
+        let at = self.asm.jcc_forward(Cond::NotEqual);
+        self.io_errors.push(at);
+    }
+}
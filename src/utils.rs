@@ -8,3 +8,32 @@ pub fn bf_to_block(bf: &str) -> Result<ir::Block, Error> {
 
     Ok(block)
 }
+
+/// 再現できる疑似乱数（xorshift64）。テストやファジングの入力を作るのに使う
+#[derive(Debug, Clone)]
+pub struct XorShift(u64);
+impl XorShift {
+    pub fn new(seed: u64) -> Self {
+        // 0から始めると0しか出ない
+        Self(if seed == 0 {
+            0x9e37_79b9_7f4a_7c15
+        } else {
+            seed
+        })
+    }
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+    /// `0..n`の値
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+    pub fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next_u64() as u8).collect()
+    }
+}
@@ -5,7 +5,6 @@ import { bf_to_wasm } from '@hotate29/bf';
 const start_button = document.querySelector('button[id="start"]');
 start_button.addEventListener('click', run);
 
-// まだ
 const abort_button = document.querySelector('button[id="abort"]');
 abort_button.disabled = true;
 abort_button.addEventListener('click', abort);
@@ -27,12 +26,14 @@ async function run() {
     const stdin_element = document.querySelector('textarea[id="stdin"]');
     const stdin = stdin_element.value;
 
+    const eof = document.querySelector('select[id="eof"]').value;
+
     const stdout_pre = document.querySelector('pre');
 
     const start_transpile = performance.now();
 
     let wasm;
-    try { wasm = bf_to_wasm(bf, true); } catch (e) {
+    try { wasm = bf_to_wasm(bf, true, eof); } catch (e) {
         alert(e);
         return
     }
@@ -56,12 +57,15 @@ async function run() {
     worker.onmessage = function (e) {
         const msg = e.data
 
-        if (typeof msg.out === 'number') {
-            stdout_pre.textContent += String.fromCharCode(msg.out)
+        if (Array.isArray(msg.out)) {
+            stdout_pre.textContent += String.fromCharCode(...msg.out)
+        }
+        else if (Array.isArray(msg.err)) {
+            stdout_pre.textContent += String.fromCharCode(...msg.err)
         }
         else if (typeof msg.exec_time === 'number') {
             const exec_time = msg.exec_time
-            status.textContent = `Transpile: ${transpile_time}ms Execution: ${exec_time}ms`;
+            status.textContent = `Transpile: ${transpile_time}ms Execution: ${exec_time}ms Exit code: ${msg.exit_code}`;
             start_button.disabled = false;
             abort_button.disabled = true;
         }
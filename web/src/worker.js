@@ -1,34 +1,82 @@
-onmessage = async function ({ data: data }) {
-    const imports = {
-        wasi_unstable: {
-            fd_read: function (fd, iov, len, _) {
-                // めっちゃ怪しい実装
-                if (stdin.length == stdin_count) {
-                    memory[0] = 255
-                } else {
-                    memory[0] = stdin.charCodeAt(stdin_count)
-                    stdin_count += 1
-                }
-            },
-            fd_write: function (fd, iov, len, _) {
-                const c = memory[0];
-                postMessage({ out: c })
-            }
-        }
-    };
+// wasi_snapshot_preview1のうち、bf_to_wasmの出力が使う関数だけを実装する
+const ERRNO_SUCCESS = 0;
+const ERRNO_BADF = 8;
 
+class ProcExit {
+    constructor(code) {
+        this.code = code;
+    }
+}
 
+onmessage = async function ({ data: data }) {
     const module = data.module
-    const stdin = data.stdin
+    const stdin = new TextEncoder().encode(data.stdin)
 
     let stdin_count = 0
+    let instance;
 
-    const instance = await WebAssembly.instantiate(module, imports);
+    // memory.growでbufferが変わるので、毎回作り直す
+    const view = () => new DataView(instance.exports.memory.buffer);
+    const bytes = () => new Uint8Array(instance.exports.memory.buffer);
 
-    const memory = new Uint32Array(instance.exports.memory.buffer)
+    // iovecを順に処理し、処理したbyte数をnbytesに書き込む
+    function each_iov(iovs, iovs_len, nbytes, f) {
+        let total = 0;
+        for (let i = 0; i < iovs_len; i++) {
+            const buf = view().getUint32(iovs + i * 8, true);
+            const len = view().getUint32(iovs + i * 8 + 4, true);
+            const n = f(buf, len);
+            total += n;
+            if (n < len) {
+                break;
+            }
+        }
+        view().setUint32(nbytes, total, true);
+        return ERRNO_SUCCESS;
+    }
 
+    const imports = {
+        wasi_snapshot_preview1: {
+            fd_read: function (fd, iovs, iovs_len, nread) {
+                if (fd !== 0) {
+                    return ERRNO_BADF;
+                }
+                // 読み切ったら0byteを返す（EOF）
+                return each_iov(iovs, iovs_len, nread, (buf, len) => {
+                    const chunk = stdin.subarray(stdin_count, stdin_count + len);
+                    bytes().set(chunk, buf);
+                    stdin_count += chunk.length;
+                    return chunk.length;
+                });
+            },
+            fd_write: function (fd, iovs, iovs_len, nwritten) {
+                if (fd !== 1 && fd !== 2) {
+                    return ERRNO_BADF;
+                }
+                return each_iov(iovs, iovs_len, nwritten, (buf, len) => {
+                    const chunk = Array.from(bytes().subarray(buf, buf + len));
+                    postMessage(fd === 1 ? { out: chunk } : { err: chunk });
+                    return len;
+                });
+            },
+            proc_exit: function (code) {
+                throw new ProcExit(code);
+            },
+        }
+    };
+
+    instance = await WebAssembly.instantiate(module, imports);
+
+    let exit_code = 0;
     const start = performance.now();
-    instance.exports._start()
+    try {
+        instance.exports._start()
+    } catch (e) {
+        if (!(e instanceof ProcExit)) {
+            throw e;
+        }
+        exit_code = e.code;
+    }
     const end = performance.now();
-    postMessage({ exec_time: end - start })
-}
\ No newline at end of file
+    postMessage({ exec_time: end - start, exit_code: exit_code })
+}